@@ -0,0 +1,589 @@
+//! Parsing [CUE sheets](https://en.wikipedia.org/wiki/Cue_sheet_(computing))
+//! in their text form, as found in `.cue` files next to a disc image.
+//!
+//! The parsed sheet can be turned into the same [Cue]s and [Tag]s
+//! that a FLAC file's embedded CUESHEET block and Vorbis comments
+//! provide, so [crate::Track::from_tags] can work from either.
+
+use anyhow::{bail, Context};
+use encoding_rs::Encoding;
+use std::{
+    borrow::Cow,
+    fmt::{self, Debug},
+    fs,
+    path::Path,
+    str::FromStr,
+};
+use symphonia_core::{
+    formats::{Cue, CuePoint},
+    meta::{StandardTagKey, Tag, Value},
+};
+use tracing::debug;
+
+/// The number of CD frames ("sectors") per second, the unit of
+/// CUE sheet timestamps.
+pub const CD_FRAMES_PER_SECOND: u64 = 75;
+
+/// The tag key that carries the index number on each [CuePoint]
+/// created from a CUE sheet (e.g. 0 for a pregap, 1 for the start of
+/// the track proper).
+pub const INDEX_TAG: &str = "INDEX";
+
+/// A parsed CUE sheet describing the tracks in a single disc image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CueSheet {
+    /// The album title.
+    pub title: Option<String>,
+
+    /// The album artist.
+    pub performer: Option<String>,
+
+    /// The album's songwriter.
+    pub songwriter: Option<String>,
+
+    /// The disc's UPC/EAN catalog number.
+    pub catalog: Option<String>,
+
+    /// The name of the audio file that the sheet describes.
+    pub file: Option<String>,
+
+    /// `REM` comments on the whole disc, like `REM GENRE Rock` or
+    /// `REM DATE 1997`, as (uppercased key, value) pairs.
+    pub comments: Vec<(String, String)>,
+
+    /// The tracks on the disc.
+    pub tracks: Vec<CueTrack>,
+}
+
+/// A single `TRACK` entry on a CUE sheet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CueTrack {
+    /// The track number.
+    pub number: u32,
+
+    /// The track title.
+    pub title: Option<String>,
+
+    /// The track's artist.
+    pub performer: Option<String>,
+
+    /// The track's songwriter.
+    pub songwriter: Option<String>,
+
+    /// The track's International Standard Recording Code.
+    pub isrc: Option<String>,
+
+    /// Subcode flags, e.g. `DCP` or `PRE`.
+    pub flags: Vec<String>,
+
+    /// `REM` comments on this track, as (uppercased key, value) pairs.
+    pub comments: Vec<(String, String)>,
+
+    /// The track's index points as (index number, position in CD
+    /// frames from the start of the file) pairs, in order of their
+    /// number and position. Index 0
+    /// marks the start of the pregap, index 1 the start of the track.
+    pub indices: Vec<(u32, u64)>,
+}
+
+impl CueSheet {
+    /// Reads a CUE sheet from a file. The sheet is decoded as UTF-8
+    /// if it is valid UTF-8, otherwise as `encoding` if given, or in
+    /// whatever legacy encoding it looks like it's in.
+    pub fn read<P: AsRef<Path> + Debug>(
+        path: P,
+        encoding: Option<&'static Encoding>,
+    ) -> anyhow::Result<Self> {
+        let bytes = fs::read(&path).with_context(|| format!("reading {:?}", path))?;
+        let text = decode_text(&bytes, encoding);
+        text.parse()
+            .with_context(|| format!("parsing cue sheet {:?}", path))
+    }
+
+    /// Converts the sheet's tracks into [Cue]s for a stream with the
+    /// given sample rate, the way a FLAC CUESHEET block describes
+    /// them: Each cue starts at the track's first index point, and has
+    /// one [CuePoint] per index, relative to that start and tagged
+    /// with its [INDEX_TAG] number. The cue's tags hold the track's
+    /// title, performer, ISRC and so on.
+    pub fn cues(&self, sample_rate: u32) -> Vec<Cue> {
+        let to_samples = |frames: u64| frames * u64::from(sample_rate) / CD_FRAMES_PER_SECOND;
+        self.tracks
+            .iter()
+            .filter_map(|track| {
+                // Parsed sheets have their indices in order, but
+                // don't rely on that:
+                let first = track.indices.iter().map(|(_, position)| *position).min()?;
+                let start_ts = to_samples(first);
+                let points = track
+                    .indices
+                    .iter()
+                    .map(|(number, position)| CuePoint {
+                        start_offset_ts: to_samples(*position) - start_ts,
+                        tags: vec![Tag::new(
+                            None,
+                            INDEX_TAG,
+                            Value::UnsignedInt((*number).into()),
+                        )],
+                    })
+                    .collect();
+                Some(Cue {
+                    index: track.number,
+                    start_ts,
+                    tags: self.track_tags(track),
+                    points,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the per-track tags for a track on this sheet.
+    pub fn track_tags(&self, track: &CueTrack) -> Vec<Tag> {
+        let mut tags = vec![Tag::new(
+            Some(StandardTagKey::TrackNumber),
+            "TRACKNUMBER",
+            Value::from(track.number.to_string()),
+        )];
+        let mut add = |std_key, key: &str, value: &Option<String>| {
+            if let Some(value) = value {
+                tags.push(Tag::new(std_key, key, Value::from(value.as_str())));
+            }
+        };
+        add(Some(StandardTagKey::TrackTitle), "TITLE", &track.title);
+        add(Some(StandardTagKey::Artist), "ARTIST", &track.performer);
+        add(
+            Some(StandardTagKey::Composer),
+            "COMPOSER",
+            &track.songwriter,
+        );
+        add(Some(StandardTagKey::IdentIsrc), "ISRC", &track.isrc);
+        tags.extend(
+            track
+                .comments
+                .iter()
+                .map(|(key, value)| Tag::new(None, key, Value::from(value.as_str()))),
+        );
+        tags
+    }
+
+    /// Returns the tags that apply to the whole disc: album title,
+    /// artist and composer, barcode (from the `CATALOG` number), and
+    /// `REM` comments like the genre and release date.
+    pub fn album_tags(&self) -> Vec<Tag> {
+        let mut tags = vec![];
+        if let Some(title) = &self.title {
+            tags.push(Tag::new(
+                Some(StandardTagKey::Album),
+                "ALBUM",
+                Value::from(title.as_str()),
+            ));
+        }
+        if let Some(performer) = &self.performer {
+            tags.push(Tag::new(
+                Some(StandardTagKey::AlbumArtist),
+                "ALBUMARTIST",
+                Value::from(performer.as_str()),
+            ));
+            tags.push(Tag::new(
+                Some(StandardTagKey::Artist),
+                "ARTIST",
+                Value::from(performer.as_str()),
+            ));
+        }
+        if let Some(songwriter) = &self.songwriter {
+            tags.push(Tag::new(
+                Some(StandardTagKey::Composer),
+                "COMPOSER",
+                Value::from(songwriter.as_str()),
+            ));
+        }
+        if let Some(catalog) = &self.catalog {
+            tags.push(Tag::new(
+                Some(StandardTagKey::IdentBarcode),
+                "BARCODE",
+                Value::from(catalog.as_str()),
+            ));
+        }
+        tags.extend(
+            self.comments
+                .iter()
+                .map(|(key, value)| Tag::new(None, key, Value::from(value.as_str()))),
+        );
+        tags
+    }
+}
+
+/// Decodes the text of a CUE sheet, which may not be UTF-8.
+fn decode_text(bytes: &[u8], encoding: Option<&'static Encoding>) -> String {
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    let encoding = encoding.unwrap_or_else(|| {
+        let mut detector = chardetng::EncodingDetector::new();
+        detector.feed(bytes, true);
+        detector.guess(None, true)
+    });
+    debug!(encoding = encoding.name(), "decoding legacy cue sheet");
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// Splits a CUE sheet line into its words, treating quoted strings
+/// as a single word. In quoted strings, `\"` stands for a quote and
+/// `\\` for a backslash; other backslashes are kept as they are (e.g.
+/// in Windows pathnames).
+fn split_words(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = vec![];
+    let mut chars = line.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next_if(|c| matches!(c, '"' | '\\')) {
+                            Some(c) => word.push(c),
+                            None => word.push('\\'),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated quoted string"),
+                    }
+                }
+                words.push(word);
+            }
+            c => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    Ok(words)
+}
+
+/// Parses a `MM:SS:FF` timestamp into a number of CD frames.
+fn parse_msf(msf: &str) -> anyhow::Result<u64> {
+    let parts = msf
+        .split(':')
+        .map(u64::from_str)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid timestamp {:?}", msf))?;
+    match parts[..] {
+        [minutes, seconds, frames] if seconds < 60 && frames < CD_FRAMES_PER_SECOND => {
+            Ok((minutes * 60 + seconds) * CD_FRAMES_PER_SECOND + frames)
+        }
+        _ => bail!("invalid timestamp {:?}", msf),
+    }
+}
+
+impl FromStr for CueSheet {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut sheet = CueSheet::default();
+        for (lineno, line) in text.lines().enumerate() {
+            let words = split_words(line).with_context(|| format!("line {}", lineno + 1))?;
+            let Some((command, args)) = words.split_first() else {
+                continue;
+            };
+            let arg = |n: usize| {
+                args.get(n)
+                    .cloned()
+                    .with_context(|| format!("line {}: {} needs an argument", lineno + 1, command))
+            };
+            let track = sheet.tracks.last_mut();
+            match (command.to_uppercase().as_str(), track) {
+                ("REM", track) => {
+                    if let Some(key) = args.first() {
+                        let comment = (key.to_uppercase(), args[1..].join(" "));
+                        match track {
+                            Some(track) => track.comments.push(comment),
+                            None => sheet.comments.push(comment),
+                        }
+                    }
+                }
+                ("FILE", _) => {
+                    if sheet.file.is_some() {
+                        bail!(
+                            "line {}: cue sheets referring to more than one FILE are not supported",
+                            lineno + 1
+                        );
+                    }
+                    sheet.file = Some(arg(0)?);
+                }
+                ("TRACK", _) => {
+                    let number = arg(0)?
+                        .parse()
+                        .with_context(|| format!("line {}: track number", lineno + 1))?;
+                    sheet.tracks.push(CueTrack {
+                        number,
+                        ..Default::default()
+                    });
+                }
+                ("INDEX", Some(track)) => {
+                    let number = arg(0)?
+                        .parse()
+                        .with_context(|| format!("line {}: index number", lineno + 1))?;
+                    let position = parse_msf(&arg(1)?)
+                        .with_context(|| format!("line {}: index position", lineno + 1))?;
+                    if let Some((previous_number, previous_position)) = track.indices.last() {
+                        if number <= *previous_number || position < *previous_position {
+                            bail!(
+                                "line {}: INDEX {:02} of track {} is out of order",
+                                lineno + 1,
+                                number,
+                                track.number
+                            );
+                        }
+                    }
+                    track.indices.push((number, position));
+                }
+                ("TITLE", Some(track)) => track.title = Some(arg(0)?),
+                ("TITLE", None) => sheet.title = Some(arg(0)?),
+                ("PERFORMER", Some(track)) => track.performer = Some(arg(0)?),
+                ("PERFORMER", None) => sheet.performer = Some(arg(0)?),
+                ("SONGWRITER", Some(track)) => track.songwriter = Some(arg(0)?),
+                ("SONGWRITER", None) => sheet.songwriter = Some(arg(0)?),
+                ("ISRC", Some(track)) => track.isrc = Some(arg(0)?),
+                ("FLAGS", Some(track)) => track.flags = args.to_vec(),
+                ("CATALOG", None) => sheet.catalog = Some(arg(0)?),
+                (other, _) => debug!(
+                    line = lineno + 1,
+                    command = other,
+                    "ignoring cue sheet command"
+                ),
+            }
+        }
+        if sheet.tracks.is_empty() {
+            bail!("cue sheet has no tracks");
+        }
+        Ok(sheet)
+    }
+}
+
+/// Writes a CUE sheet's text form.
+impl fmt::Display for CueSheet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.comments {
+            writeln!(f, "REM {} {}", key, quote(value))?;
+        }
+        if let Some(catalog) = &self.catalog {
+            writeln!(f, "CATALOG {}", catalog)?;
+        }
+        if let Some(performer) = &self.performer {
+            writeln!(f, "PERFORMER {}", quote(performer))?;
+        }
+        if let Some(songwriter) = &self.songwriter {
+            writeln!(f, "SONGWRITER {}", quote(songwriter))?;
+        }
+        if let Some(title) = &self.title {
+            writeln!(f, "TITLE {}", quote(title))?;
+        }
+        if let Some(file) = &self.file {
+            writeln!(f, "FILE {} WAVE", quote(file))?;
+        }
+        for track in &self.tracks {
+            writeln!(f, "  TRACK {:02} AUDIO", track.number)?;
+            if let Some(title) = &track.title {
+                writeln!(f, "    TITLE {}", quote(title))?;
+            }
+            if let Some(performer) = &track.performer {
+                writeln!(f, "    PERFORMER {}", quote(performer))?;
+            }
+            if let Some(songwriter) = &track.songwriter {
+                writeln!(f, "    SONGWRITER {}", quote(songwriter))?;
+            }
+            if !track.flags.is_empty() {
+                writeln!(f, "    FLAGS {}", track.flags.join(" "))?;
+            }
+            if let Some(isrc) = &track.isrc {
+                writeln!(f, "    ISRC {}", isrc)?;
+            }
+            for (key, value) in &track.comments {
+                writeln!(f, "    REM {} {}", key, quote(value))?;
+            }
+            for (number, position) in &track.indices {
+                let seconds = position / CD_FRAMES_PER_SECOND;
+                writeln!(
+                    f,
+                    "    INDEX {:02} {:02}:{:02}:{:02}",
+                    number,
+                    seconds / 60,
+                    seconds % 60,
+                    position % CD_FRAMES_PER_SECOND
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a string for a CUE sheet if it contains whitespace or
+/// quotes, escaping the quotes and backslashes in it (see
+/// [split_words]).
+fn quote(value: &str) -> Cow<'_, str> {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+        Cow::Owned(format!("\"{}\"", escaped))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE "Progressive Rock"
+REM DATE 1973
+REM DISCID 2F0B2B13
+REM COMMENT "ExactAudioCopy v0.99pb5"
+CATALOG 0724384260927
+PERFORMER "Some Band"
+TITLE "An Album"
+FILE "An Album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "First Song"
+    ISRC GBAYE7300001
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second Song"
+    PERFORMER "Some Band feat. Guest"
+    FLAGS DCP PRE
+    INDEX 00 03:20:40
+    INDEX 01 03:22:00
+"#;
+
+    #[test]
+    fn test_parse() {
+        let sheet: CueSheet = SHEET.parse().expect("parsing");
+        assert_eq!(sheet.title.as_deref(), Some("An Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("Some Band"));
+        assert_eq!(sheet.catalog.as_deref(), Some("0724384260927"));
+        let barcode = sheet
+            .album_tags()
+            .into_iter()
+            .find(|tag| tag.key == "BARCODE");
+        assert_eq!(
+            barcode.map(|tag| tag.value.to_string()).as_deref(),
+            Some("0724384260927")
+        );
+        assert_eq!(
+            sheet.comments[0],
+            ("GENRE".to_string(), "Progressive Rock".to_string())
+        );
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(sheet.tracks[0].isrc.as_deref(), Some("GBAYE7300001"));
+        assert_eq!(sheet.tracks[1].flags, vec!["DCP", "PRE"]);
+        assert_eq!(
+            sheet.tracks[1].indices,
+            vec![(0, (3 * 60 + 20) * 75 + 40), (1, (3 * 60 + 22) * 75)]
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let sheet: CueSheet = SHEET.parse().expect("parsing");
+        let written = sheet.to_string();
+        assert_eq!(written.parse::<CueSheet>().expect("reparsing"), sheet);
+    }
+
+    #[test]
+    fn test_roundtrip_quotes() {
+        let sheet = CueSheet {
+            title: Some(r#"The "Quoted" Album"#.to_string()),
+            performer: Some(r#"Back\slash "#.to_string()),
+            songwriter: Some(r#"No"Space"#.to_string()),
+            file: Some(r"C:\Music\album.flac".to_string()),
+            tracks: vec![CueTrack {
+                number: 1,
+                title: Some(r#"Say "Hi""#.to_string()),
+                indices: vec![(1, 0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let written = sheet.to_string();
+        assert!(written.contains(r#"TITLE "The \"Quoted\" Album""#));
+        assert_eq!(written.parse::<CueSheet>().expect("reparsing"), sheet);
+
+        // Backslashes that don't escape anything stay:
+        let sheet: CueSheet =
+            "FILE \"C:\\Music\\album.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n"
+                .parse()
+                .expect("parsing");
+        assert_eq!(sheet.file.as_deref(), Some(r"C:\Music\album.flac"));
+    }
+
+    #[test]
+    fn test_cues() {
+        let sheet: CueSheet = SHEET.parse().expect("parsing");
+        let cues = sheet.cues(44100);
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[1].index, 2);
+        assert_eq!(cues[1].start_ts, ((3 * 60 + 20) * 75 + 40) * 588);
+        let offsets: Vec<_> = cues[1].points.iter().map(|p| p.start_offset_ts).collect();
+        assert_eq!(offsets, vec![0, 110 * 588]);
+
+        let tag = |cue: &Cue, key: &str| {
+            cue.tags
+                .iter()
+                .find(|tag| tag.key == key)
+                .map(|tag| tag.value.to_string())
+        };
+        assert_eq!(tag(&cues[0], "ARTIST"), None);
+        assert_eq!(
+            tag(&cues[1], "ARTIST").as_deref(),
+            Some("Some Band feat. Guest")
+        );
+        assert_eq!(tag(&cues[1], "TRACKNUMBER").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn test_legacy_encoding() {
+        let (bytes, _, _) = encoding_rs::WINDOWS_1252
+            .encode("TITLE \"Motörhead\"\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n");
+        let sheet: CueSheet = decode_text(&bytes, Some(encoding_rs::WINDOWS_1252))
+            .parse()
+            .expect("parsing");
+        assert_eq!(sheet.title.as_deref(), Some("Motörhead"));
+    }
+
+    #[test]
+    fn test_detect_encoding() {
+        let text = "PERFORMER \"Björk\"\nTITLE \"Début à la française, für Sie\"\n\
+                    TRACK 01 AUDIO\nTITLE \"Où est la clé? Schöne Grüße\"\nINDEX 01 00:00:00\n";
+        let (bytes, _, _) = encoding_rs::WINDOWS_1252.encode(text);
+        assert!(std::str::from_utf8(&bytes).is_err());
+        let sheet: CueSheet = decode_text(&bytes, None).parse().expect("parsing");
+        assert_eq!(sheet.performer.as_deref(), Some("Björk"));
+        assert_eq!(
+            sheet.tracks[0].title.as_deref(),
+            Some("Où est la clé? Schöne Grüße")
+        );
+    }
+
+    #[test]
+    fn test_indices_out_of_order() {
+        let text = "TRACK 01 AUDIO\nINDEX 01 00:02:00\nINDEX 00 00:00:00\n";
+        assert!(text.parse::<CueSheet>().is_err());
+
+        let sheet = CueSheet {
+            tracks: vec![CueTrack {
+                number: 1,
+                indices: vec![(1, 150), (0, 0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let cues = sheet.cues(44100);
+        assert_eq!(cues[0].start_ts, 0);
+        let offsets: Vec<_> = cues[0].points.iter().map(|p| p.start_offset_ts).collect();
+        assert_eq!(offsets, vec![150 * 588, 0]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Releases that span several discs, each ripped to its own disc
+//! image.
+
+use std::{collections::HashMap, fmt::Debug, path::Path};
+use symphonia_core::meta::{StandardTagKey, Tag, Value};
+use tracing::warn;
+
+/// How [crate::Track::pathname_in] keeps the tracks of the discs of a
+/// multi-disc release apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiscLayout {
+    /// Put each disc's tracks into a `Disc N` subdirectory of the
+    /// album directory.
+    #[default]
+    Subdirectory,
+
+    /// Prefix each track's filename with its disc number, like
+    /// `2-01.Title.flac`.
+    Prefix,
+}
+
+/// Parses a DISCNUMBER value, which may also hold the total number
+/// of discs (like `1/2`).
+pub fn parse_disc_number(value: &str) -> Option<(u32, Option<u32>)> {
+    let (number, total) = match value.split_once('/') {
+        Some((number, total)) => (number, total.trim().parse().ok()),
+        None => (value, None),
+    };
+    Some((number.trim().parse().ok()?, total))
+}
+
+/// Works out which of the given disc images belong to the same
+/// multi-disc release, going by their ALBUM and MUSICBRAINZ_ALBUMID
+/// tags, and returns the DISCNUMBER and TOTALDISCS tags that each of
+/// them is missing (in the order of `paths`).
+///
+/// Discs without a number get the lowest free numbers, in the order
+/// of their pathnames. Images whose tags can't be read are left out
+/// (splitting them will fail with a better error anyway).
+pub fn synthesize_disc_tags<P: AsRef<Path> + Debug>(paths: &[P]) -> Vec<Vec<Tag>> {
+    struct Disc<'a> {
+        path: &'a Path,
+        number: Option<u32>,
+        total: Option<u32>,
+    }
+
+    let mut releases: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    let mut discs = vec![];
+    for (i, path) in paths.iter().enumerate() {
+        let tag = match metaflac::Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(error) => {
+                warn!(?path, %error, "can't read tags, not looking for other discs");
+                discs.push(Disc {
+                    path: path.as_ref(),
+                    number: None,
+                    total: None,
+                });
+                continue;
+            }
+        };
+        let first = |key: &str| tag.get_vorbis(key).and_then(|mut values| values.next());
+        let (number, mut total) = match first("DISCNUMBER").and_then(parse_disc_number) {
+            Some((number, total)) => (Some(number), total),
+            None => (None, None),
+        };
+        total = total.or_else(|| {
+            ["TOTALDISCS", "DISCTOTAL"]
+                .iter()
+                .find_map(|key| first(key)?.trim().parse().ok())
+        });
+        if let (Some(album), Some(release)) = (first("ALBUM"), first("MUSICBRAINZ_ALBUMID")) {
+            releases
+                .entry((album.to_string(), release.to_string()))
+                .or_default()
+                .push(i);
+        }
+        discs.push(Disc {
+            path: path.as_ref(),
+            number,
+            total,
+        });
+    }
+
+    let mut synthesized = vec![vec![]; paths.len()];
+    for mut members in releases.into_values().filter(|members| members.len() > 1) {
+        members.sort_by_key(|i| discs[*i].path);
+        let mut used: Vec<u32> = members.iter().filter_map(|i| discs[*i].number).collect();
+        let mut next = 1;
+        for i in &members {
+            if discs[*i].number.is_none() {
+                while used.contains(&next) {
+                    next += 1;
+                }
+                used.push(next);
+                discs[*i].number = Some(next);
+                synthesized[*i].push(Tag::new(
+                    Some(StandardTagKey::DiscNumber),
+                    "DISCNUMBER",
+                    Value::from(next.to_string()),
+                ));
+            }
+        }
+        let total = used
+            .iter()
+            .copied()
+            .chain([members.len() as u32])
+            .max()
+            .expect("there are at least two discs");
+        for i in &members {
+            if discs[*i].total.is_none() {
+                synthesized[*i].push(Tag::new(
+                    Some(StandardTagKey::DiscTotal),
+                    "TOTALDISCS",
+                    Value::from(total.to_string()),
+                ));
+            }
+        }
+    }
+    synthesized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_disc_number() {
+        assert_eq!(parse_disc_number("2"), Some((2, None)));
+        assert_eq!(parse_disc_number("1/3"), Some((1, Some(3))));
+        assert_eq!(parse_disc_number(" 1 / x"), Some((1, None)));
+        assert_eq!(parse_disc_number("A"), None);
+    }
+
+    #[test]
+    fn test_synthesize_disc_tags() {
+        use crate::{plan_one_file, test::write_test_image, SplitOptions};
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = |name: &str, tags: &[(&str, &str)]| {
+            let path = dir.path().join(name);
+            write_test_image(&path, 10_000, &[0]);
+            let mut tag = metaflac::Tag::read_from_path(&path).unwrap();
+            for (key, value) in tags {
+                tag.set_vorbis(*key, vec![*value]);
+            }
+            tag.save().unwrap();
+            path
+        };
+        let paths = vec![
+            image("a.flac", &[("ALBUM", "Box"), ("MUSICBRAINZ_ALBUMID", "x")]),
+            image(
+                "b.flac",
+                &[
+                    ("ALBUM", "Box"),
+                    ("MUSICBRAINZ_ALBUMID", "x"),
+                    ("DISCNUMBER", "1"),
+                ],
+            ),
+            image("c.flac", &[("ALBUM", "Box"), ("MUSICBRAINZ_ALBUMID", "x")]),
+            // Same album title, different release:
+            image("d.flac", &[("ALBUM", "Box"), ("MUSICBRAINZ_ALBUMID", "y")]),
+            image(
+                "e.flac",
+                &[
+                    ("ALBUM", "Pair"),
+                    ("MUSICBRAINZ_ALBUMID", "z"),
+                    ("DISCNUMBER", "1/2"),
+                ],
+            ),
+            image(
+                "f.flac",
+                &[
+                    ("ALBUM", "Pair"),
+                    ("MUSICBRAINZ_ALBUMID", "z"),
+                    ("DISCNUMBER", "2"),
+                    ("DISCTOTAL", "2"),
+                ],
+            ),
+        ];
+        let synthesized = synthesize_disc_tags(&paths);
+        let pairs: Vec<Vec<(String, String)>> = synthesized
+            .iter()
+            .map(|tags| {
+                tags.iter()
+                    .map(|tag| (tag.key.clone(), tag.value.to_string()))
+                    .collect()
+            })
+            .collect();
+        let pair = |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(
+            pairs,
+            vec![
+                vec![pair("DISCNUMBER", "2"), pair("TOTALDISCS", "3")],
+                vec![pair("TOTALDISCS", "3")],
+                vec![pair("DISCNUMBER", "3"), pair("TOTALDISCS", "3")],
+                vec![],
+                vec![],
+                vec![],
+            ]
+        );
+
+        let out = dir.path().join("out");
+        let planned = |index: usize, disc_layout| {
+            let options = SplitOptions {
+                disc_layout,
+                tags: synthesized[index].clone(),
+                ..Default::default()
+            };
+            let tracks = plan_one_file(&paths[index], &out, &options).unwrap();
+            tracks[0].path.strip_prefix(&out).unwrap().to_path_buf()
+        };
+        let expected = |path: &str| Path::new(path).to_path_buf();
+        for (index, subdirectory, prefix) in [
+            (
+                0,
+                "Unknown Artist/Box/Disc 2/01.Track 01.flac",
+                "Unknown Artist/Box/2-01.Track 01.flac",
+            ),
+            (
+                1,
+                "Unknown Artist/Box/Disc 1/01.Track 01.flac",
+                "Unknown Artist/Box/1-01.Track 01.flac",
+            ),
+            (
+                3,
+                "Unknown Artist/Box/01.Track 01.flac",
+                "Unknown Artist/Box/01.Track 01.flac",
+            ),
+            (
+                5,
+                "Unknown Artist/Pair/Disc 2/01.Track 01.flac",
+                "Unknown Artist/Pair/2-01.Track 01.flac",
+            ),
+        ] {
+            assert_eq!(
+                planned(index, DiscLayout::Subdirectory),
+                expected(subdirectory)
+            );
+            assert_eq!(planned(index, DiscLayout::Prefix), expected(prefix));
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! A small FLAC [frame](https://xiph.org/flac/format.html#frame)
+//! encoder, used to re-encode the few frames that straddle a track
+//! boundary when splitting sample-accurately.
+//!
+//! This only knows the simple parts of the format - constant,
+//! verbatim and fixed-predictor subframes with a single rice
+//! partition, and independent channels. The frames it produces are a
+//! bit larger than what a real encoder would emit, but there are only
+//! one or two of them per track.
+
+use crate::utf8_encode_be_u64;
+use anyhow::bail;
+use int_conv::Truncate;
+use symphonia_core::{
+    checksum::{Crc16Ansi, Crc8Ccitt},
+    io::Monitor,
+};
+
+/// The largest rice parameter we can encode (using the 5-bit
+/// parameter coding method; 31 is the escape code).
+const MAX_RICE_PARAM: u32 = 30;
+
+/// The largest rice parameter that fits the 4-bit parameter coding
+/// method (15 is the escape code).
+const MAX_RICE4_PARAM: u32 = 14;
+
+/// The highest order of the fixed predictors that FLAC defines.
+const MAX_FIXED_ORDER: usize = 4;
+
+#[derive(Default)]
+struct BitWriter {
+    buf: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `n` bits of `value`, most significant bit first.
+    fn write(&mut self, value: u64, n: u32) {
+        debug_assert!(n <= 32);
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.buf.push((self.acc >> self.bits).truncate());
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    /// Writes a signed value as an `n`-bit two's complement number.
+    fn write_signed(&mut self, value: i64, n: u32) {
+        self.write(value as u64, n);
+    }
+
+    /// Writes `q` zero bits followed by a one bit.
+    fn write_unary(&mut self, mut q: u64) {
+        while q >= 32 {
+            self.write(0, 32);
+            q -= 32;
+        }
+        self.write(1, q as u32 + 1);
+    }
+
+    /// Pads the output with zero bits up to the next byte boundary
+    /// and returns the bytes written.
+    fn into_bytes(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+        self.buf
+    }
+}
+
+/// Computes the residual of a fixed predictor of the given order, or
+/// `None` if the residual doesn't fit into 32 bits.
+fn fixed_residual(samples: &[i32], order: usize) -> Option<Vec<i64>> {
+    let s = |i: usize| i64::from(samples[i]);
+    let residual: Vec<i64> = (order..samples.len())
+        .map(|i| match order {
+            0 => s(i),
+            1 => s(i) - s(i - 1),
+            2 => s(i) - 2 * s(i - 1) + s(i - 2),
+            3 => s(i) - 3 * s(i - 1) + 3 * s(i - 2) - s(i - 3),
+            4 => s(i) - 4 * s(i - 1) + 6 * s(i - 2) - 4 * s(i - 3) + s(i - 4),
+            _ => unreachable!("FLAC fixed predictors only go up to order 4"),
+        })
+        .collect();
+    if residual
+        .iter()
+        .all(|r| i32::try_from(*r).is_ok() && *r != i64::from(i32::MIN))
+    {
+        Some(residual)
+    } else {
+        None
+    }
+}
+
+fn zigzag(residual: i64) -> u64 {
+    if residual >= 0 {
+        (residual as u64) << 1
+    } else {
+        ((-residual as u64) << 1) - 1
+    }
+}
+
+/// Finds the rice parameter that encodes `residual` in the fewest
+/// bits, returning the parameter and the resulting size in bits.
+fn best_rice_param(residual: &[i64]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAM)
+        .map(|k| {
+            let bits = residual
+                .iter()
+                .map(|r| (zigzag(*r) >> k) + 1 + u64::from(k))
+                .sum();
+            (k, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .expect("parameter range is not empty")
+}
+
+/// Writes a subframe header: a zero padding bit, 6 bits of subframe
+/// type and a zero "wasted bits" flag (we don't use those).
+fn write_subframe_header(out: &mut BitWriter, subframe_type: u64) {
+    out.write(0, 1);
+    out.write(subframe_type, 6);
+    out.write(0, 1);
+}
+
+fn write_subframe(out: &mut BitWriter, samples: &[i32], bits_per_sample: u32) {
+    if samples.iter().all(|s| *s == samples[0]) {
+        write_subframe_header(out, 0b000000);
+        out.write_signed(samples[0].into(), bits_per_sample);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * u64::from(bits_per_sample);
+    let best_fixed = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .filter_map(|order| {
+            let residual = fixed_residual(samples, order)?;
+            let (param, rice_bits) = best_rice_param(&residual);
+            let bits = order as u64 * u64::from(bits_per_sample) + 2 + 4 + 5 + rice_bits;
+            Some((order, residual, param, bits))
+        })
+        .min_by_key(|(_, _, _, bits)| *bits);
+
+    match best_fixed {
+        Some((order, residual, param, bits)) if bits < verbatim_bits => {
+            write_subframe_header(out, 0b001000 | order as u64);
+            for warmup in &samples[..order] {
+                out.write_signed((*warmup).into(), bits_per_sample);
+            }
+            // Residual coding method, then a rice partition order of 0:
+            if param <= MAX_RICE4_PARAM {
+                out.write(0b00, 2);
+                out.write(0, 4);
+                out.write(param.into(), 4);
+            } else {
+                out.write(0b01, 2);
+                out.write(0, 4);
+                out.write(param.into(), 5);
+            }
+            for r in residual {
+                let folded = zigzag(r);
+                out.write_unary(folded >> param);
+                out.write(folded, param);
+            }
+        }
+        _ => {
+            write_subframe_header(out, 0b000001);
+            for sample in samples {
+                out.write_signed((*sample).into(), bits_per_sample);
+            }
+        }
+    }
+}
+
+/// Encodes one FLAC frame from the given per-channel samples.
+///
+/// The frame's sample rate and bit depth refer to the stream's
+/// STREAMINFO block where the frame header has no code for
+/// them. `number` is the frame number for fixed-blocksize streams,
+/// and the sample number of the frame's first sample in
+/// variable-blocksize streams.
+pub(crate) fn encode_frame(
+    number: u64,
+    variable_block_size: bool,
+    bits_per_sample: u32,
+    channels: &[Vec<i32>],
+) -> anyhow::Result<Vec<u8>> {
+    let block_size = match channels.first() {
+        Some(samples) if !samples.is_empty() => samples.len(),
+        _ => bail!("can not encode an empty frame"),
+    };
+    if channels.len() > 8 {
+        bail!("FLAC frames can have at most 8 channels");
+    }
+    if block_size > usize::from(u16::MAX) {
+        bail!("block size {} is too large for a FLAC frame", block_size);
+    }
+    let block_size_enc: u8 = if block_size <= 256 { 0b0110 } else { 0b0111 };
+    let sample_size_enc: u8 = match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    };
+
+    let mut header = vec![0xff, 0xf8 | u8::from(variable_block_size)];
+    // block size; sample rate comes from STREAMINFO:
+    header.push(block_size_enc << 4);
+    // channel assignment (independent), sample size, reserved bit:
+    header.push(((channels.len() - 1) as u8) << 4 | sample_size_enc << 1);
+    header.extend(utf8_encode_be_u64(number)?);
+    let encoded_size = block_size as u64 - 1;
+    if block_size_enc == 0b0110 {
+        header.push(encoded_size.truncate());
+    } else {
+        header.extend(<u64 as Truncate<u16>>::truncate(encoded_size).to_be_bytes());
+    }
+    let mut header_crc = Crc8Ccitt::new(0);
+    header_crc.process_buf_bytes(&header);
+    header.push(header_crc.crc());
+
+    let mut subframes = BitWriter::default();
+    for samples in channels {
+        if samples.len() != block_size {
+            bail!("all channels must have the same number of samples");
+        }
+        write_subframe(&mut subframes, samples, bits_per_sample);
+    }
+
+    let mut frame = header;
+    frame.extend(subframes.into_bytes());
+    let mut footer_crc = Crc16Ansi::new(0);
+    footer_crc.process_buf_bytes(&frame);
+    frame.extend(footer_crc.crc().to_be_bytes());
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::{collection::vec, prop_assert_eq, proptest};
+    use symphonia_bundle_flac::FlacDecoder;
+    use symphonia_core::{
+        audio::{AudioBufferRef, Signal},
+        codecs::{CodecParameters, Decoder, CODEC_TYPE_FLAC},
+        formats::Packet,
+    };
+
+    fn decode(frame: Vec<u8>, bits_per_sample: u32, channels: usize) -> Vec<Vec<i32>> {
+        let info = metaflac::block::StreamInfo {
+            min_block_size: 16,
+            max_block_size: u16::MAX,
+            sample_rate: 44100,
+            num_channels: channels as u8,
+            bits_per_sample: bits_per_sample as u8,
+            md5: vec![0; 16],
+            ..Default::default()
+        };
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_FLAC)
+            .with_extra_data(info.to_bytes().into_boxed_slice());
+        let mut decoder = FlacDecoder::try_new(&params, &Default::default()).unwrap();
+        let packet = Packet::new_from_slice(0, 0, 0, &frame);
+        match decoder.decode(&packet).expect("decoding") {
+            AudioBufferRef::S32(buf) => (0..channels)
+                .map(|c| {
+                    buf.chan(c)
+                        .iter()
+                        .map(|s| s >> (32 - bits_per_sample))
+                        .collect()
+                })
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip_16bit(left in vec(-32768i32..32768, 1..600), offset in -20000i32..20000) {
+            let right: Vec<i32> = left.iter().map(|s| (s / 2 + offset).clamp(-32768, 32767)).collect();
+            let channels = vec![left, right];
+            let frame = encode_frame(3, true, 16, &channels).expect("encoding");
+            prop_assert_eq!(decode(frame, 16, 2), channels);
+        }
+
+        #[test]
+        fn test_roundtrip_24bit_smooth(start in -8_000_000i32..8_000_000, len in 1usize..5000) {
+            let samples: Vec<i32> = (0..len as i32).map(|i| start + (i * 37) % 1000).collect();
+            let channels = vec![samples];
+            let frame = encode_frame(0, false, 24, &channels).expect("encoding");
+            prop_assert_eq!(decode(frame, 24, 1), channels);
+        }
+    }
+}
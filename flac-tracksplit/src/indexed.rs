@@ -0,0 +1,830 @@
+//! Splitting disc images without decoding them: the frames of the
+//! image are found once (see [IndexedImage::open]), and the frames of
+//! each track are read from the image with positioned reads and go
+//! straight into the track's file, with only their headers and footers
+//! rewritten (see [OffsetFrame::process_borrowed]).
+//!
+//! The image is read, not memory-mapped, so an image that gets
+//! truncated while it's being split makes splitting it fail with an
+//! error instead of crashing the process.
+//!
+//! That only works when tracks are made of whole frames, i.e. unless
+//! [SplitOptions::sample_accurate] or [SplitOptions::compute_md5]
+//! (which both need the frames decoded) are set.
+//!
+//! Finding the frames means computing the CRC-16 of every byte of the
+//! audio once, but that costs less than reading the frames with
+//! symphonia: a 30-minute, 317 MB image (from the page cache) splits
+//! in about 0.6s this way, and in about 0.9s with `--sample-accurate`.
+
+use crate::{
+    metadata_end, seek_point, seekpoint_spacing, AudioSummary, FrameHeader, OffsetFrame,
+    SplitOptions, Track, MAX_FRAME_HEADER_LEN,
+};
+use anyhow::{bail, Context};
+use metaflac::block::StreamInfo;
+use std::{
+    fs::File,
+    io::{self, IoSlice, Write},
+    ops::Range,
+    path::Path,
+};
+use symphonia_bundle_flac::FlacDecoder;
+use symphonia_core::{
+    checksum::{Crc16Ansi, Crc8Ccitt},
+    codecs::{CodecParameters, Decoder, CODEC_TYPE_FLAC},
+    formats::Packet,
+    io::Monitor,
+};
+use tracing::debug;
+
+/// How many frames to write with one vectored write; each takes three
+/// slices, which keeps us below the usual limit of 1024 slices.
+const FRAMES_PER_WRITE: usize = 256;
+
+/// How much of the image to read at once while finding its frames.
+const READ_AHEAD: usize = 4 << 20;
+
+/// Where a frame is in an [IndexedImage].
+#[derive(Debug, Clone, Copy)]
+struct IndexedFrame {
+    /// The byte range of the frame in the image file.
+    start: u64,
+    end: u64,
+
+    /// The number of the frame's first sample.
+    ts: u64,
+
+    /// The number of samples (per channel) in the frame.
+    dur: u64,
+}
+
+/// A FLAC disc image, and where its frames are.
+pub struct IndexedImage {
+    file: File,
+    frames: Vec<IndexedFrame>,
+}
+
+impl IndexedImage {
+    /// Opens a FLAC file and finds its frames.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {:?}", path))?;
+        let frames = find_frames(&file).with_context(|| format!("finding frames in {:?}", path))?;
+        debug!(?path, frames = frames.len(), "indexed disc image");
+        Ok(Self { file, frames })
+    }
+
+    /// Hands the image's frames to `tracks` (which must be in order)
+    /// the way [Track::write_audio] does when reading them one after
+    /// another: the frame that straddles a track boundary goes to the
+    /// earlier track. Returns the range of frames for each track.
+    pub fn assign_frames<'a>(
+        &self,
+        tracks: impl IntoIterator<Item = &'a Track>,
+    ) -> anyhow::Result<Vec<Range<usize>>> {
+        let mut next = 0;
+        tracks
+            .into_iter()
+            .map(|track| {
+                // Skip audio that belongs to no track, like a discarded
+                // pregap:
+                while self
+                    .frames
+                    .get(next)
+                    .is_some_and(|frame| frame.ts + frame.dur <= track.start_ts)
+                {
+                    next += 1;
+                }
+                let first = next;
+                loop {
+                    let frame = self.frames.get(next).with_context(|| {
+                        format!(
+                            "track {} ends at sample {}, after the last frame",
+                            track.number, track.end_ts
+                        )
+                    })?;
+                    next += 1;
+                    if frame.ts + frame.dur >= track.end_ts {
+                        return Ok(first..next);
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Summarizes the given range of frames (see
+    /// [IndexedImage::assign_frames]) for a track that isn't written,
+    /// like [Track::skip_audio] does.
+    pub fn skip_track(&self, track: &Track, frames: Range<usize>) -> AudioSummary {
+        let frames = &self.frames[frames];
+        AudioSummary {
+            total_samples: frames.iter().map(|frame| frame.dur).sum(),
+            frames: frames.len() as u64,
+            warnings: frames
+                .first()
+                .filter(|first| first.ts < track.start_ts)
+                .map(|first| track.mid_frame_start_warning(first.ts))
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Writes `track` to `to` as a complete FLAC stream made of the
+    /// given range of frames (see [IndexedImage::assign_frames]), the
+    /// same stream [Track::write_seekable] writes when reading those
+    /// frames.
+    ///
+    /// The metadata goes first, since all the frames are known up
+    /// front; `to` shouldn't be buffered, as the frames are written
+    /// with vectored writes.
+    pub fn write_track<W: Write>(
+        &self,
+        track: &Track,
+        frames: Range<usize>,
+        options: &SplitOptions,
+        mut to: W,
+    ) -> anyhow::Result<AudioSummary> {
+        let (audio, file) = self.rewrite_track(track, frames, options)?;
+        to.write_all(&file.metadata).context("writing metadata")?;
+        let mut buf = vec![];
+        for batch in file.frames.chunks(FRAMES_PER_WRITE) {
+            let batch_start = batch[0].subframes.start;
+            self.read(batch_start..batch[batch.len() - 1].subframes.end, &mut buf)?;
+            let mut slices = Vec::with_capacity(3 * batch.len());
+            slices.extend(batch.iter().flat_map(|frame| {
+                let subframes = (frame.subframes.start - batch_start) as usize
+                    ..(frame.subframes.end - batch_start) as usize;
+                [
+                    IoSlice::new(&frame.header[..frame.header_len]),
+                    IoSlice::new(&buf[subframes]),
+                    IoSlice::new(&frame.footer),
+                ]
+            }));
+            write_all_vectored(&mut to, &mut slices).context("writing frames")?;
+        }
+        Ok(audio)
+    }
+
+    /// Returns the file [IndexedImage::write_track] would write for
+    /// `track`, without writing it: see [TrackFile::read_at].
+    pub fn track_file(
+        &self,
+        track: &Track,
+        frames: Range<usize>,
+        options: &SplitOptions,
+    ) -> anyhow::Result<TrackFile> {
+        Ok(self.rewrite_track(track, frames, options)?.1)
+    }
+
+    /// Rewrites the given range of frames for `track`, returning what
+    /// they hold and the track file they make up.
+    fn rewrite_track(
+        &self,
+        track: &Track,
+        frames: Range<usize>,
+        options: &SplitOptions,
+    ) -> anyhow::Result<(AudioSummary, TrackFile)> {
+        let frames = &self.frames[frames];
+        let spacing = seekpoint_spacing(options, track.sample_rate());
+        let mut warnings = vec![];
+        if let Some(first) = frames.first().filter(|first| first.ts < track.start_ts) {
+            // Only happens after a gap between tracks; the previous
+            // track ended at or after our start otherwise.
+            warnings.push(track.mid_frame_start_warning(first.ts));
+        }
+
+        let mut offset_frame = OffsetFrame::new(spacing);
+        let mut rewritten = Vec::with_capacity(frames.len());
+        // Where each frame starts in the track's audio:
+        let mut offset = 0;
+        let mut buf = vec![];
+        for batch in frames.chunks(FRAMES_PER_WRITE) {
+            let batch_start = batch[0].start;
+            self.read(batch_start..batch[batch.len() - 1].end, &mut buf)?;
+            for frame in batch {
+                let bytes =
+                    &buf[(frame.start - batch_start) as usize..(frame.end - batch_start) as usize];
+                let borrowed = offset_frame
+                    .process_borrowed(bytes)
+                    .with_context(|| format!("processing frame at ts {}", frame.ts))?;
+                let subframes_len = borrowed.subframes().len() as u64;
+                let subframes_start = frame.end - 2 - subframes_len;
+                let mut header = [0; MAX_FRAME_HEADER_LEN];
+                header[..borrowed.header().len()].copy_from_slice(borrowed.header());
+                rewritten.push(TrackFileFrame {
+                    offset,
+                    header,
+                    header_len: borrowed.header().len(),
+                    subframes: subframes_start..subframes_start + subframes_len,
+                    footer: [borrowed.footer()[0], borrowed.footer()[1]],
+                });
+                offset += borrowed.size() as u64;
+            }
+        }
+        let audio = AudioSummary {
+            warnings,
+            ..offset_frame.summary()
+        };
+
+        // Reserve the same SEEKTABLE space as write_seekable does, so
+        // tracks come out the same either way:
+        let mut seek_points = audio.seek_points.clone();
+        seek_points.resize(
+            track.reserved_seek_points(spacing)?,
+            seek_point(u64::MAX, 0, 0),
+        );
+        let mut metadata = vec![];
+        track.write_metadata(
+            &AudioSummary {
+                seek_points,
+                ..audio.clone()
+            },
+            options.metadata_padding,
+            &mut metadata,
+        )?;
+        let audio_start = metadata.len() as u64;
+        for frame in &mut rewritten {
+            frame.offset += audio_start;
+        }
+        let file = TrackFile {
+            metadata,
+            frames: rewritten,
+            len: audio_start + offset,
+        };
+        Ok((audio, file))
+    }
+
+    /// Reads the given byte range of the image into `buf`.
+    fn read(&self, range: Range<u64>, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        buf.resize((range.end - range.start) as usize, 0);
+        read_exact_at(&self.file, buf, range.start).with_context(|| {
+            format!(
+                "reading bytes {} to {} of the image",
+                range.start, range.end
+            )
+        })
+    }
+}
+
+/// A track of an [IndexedImage] as the FLAC file that
+/// [IndexedImage::write_track] writes, put together on demand.
+#[derive(Debug, Clone)]
+pub struct TrackFile {
+    metadata: Vec<u8>,
+    frames: Vec<TrackFileFrame>,
+    len: u64,
+}
+
+/// Where a frame is in a [TrackFile], and what it's made of.
+#[derive(Debug, Clone)]
+struct TrackFileFrame {
+    /// Where the frame starts in the track file.
+    offset: u64,
+
+    /// The rewritten header and footer, see [crate::BorrowedFrame].
+    header: [u8; MAX_FRAME_HEADER_LEN],
+    header_len: usize,
+    footer: [u8; 2],
+
+    /// The byte range of the frame's subframes in the image file.
+    subframes: Range<u64>,
+}
+
+impl TrackFile {
+    /// The size of the track file, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the track file is empty (which it never is).
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the track file's bytes from `offset` on into `buf`, taking
+    /// the audio from `image` (which the track file must have been
+    /// made from). Returns how many bytes were read, which is less
+    /// than requested only at the end of the file.
+    pub fn read_at(&self, image: &IndexedImage, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        let mut position = offset;
+        while read < buf.len() && position < self.len {
+            let out = &mut buf[read..];
+            let len = if position < self.metadata.len() as u64 {
+                copy_from(&self.metadata, position, out)
+            } else {
+                let frame = &self.frames[self
+                    .frames
+                    .partition_point(|frame| frame.offset <= position)
+                    - 1];
+                let subframes_start = frame.offset + frame.header_len as u64;
+                let footer_start = subframes_start + (frame.subframes.end - frame.subframes.start);
+                if position < subframes_start {
+                    copy_from(
+                        &frame.header[..frame.header_len],
+                        position - frame.offset,
+                        out,
+                    )
+                } else if position < footer_start {
+                    let len = ((footer_start - position) as usize).min(out.len());
+                    let from = frame.subframes.start + (position - subframes_start);
+                    read_exact_at(&image.file, &mut out[..len], from)?;
+                    len
+                } else {
+                    copy_from(&frame.footer, position - footer_start, out)
+                }
+            };
+            read += len;
+            position += len as u64;
+        }
+        Ok(read)
+    }
+}
+
+/// Copies what fits into `out` of `piece` from `from` on, returning
+/// how many bytes that was.
+fn copy_from(piece: &[u8], from: u64, out: &mut [u8]) -> usize {
+    let piece = &piece[from as usize..];
+    let len = piece.len().min(out.len());
+    out[..len].copy_from_slice(&piece[..len]);
+    len
+}
+
+/// Reads exactly `buf.len()` bytes of `file` at `offset`, without
+/// moving its cursor, so that tracks can read the image at the same
+/// time.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Reads exactly `buf.len()` bytes of `file` at `offset`.
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Writes all of `slices` with as few (vectored) writes as `to` allows.
+fn write_all_vectored<W: Write>(to: &mut W, slices: &mut [IoSlice]) -> io::Result<()> {
+    let mut remaining = slices;
+    while !remaining.is_empty() {
+        match to.write_vectored(remaining) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut remaining, written),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// The image file, read ahead in big chunks while finding its frames.
+struct ReadAhead<'a> {
+    file: &'a File,
+    len: u64,
+
+    /// The bytes of the file from `start` on.
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl<'a> ReadAhead<'a> {
+    fn new(file: &'a File) -> io::Result<Self> {
+        Ok(Self {
+            file,
+            len: file.metadata()?.len(),
+            start: 0,
+            bytes: vec![],
+        })
+    }
+
+    /// Returns the `len` bytes of the file from `at` on, or fewer at
+    /// the end of the file.
+    fn bytes(&mut self, at: u64, len: usize) -> io::Result<&[u8]> {
+        let end = (at + len as u64).min(self.len);
+        if at < self.start || end > self.start + self.bytes.len() as u64 {
+            let read_end = (at + len.max(READ_AHEAD) as u64).min(self.len);
+            self.bytes.resize(read_end.saturating_sub(at) as usize, 0);
+            read_exact_at(self.file, &mut self.bytes, at)?;
+            self.start = at;
+        }
+        let from = (at - self.start) as usize;
+        Ok(&self.bytes[from..from + end.saturating_sub(at) as usize])
+    }
+}
+
+/// Finds the frames of a FLAC file.
+///
+/// FLAC frames don't say how long they are, so a frame ends where
+/// the next one starts: at the next frame sync code that starts a
+/// valid header (going by its CRC) for the frame/sample number that
+/// comes next, in the same stream format, and before which the frame's
+/// footer CRC checks out. It's looked for only as far from the frame's
+/// start as frames can be long (see [frame_len_bounds]). The last frame
+/// ends where it can be decoded in full (see [last_frame_end]).
+fn find_frames(file: &File) -> anyhow::Result<Vec<IndexedFrame>> {
+    let audio_start = metadata_end(&mut &*file)?;
+    let mut image = ReadAhead::new(file)?;
+    // The first metadata block is always STREAMINFO:
+    let head = image.bytes(0, 42)?;
+    if head.len() < 42 {
+        bail!("truncated STREAMINFO");
+    }
+    if head[4] & 0x7f != 0 {
+        bail!("no STREAMINFO block");
+    }
+    let streaminfo_bytes = head[8..42].to_vec();
+    let streaminfo = StreamInfo::from_bytes(&streaminfo_bytes);
+
+    let mut frames = vec![];
+    let mut start = audio_start;
+    let mut header = header_at(image.bytes(start, MAX_FRAME_HEADER_LEN)?)
+        .with_context(|| format!("no frame after the metadata, at byte {}", start))?;
+    let mut ts = 0;
+    loop {
+        let expected = if header.variable_block_size {
+            header.number + header.block_samples
+        } else {
+            header.number + 1
+        };
+        let (min_len, max_len) = frame_len_bounds(&streaminfo, &header);
+        // The frame, and the header of the next one:
+        let bytes = image.bytes(start, max_len + MAX_FRAME_HEADER_LEN)?;
+        let next = next_frame(bytes, &header, min_len, expected)
+            .with_context(|| format!("in the frame at byte {}", start))?;
+        let len = match &next {
+            Some((next_start, _)) => *next_start,
+            None => {
+                let frame = &bytes[..bytes.len().min(max_len)];
+                last_frame_end(frame, header.len, &streaminfo_bytes)
+                    .with_context(|| format!("the last frame (at byte {}) is cut off", start))?
+            }
+        };
+        frames.push(IndexedFrame {
+            start,
+            end: start + len as u64,
+            ts,
+            dur: header.block_samples,
+        });
+        ts += header.block_samples;
+        match next {
+            Some((next_start, next_header)) => {
+                start += next_start as u64;
+                header = next_header;
+            }
+            None => break,
+        }
+    }
+    if streaminfo.total_samples != 0 && ts != streaminfo.total_samples {
+        bail!(
+            "found frames for {} samples, but STREAMINFO says there are {}",
+            ts,
+            streaminfo.total_samples
+        );
+    }
+    Ok(frames)
+}
+
+/// How short and how long the frame with the given `header` can be:
+/// what STREAMINFO says, or else no shorter than its header and footer,
+/// and no longer than with its samples stored verbatim (which encoders
+/// fall back to when compressing doesn't pay off), with an extra bit
+/// for side channels, and some room for the subframe headers.
+fn frame_len_bounds(streaminfo: &StreamInfo, header: &FrameHeader) -> (usize, usize) {
+    let min_len = match streaminfo.min_frame_size {
+        0 => header.len + 2,
+        len => len as usize,
+    };
+    let max_len = match streaminfo.max_frame_size {
+        0 => {
+            let channels = usize::from(streaminfo.num_channels);
+            let sample_bits = usize::from(streaminfo.bits_per_sample) + 1;
+            let subframe_len = 2 + (header.block_samples as usize * sample_bits).div_ceil(8);
+            header.len + channels * subframe_len + 2
+        }
+        len => len as usize,
+    };
+    (min_len, max_len)
+}
+
+/// Parses the frame header at the start of `bytes`, if there is a
+/// valid one.
+fn header_at(bytes: &[u8]) -> Option<FrameHeader> {
+    // The frame sync code, followed by a reserved bit that must be 0:
+    if bytes.len() < 4 || bytes[0] != 0xff || bytes[1] & 0xfe != 0xf8 {
+        return None;
+    }
+    let header = FrameHeader::parse(bytes).ok()?;
+    let crc_byte = *bytes.get(header.len - 1)?;
+    let mut crc = Crc8Ccitt::new(0);
+    crc.process_buf_bytes(&bytes[..header.len - 1]);
+    (crc.crc() == crc_byte).then_some(header)
+}
+
+/// Finds the frame after the one at the start of `bytes`, which has
+/// the given `header`, is at least `min_len` bytes long, and whose
+/// successor should be numbered `expected`. Returns where the next
+/// frame starts in `bytes`, `None` if there's no next frame in `bytes`,
+/// and an error if what looks like the next frame doesn't follow a
+/// frame footer with the right CRC.
+fn next_frame(
+    bytes: &[u8],
+    header: &FrameHeader,
+    min_len: usize,
+    expected: u64,
+) -> anyhow::Result<Option<(usize, FrameHeader)>> {
+    // The frame's CRC so far, up to `crc_end`; it is 0 right after
+    // the footer:
+    let mut crc = Crc16Ansi::new(0);
+    let mut crc_end = 0;
+    let mut unverified = None;
+    let mut from = min_len.max(header.len);
+    while let Some(found) = memchr::memchr(0xff, bytes.get(from..).unwrap_or_default()) {
+        let candidate = from + found;
+        if let Some(next) = header_at(&bytes[candidate..]) {
+            // Frames of one stream share their blocking strategy,
+            // sample rate and sample size:
+            if next.variable_block_size == header.variable_block_size
+                && next.number == expected
+                && bytes[candidate + 2] & 0x0f == bytes[2] & 0x0f
+                && bytes[candidate + 3] & 0x0e == bytes[3] & 0x0e
+            {
+                // The audio can hold something that looks like the
+                // next frame's header, so only take it after a footer:
+                crc.process_buf_bytes(&bytes[crc_end..candidate]);
+                crc_end = candidate;
+                if crc.crc() == 0 {
+                    return Ok(Some((candidate, next)));
+                }
+                unverified.get_or_insert(candidate);
+            }
+        }
+        from = candidate + 1;
+    }
+    match unverified {
+        Some(candidate) => bail!(
+            "no valid footer before the frame header {} bytes in",
+            candidate
+        ),
+        None => Ok(None),
+    }
+}
+
+/// Finds where the last frame, which starts at the start of `bytes`
+/// with a header `header_len` bytes long, ends: at the first place
+/// where the CRC of the frame so far is 0, as it is after the footer,
+/// and the frame before the footer decodes (it doesn't when it's cut
+/// short). Anything after that (like an ID3v1 tag, or padding whose
+/// CRC happens to check out too) isn't part of the frame.
+///
+/// `streaminfo` is the content of the image's STREAMINFO block.
+fn last_frame_end(bytes: &[u8], header_len: usize, streaminfo: &[u8]) -> Option<usize> {
+    let mut params = CodecParameters::new();
+    params
+        .for_codec(CODEC_TYPE_FLAC)
+        .with_extra_data(streaminfo.into());
+    let mut decoder = FlacDecoder::try_new(&params, &Default::default()).ok()?;
+
+    let mut crc = Crc16Ansi::new(0);
+    crc.process_buf_bytes(bytes.get(..header_len)?);
+    for (len, byte) in (header_len + 1..).zip(&bytes[header_len..]) {
+        crc.process_byte(*byte);
+        // There's at least one byte of subframes before the footer:
+        if crc.crc() == 0 && len >= header_len + 3 {
+            let frame = &bytes[..len - 2];
+            if decoder
+                .decode(&Packet::new_from_slice(0, 0, 0, frame))
+                .is_ok()
+            {
+                return Some(len);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        open_disc_image, split_one_file,
+        test::{write_test_image, write_test_image_from},
+    };
+
+    #[test]
+    fn test_find_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let len = std::fs::metadata(&image).unwrap().len();
+        // Trailing data after the last frame, like an ID3v1 tag:
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&image)
+            .unwrap();
+        file.write_all(&[b'T'; 128]).unwrap();
+        drop(file);
+
+        let indexed = IndexedImage::open(&image).unwrap();
+        assert_eq!(indexed.frames.len(), 13);
+        assert_eq!(indexed.frames[12].ts, 12 * 4096);
+        assert_eq!(indexed.frames[12].end, len);
+        for pair in indexed.frames.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert_eq!(pair[0].ts + pair[0].dur, pair[1].ts);
+        }
+
+        let tracks = open_disc_image(&image, dir.path(), &SplitOptions::default()).unwrap();
+        let assigned = indexed
+            .assign_frames(tracks.iter().map(|planned| &planned.track))
+            .unwrap();
+        // Frame 4 (samples 16384..20480) straddles the boundary:
+        assert_eq!(assigned, vec![0..5, 5..13]);
+    }
+
+    #[test]
+    fn test_last_frame_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let len = std::fs::metadata(&image).unwrap().len();
+        // Zero bytes keep the CRC of the last frame at 0, so its
+        // footer CRC checks out after each of them:
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&image)
+            .unwrap();
+        file.write_all(&[0; 128]).unwrap();
+        drop(file);
+        let indexed = IndexedImage::open(&image).unwrap();
+        assert_eq!(indexed.frames[12].end, len);
+
+        // A cut-off last frame has no end, and the image gets read
+        // instead:
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&image)
+            .unwrap();
+        file.set_len(len - 10).unwrap();
+        drop(file);
+        let error = IndexedImage::open(&image).err().unwrap();
+        assert!(format!("{:#}", error).contains("cut off"), "{:#}", error);
+    }
+
+    #[test]
+    fn test_truncated_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 71_000]);
+        let indexed = IndexedImage::open(&image).unwrap();
+        let tracks = open_disc_image(&image, dir.path(), &SplitOptions::default()).unwrap();
+        let assigned = indexed
+            .assign_frames(tracks.iter().map(|planned| &planned.track))
+            .unwrap();
+        let file = indexed
+            .track_file(&tracks[2].track, assigned[2].clone(), &Default::default())
+            .unwrap();
+
+        // Truncating the image while it's being split makes reading
+        // it fail:
+        let len = std::fs::metadata(&image).unwrap().len();
+        let truncated = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&image)
+            .unwrap();
+        truncated.set_len(len / 2).unwrap();
+        drop(truncated);
+        let error = indexed
+            .write_track(
+                &tracks[2].track,
+                assigned[2].clone(),
+                &Default::default(),
+                io::sink(),
+            )
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", error).contains("reading bytes"),
+            "{:#}",
+            error
+        );
+        let mut buf = vec![0; 4096];
+        assert!(file.read_at(&indexed, file.len() - 4096, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_frame_header_in_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        // Full-range noise gets stored verbatim, so the samples at
+        // 100..104 put the header of frame 1 into both channels of
+        // frame 0's audio:
+        let header = crate::encode::encode_frame(1, false, 16, &[vec![0; 4096], vec![0; 4096]])
+            .unwrap()[..8]
+            .to_vec();
+        let mut state = 1u32;
+        write_test_image_from(&image, 50_000, &[0, 20_000], 16, |n| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            match n {
+                100..=103 => {
+                    let at = 2 * (n - 100) as usize;
+                    i32::from(i16::from_be_bytes([header[at], header[at + 1]]))
+                }
+                _ => i32::from((state >> 16) as i16),
+            }
+        });
+        let bytes = std::fs::read(&image).unwrap();
+        let headers: Vec<u64> = bytes
+            .windows(header.len())
+            .enumerate()
+            .filter(|(_, window)| *window == header)
+            .map(|(at, _)| at as u64)
+            .collect();
+        assert_eq!(headers.len(), 3);
+
+        let indexed = IndexedImage::open(&image).unwrap();
+        assert_eq!(indexed.frames.len(), 13);
+        assert_eq!(indexed.frames[1].start, headers[2]);
+        // The first track starts at frame 0, so its frames are the
+        // image's, unchanged:
+        let out = dir.path().join("split");
+        let report = split_one_file(&image, &out, &SplitOptions::default()).unwrap();
+        let track = std::fs::read(&report.tracks[0].path).unwrap();
+        let frames = &bytes[indexed.frames[0].start as usize..indexed.frames[4].end as usize];
+        assert!(track.ends_with(frames));
+
+        // Without a frame footer before it, the header is no frame
+        // boundary, and the image can't be indexed:
+        let mut corrupt = bytes.clone();
+        corrupt[headers[2] as usize - 1] ^= 1;
+        std::fs::write(&image, &corrupt).unwrap();
+        let error = IndexedImage::open(&image).err().unwrap();
+        assert!(
+            format!("{:#}", error).contains("no valid footer"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_track_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 30_100, 71_000]);
+        let options = SplitOptions {
+            seekpoint_interval: Some(std::time::Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let indexed = IndexedImage::open(&image).unwrap();
+        let tracks = open_disc_image(&image, dir.path(), &options).unwrap();
+        let assigned = indexed
+            .assign_frames(tracks.iter().map(|planned| &planned.track))
+            .unwrap();
+        for (planned, frames) in tracks.iter().zip(assigned) {
+            let mut written = vec![];
+            indexed
+                .write_track(&planned.track, frames.clone(), &options, &mut written)
+                .unwrap();
+            let file = indexed
+                .track_file(&planned.track, frames, &options)
+                .unwrap();
+            assert_eq!(file.len(), written.len() as u64);
+
+            // Reads of all sizes, at all kinds of offsets:
+            for chunk in [1, 7, 100, 4096, 65536] {
+                let mut read = vec![];
+                let mut buf = vec![0; chunk];
+                loop {
+                    let len = file.read_at(&indexed, read.len() as u64, &mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    read.extend_from_slice(&buf[..len]);
+                }
+                assert!(
+                    read == written,
+                    "track {}, chunk {}",
+                    planned.track.number,
+                    chunk
+                );
+            }
+            let mut buf = [0; 10];
+            assert_eq!(file.read_at(&indexed, file.len() - 3, &mut buf).unwrap(), 3);
+            assert_eq!(buf[..3], written[written.len() - 3..]);
+            assert_eq!(file.read_at(&indexed, file.len() + 5, &mut buf).unwrap(), 0);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Finding the disc images to split in a directory tree.
+
+use crate::find_cue_sheet;
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use metaflac::Block;
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+/// Which files to pick up when walking a directory, going by their
+/// pathnames relative to that directory.
+#[derive(Debug, Clone, Default)]
+pub struct InputFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl InputFilter {
+    /// Creates a filter that picks up files matching any of the
+    /// `include` globs (or all files if there are none), except for
+    /// those matching any of the `exclude` globs.
+    pub fn new(include: &[String], exclude: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Returns whether a file with the given relative pathname should
+    /// be picked up.
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative_path))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(relative_path))
+    }
+}
+
+fn glob_set(globs: &[String]) -> anyhow::Result<Option<GlobSet>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("invalid glob {:?}", glob))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Returns whether a FLAC file is a disc image that can be split: one
+/// with a CUESHEET block or comment, or a CUE sheet next to it.
+/// Plain single-track FLAC files aren't.
+pub fn is_disc_image(path: &Path) -> anyhow::Result<bool> {
+    if find_cue_sheet(path).is_some() {
+        return Ok(true);
+    }
+    let tag = metaflac::Tag::read_from_path(path).with_context(|| format!("reading {:?}", path))?;
+    let has_cue_sheet = tag
+        .blocks()
+        .any(|block| matches!(block, Block::CueSheet(_)))
+        || tag
+            .get_vorbis("CUESHEET")
+            .is_some_and(|mut sheets| sheets.next().is_some());
+    Ok(has_cue_sheet)
+}
+
+/// Walks a directory tree and returns the pathnames of the disc
+/// images in it that `filter` picks up, in order.
+pub fn find_disc_images<P: AsRef<Path> + Debug>(
+    dir: P,
+    filter: &InputFilter,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    let mut images = vec![];
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("walking {:?}", dir))?;
+        let path = entry.path();
+        let is_flac = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("flac"));
+        if !entry.file_type().is_file() || !is_flac {
+            continue;
+        }
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        if !filter.matches(relative) {
+            debug!(?path, "filtered out");
+            continue;
+        }
+        match is_disc_image(path) {
+            Ok(true) => images.push(path.to_path_buf()),
+            Ok(false) => debug!(?path, "not a disc image"),
+            Err(error) => warn!(?path, error = format!("{:#}", error), "skipping"),
+        }
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter =
+            InputFilter::new(&["*.flac".to_string()], &["**/bootlegs/**".to_string()]).unwrap();
+        assert!(filter.matches(Path::new("Artist/Album.flac")));
+        assert!(!filter.matches(Path::new("Artist/Album.wav")));
+        assert!(!filter.matches(Path::new("Artist/bootlegs/Live.flac")));
+        assert!(InputFilter::default().matches(Path::new("anything")));
+    }
+}
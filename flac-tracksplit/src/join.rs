@@ -0,0 +1,576 @@
+//! Joining split tracks back into one disc image with an embedded cue
+//! sheet: the inverse of [crate::split_one_file].
+
+use crate::{
+    resolve_conflict, seek_point, temp_file_in, update_md5, vorbis_comment_block, AudioSummary,
+    ConflictPolicy, Frames, OffsetFrame, SplitOptions, LEAD_OUT_TRACK_NUMBER,
+    NON_CD_LEAD_OUT_TRACK_NUMBER,
+};
+use anyhow::{bail, Context};
+use md5::{Digest, Md5};
+use metaflac::{
+    block::{CueSheet, CueSheetTrack, CueSheetTrackIndex, SeekTable, StreamInfo, VorbisComment},
+    Block,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, File},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use symphonia_bundle_flac::FlacReader;
+use symphonia_core::{formats::FormatReader, io::MediaSourceStream};
+use tracing::{debug, info, instrument};
+
+/// The number of samples in a CD frame, which CD cue sheet offsets
+/// have to be a multiple of.
+const CD_FRAME_SAMPLES: u64 = 588;
+
+/// The tags that don't survive being joined, because they make no
+/// sense for a disc image. (TRACKNUMBER does, as splitting the image
+/// only gives tracks the numbers their comments say.)
+const DROPPED_TAGS: &[&str] = &["CUESHEET"];
+
+/// A track file to be joined into a disc image.
+struct JoinedTrack {
+    path: PathBuf,
+    streaminfo: StreamInfo,
+    /// The track's vorbis comments, by upper-case key.
+    comments: BTreeMap<String, Vec<String>>,
+    pictures: Vec<Block>,
+}
+
+impl JoinedTrack {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let tag =
+            metaflac::Tag::read_from_path(path).with_context(|| format!("reading {:?}", path))?;
+        let streaminfo = tag
+            .get_streaminfo()
+            .with_context(|| format!("{:?} has no STREAMINFO block", path))?
+            .clone();
+        let mut comments: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (key, values) in tag.vorbis_comments().iter().flat_map(|c| &c.comments) {
+            comments
+                .entry(key.to_uppercase())
+                .or_default()
+                .extend(values.iter().cloned());
+        }
+        let pictures = tag
+            .blocks()
+            .filter(|block| matches!(block, Block::Picture(_)))
+            .cloned()
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            streaminfo,
+            comments,
+            pictures,
+        })
+    }
+
+    /// The number in the track's TRACKNUMBER tag (like `3` or `3/12`).
+    fn track_number(&self) -> Option<u8> {
+        let value = self.comments.get("TRACKNUMBER")?.first()?;
+        value.split('/').next()?.trim().parse().ok()
+    }
+}
+
+/// Returns the track files in a directory in the order they should
+/// be joined: by their TRACKNUMBER tag, or else by file name.
+pub fn find_tracks<P: AsRef<Path> + Debug>(dir: P) -> anyhow::Result<Vec<PathBuf>> {
+    let mut tracks = vec![];
+    for entry in fs::read_dir(&dir).with_context(|| format!("reading {:?}", dir))? {
+        let path = entry.with_context(|| format!("reading {:?}", dir))?.path();
+        let is_flac = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("flac"));
+        if is_flac && path.is_file() {
+            let track = JoinedTrack::read(&path)?;
+            tracks.push((track.track_number(), path));
+        }
+    }
+    tracks.sort();
+    Ok(tracks.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Joins track files (in the given order) into a disc image at
+/// `output_path`, with a CUESHEET block marking where each track
+/// starts and `KEY[n]`-style vorbis comments for the tags that differ
+/// between tracks; splitting that image gives back the same tracks.
+///
+/// All tracks need to have the same sample rate, channels and bit
+/// depth. A track numbered 0 (hidden audio before track 1) becomes the
+/// pregap of the track after it. The image's MD5 signature is always
+/// computed, and its frames are numbered by their first sample, since
+/// the tracks' last frames can be shorter than the others.
+///
+/// Returns where the image was written, which differs from
+/// `output_path` with [crate::ConflictPolicy::Rename], or `None` if it
+/// was skipped because it already exists.
+#[instrument(skip(options), err)]
+pub fn join_tracks<P: AsRef<Path> + Debug, O: AsRef<Path> + Debug>(
+    track_paths: &[P],
+    output_path: O,
+    options: &SplitOptions,
+) -> anyhow::Result<Option<PathBuf>> {
+    let tracks = track_paths
+        .iter()
+        .map(|path| JoinedTrack::read(path.as_ref()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let Some(first) = tracks.first() else {
+        bail!("no tracks to join");
+    };
+    let format = |info: &StreamInfo| (info.sample_rate, info.num_channels, info.bits_per_sample);
+    for track in &tracks {
+        if format(&track.streaminfo) != format(&first.streaminfo) {
+            bail!(
+                "{:?} has a different sample rate, channel count or bit depth than {:?}",
+                track.path,
+                first.path
+            );
+        }
+        if track.streaminfo.total_samples == 0 {
+            bail!("{:?} doesn't say how many samples it has", track.path);
+        }
+    }
+    let Some(output_path) = resolve_conflict(output_path.as_ref(), options.on_conflict)? else {
+        info!(?output_path, "image already exists, skipping");
+        return Ok(None);
+    };
+
+    // Each track starts where the ones before it end:
+    let starts: Vec<u64> = tracks
+        .iter()
+        .scan(0, |total, track| {
+            let start = *total;
+            *total += track.streaminfo.total_samples;
+            Some(start)
+        })
+        .collect();
+    let total_samples = tracks
+        .iter()
+        .map(|track| track.streaminfo.total_samples)
+        .sum();
+    let cue_sheet = cue_sheet(&tracks, &starts, total_samples);
+    let comments = joined_comments(&tracks, &cue_sheet);
+    let seekpoint_spacing = options
+        .seekpoint_interval
+        .map(|interval| (interval.as_secs_f64() * f64::from(first.streaminfo.sample_rate)) as u64)
+        .filter(|spacing| *spacing > 0);
+    // A seek point is recorded at most once per spacing:
+    let reserved_seek_points = match seekpoint_spacing {
+        Some(spacing) => usize::try_from(total_samples / spacing + 1)?,
+        None => 0,
+    };
+    let placeholder = seek_point(u64::MAX, 0, 0);
+    let metadata = |audio: &AudioSummary, md5: Vec<u8>| {
+        let streaminfo = StreamInfo {
+            total_samples: audio.total_samples,
+            md5,
+            min_block_size: audio.min_block_size,
+            max_block_size: audio.max_block_size,
+            min_frame_size: audio.min_frame_size,
+            max_frame_size: audio.max_frame_size,
+            ..first.streaminfo.clone()
+        };
+        let mut blocks = vec![Block::StreamInfo(streaminfo)];
+        if reserved_seek_points > 0 {
+            let mut seekpoints = audio.seek_points.clone();
+            seekpoints.resize(reserved_seek_points, placeholder);
+            blocks.push(Block::SeekTable(SeekTable { seekpoints }));
+        }
+        blocks.push(Block::CueSheet(cue_sheet.clone()));
+        blocks.push(vorbis_comment_block(&VorbisComment {
+            vendor_string: "flac-tracksplit".to_string(),
+            comments: comments.clone().into_iter().collect(),
+        }));
+        blocks.extend(first.pictures.iter().cloned());
+        blocks.push(Block::Padding(options.metadata_padding));
+        blocks
+    };
+
+    let dir = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir).context("creating output dir")?;
+    let mut f =
+        temp_file_in(dir).with_context(|| format!("creating temporary file in {:?}", dir))?;
+    // The metadata goes first, and gets rewritten in place once the
+    // audio it describes is known:
+    write_blocks(
+        &metadata(&AudioSummary::default(), vec![0; 16]),
+        f.as_file_mut(),
+    )?;
+    let audio_start = f.stream_position()?;
+
+    let mut frame = OffsetFrame::new(seekpoint_spacing).with_variable_block_size();
+    let mut md5 = Md5::new();
+    let mut writer = BufWriter::new(f.as_file_mut());
+    for track in &tracks {
+        let file = File::open(&track.path).with_context(|| format!("opening {:?}", track.path))?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let reader = FlacReader::try_new(mss, &Default::default())
+            .with_context(|| format!("reading {:?}", track.path))?;
+        let mut frames = Frames::new(reader, options)?;
+        let mut position = 0;
+        while position < track.streaminfo.total_samples {
+            let packet = frames
+                .next_packet()
+                .with_context(|| format!("reading {:?} at sample {}", track.path, position))?;
+            let samples = frames
+                .decode(&packet)
+                .with_context(|| format!("decoding {:?} at sample {}", track.path, position))?;
+            update_md5(&mut md5, &samples, frames.bits_per_sample);
+            position += samples.first().map_or(0, |channel| channel.len() as u64);
+            writer
+                .write_all(
+                    &frame
+                        .process(packet)
+                        .with_context(|| format!("processing frame of {:?}", track.path))?,
+                )
+                .context("writing frame")?;
+        }
+        // The cue sheet is made from where the tracks say they end:
+        if position != track.streaminfo.total_samples {
+            bail!(
+                "{:?} has {} samples, but its STREAMINFO says {}",
+                track.path,
+                position,
+                track.streaminfo.total_samples
+            );
+        }
+        debug!(path = ?track.path, samples = position, "joined track");
+    }
+    writer.flush().context("writing frames")?;
+    drop(writer);
+
+    let audio = frame.summary();
+    if audio.seek_points.len() > reserved_seek_points {
+        bail!(
+            "{} seek points don't fit into the {} reserved",
+            audio.seek_points.len(),
+            reserved_seek_points
+        );
+    }
+    let mut header = vec![];
+    write_blocks(&metadata(&audio, md5.finalize().to_vec()), &mut header)?;
+    if header.len() as u64 != audio_start {
+        bail!(
+            "metadata changed size from {} to {} bytes",
+            audio_start,
+            header.len()
+        );
+    }
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&header).context("rewriting metadata")?;
+    if options.on_conflict == ConflictPolicy::Overwrite {
+        f.persist(&output_path)
+            .with_context(|| format!("moving {:?} into place", output_path))?;
+    } else {
+        f.persist_noclobber(&output_path)
+            .with_context(|| format!("moving {:?} into place", output_path))?;
+    }
+    Ok(Some(output_path))
+}
+
+/// Writes the `fLaC` marker and metadata blocks that start a FLAC
+/// stream; the last block is marked as such.
+fn write_blocks<W: Write>(blocks: &[Block], mut to: W) -> anyhow::Result<()> {
+    to.write_all(b"fLaC")?;
+    for (i, block) in blocks.iter().enumerate() {
+        block
+            .write_to(i + 1 == blocks.len(), &mut to)
+            .with_context(|| format!("writing block {:?}", block))?;
+    }
+    Ok(())
+}
+
+/// Returns a cue sheet with a track starting at each of `starts`;
+/// tracks numbered 0 become the next track's pregap (INDEX 00).
+fn cue_sheet(tracks: &[JoinedTrack], starts: &[u64], total_samples: u64) -> CueSheet {
+    let info = &tracks[0].streaminfo;
+    let is_cd = (info.sample_rate, info.num_channels, info.bits_per_sample) == (44100, 2, 16)
+        && starts
+            .iter()
+            .chain([&total_samples])
+            .all(|offset| offset % CD_FRAME_SAMPLES == 0);
+
+    // Use the tracks' own numbers if they're usable:
+    let numbers: Vec<Option<u8>> = tracks.iter().map(JoinedTrack::track_number).collect();
+    let numbered: Vec<u8> = numbers
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|n| *n != 0)
+        .collect();
+    let usable = numbered.len() == numbers.iter().filter(|n| **n != Some(0)).count()
+        && numbered.windows(2).all(|pair| pair[0] < pair[1])
+        && numbered
+            .iter()
+            .all(|n| u32::from(*n) < LEAD_OUT_TRACK_NUMBER);
+
+    let mut sheet_tracks: Vec<CueSheetTrack> = vec![];
+    let mut pregap_start = None;
+    let mut next_number = 1;
+    for (i, (number, start)) in numbers.iter().zip(starts).enumerate() {
+        if *number == Some(0) && i + 1 < tracks.len() {
+            pregap_start = Some(*start);
+            continue;
+        }
+        let number = match number {
+            Some(number) if usable && *number != 0 => *number,
+            _ => next_number,
+        };
+        next_number = number.saturating_add(1);
+        let mut indices = vec![];
+        let offset = match pregap_start.take() {
+            Some(pregap_start) => {
+                indices.push(CueSheetTrackIndex {
+                    offset: 0,
+                    point_num: 0,
+                });
+                indices.push(CueSheetTrackIndex {
+                    offset: start - pregap_start,
+                    point_num: 1,
+                });
+                pregap_start
+            }
+            None => {
+                indices.push(CueSheetTrackIndex {
+                    offset: 0,
+                    point_num: 1,
+                });
+                *start
+            }
+        };
+        let isrc = tracks[i]
+            .comments
+            .get("ISRC")
+            .and_then(|isrcs| isrcs.first())
+            .filter(|isrc| isrc.len() == 12 && isrc.is_ascii())
+            .cloned()
+            .unwrap_or_default();
+        sheet_tracks.push(CueSheetTrack {
+            offset,
+            number,
+            isrc,
+            is_audio: true,
+            pre_emphasis: false,
+            indices,
+        });
+    }
+    sheet_tracks.push(CueSheetTrack {
+        offset: total_samples,
+        number: if is_cd {
+            LEAD_OUT_TRACK_NUMBER as u8
+        } else {
+            NON_CD_LEAD_OUT_TRACK_NUMBER as u8
+        },
+        indices: vec![],
+        ..CueSheetTrack::new()
+    });
+    CueSheet {
+        catalog_num: String::new(),
+        // The two seconds of silence before a CD's first track:
+        num_leadin: if is_cd { 2 * 44100 } else { 0 },
+        is_cd,
+        tracks: sheet_tracks,
+    }
+}
+
+/// Returns the vorbis comments of a disc image joined from `tracks`:
+/// the tags all tracks agree on as they are, the others suffixed with
+/// their track's number on the cue sheet. A track 0 that became the
+/// first track's pregap keeps its number, as splitting the image turns
+/// that pregap back into a track 0.
+fn joined_comments(tracks: &[JoinedTrack], cue_sheet: &CueSheet) -> BTreeMap<String, Vec<String>> {
+    // The tracks on the cue sheet (without the lead-out), and a
+    // leading track 0; later tracks 0 only survive as pregaps:
+    let mut sheet_numbers = cue_sheet.tracks.iter().map(|track| track.number);
+    let numbered: Vec<(u8, &JoinedTrack)> = tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, track)| match track.track_number() {
+            Some(0) if i == 0 && i + 1 < tracks.len() => Some((0, track)),
+            Some(0) if i + 1 < tracks.len() => None,
+            _ => Some((sheet_numbers.next()?, track)),
+        })
+        .collect();
+    let mut comments = BTreeMap::new();
+    let keys = numbered
+        .iter()
+        .flat_map(|(_, track)| track.comments.keys())
+        .filter(|key| !DROPPED_TAGS.contains(&key.as_str()));
+    for key in keys {
+        if comments.contains_key(key) {
+            continue;
+        }
+        let first = numbered[0].1.comments.get(key);
+        let shared = numbered
+            .iter()
+            .all(|(_, track)| track.comments.get(key) == first);
+        if shared {
+            comments.insert(key.clone(), first.cloned().unwrap_or_default());
+            continue;
+        }
+        for (number, track) in &numbered {
+            if let Some(values) = track.comments.get(key) {
+                comments.insert(format!("{}[{}]", key, number), values.clone());
+            }
+        }
+        // Don't look at this key again:
+        comments.entry(key.clone()).or_default();
+    }
+    comments.retain(|_, values| !values.is_empty());
+    comments
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        split_one_file,
+        test::{decode_file, write_test_image},
+    };
+
+    fn track(number: Option<&str>, title: &str, artist: &str) -> JoinedTrack {
+        let mut comments = BTreeMap::new();
+        if let Some(number) = number {
+            comments.insert("TRACKNUMBER".to_string(), vec![number.to_string()]);
+        }
+        comments.insert("TITLE".to_string(), vec![title.to_string()]);
+        comments.insert("ARTIST".to_string(), vec![artist.to_string()]);
+        JoinedTrack {
+            path: PathBuf::from(format!("{}.flac", title)),
+            streaminfo: StreamInfo {
+                sample_rate: 44100,
+                num_channels: 2,
+                bits_per_sample: 16,
+                ..StreamInfo::new()
+            },
+            comments,
+            pictures: vec![],
+        }
+    }
+
+    #[test]
+    fn test_cue_sheet_and_comments() {
+        let tracks = vec![
+            track(Some("0"), "Hidden", "Band"),
+            track(Some("1/3"), "One", "Band"),
+            track(Some("2/3"), "Two", "Band"),
+            track(Some("3/3"), "Three", "Band feat. Guest"),
+        ];
+        let sheet = cue_sheet(&tracks, &[0, 588, 1176, 1200], 2000);
+        assert!(!sheet.is_cd);
+        let summary: Vec<_> = sheet
+            .tracks
+            .iter()
+            .map(|track| {
+                let indices: Vec<_> = track
+                    .indices
+                    .iter()
+                    .map(|index| (index.point_num, index.offset))
+                    .collect();
+                (track.number, track.offset, indices)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, 0, vec![(0, 0), (1, 588)]),
+                (2, 1176, vec![(1, 0)]),
+                (3, 1200, vec![(1, 0)]),
+                (255, 2000, vec![]),
+            ]
+        );
+
+        let comments = joined_comments(&tracks, &sheet);
+        let expected: BTreeMap<String, Vec<String>> = [
+            ("ARTIST[0]", "Band"),
+            ("ARTIST[1]", "Band"),
+            ("ARTIST[2]", "Band"),
+            ("ARTIST[3]", "Band feat. Guest"),
+            ("TITLE[0]", "Hidden"),
+            ("TITLE[1]", "One"),
+            ("TITLE[2]", "Two"),
+            ("TITLE[3]", "Three"),
+            ("TRACKNUMBER[0]", "0"),
+            ("TRACKNUMBER[1]", "1/3"),
+            ("TRACKNUMBER[2]", "2/3"),
+            ("TRACKNUMBER[3]", "3/3"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), vec![value.to_string()]))
+        .collect();
+        assert_eq!(comments, expected);
+
+        let tracks = vec![track(None, "One", "Band"), track(None, "Two", "Band")];
+        let sheet = cue_sheet(&tracks, &[0, 588], 1176);
+        assert!(sheet.is_cd);
+        let numbers: Vec<_> = sheet.tracks.iter().map(|track| track.number).collect();
+        assert_eq!(numbers, vec![1, 2, 170]);
+        assert_eq!(joined_comments(&tracks, &sheet)["ARTIST"], vec!["Band"]);
+        // A CD's lead-out is on a CD frame boundary, too:
+        assert!(!cue_sheet(&tracks, &[0, 588], 1177).is_cd);
+    }
+
+    /// The vorbis comments of a FLAC file.
+    fn read_comments(path: &Path) -> BTreeMap<String, Vec<String>> {
+        let tag = metaflac::Tag::read_from_path(path).unwrap();
+        tag.vorbis_comments()
+            .unwrap()
+            .comments
+            .clone()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_join_and_split_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 71_000]);
+        let options = SplitOptions {
+            flat_output: true,
+            ..Default::default()
+        };
+        let split = split_one_file(&image, dir.path().join("tracks"), &options).unwrap();
+        // Tag the tracks the way a ripper might, with the hidden audio
+        // before track 1 as track 0:
+        let tracks: Vec<PathBuf> = split.written_paths().cloned().collect();
+        let tags = [
+            ("0", "Intro", "Band"),
+            ("1", "One", "Band"),
+            ("2", "Two", "Band feat. Guest"),
+        ];
+        for (path, (number, title, artist)) in tracks.iter().zip(tags) {
+            let mut tag = metaflac::Tag::read_from_path(path).unwrap();
+            let comments = tag.vorbis_comments_mut();
+            comments.comments.clear();
+            comments.set("TRACKNUMBER", vec![number]);
+            comments.set("TITLE", vec![title]);
+            comments.set("ARTIST", vec![artist]);
+            comments.set("ALBUM", vec!["Album"]);
+            tag.save().unwrap();
+        }
+
+        let joined = dir.path().join("joined.flac");
+        let join_options = SplitOptions {
+            seekpoint_interval: Some(std::time::Duration::from_secs(1)),
+            ..Default::default()
+        };
+        join_tracks(&tracks, &joined, &join_options).unwrap();
+        assert_eq!(decode_file(&joined), decode_file(&image));
+
+        let again = split_one_file(&joined, dir.path().join("again"), &options).unwrap();
+        let numbers: Vec<u32> = again.tracks.iter().map(|track| track.number).collect();
+        assert_eq!(numbers, vec![0, 1, 2]);
+        for (original, split) in tracks.iter().zip(again.written_paths()) {
+            assert_eq!(decode_file(split), decode_file(original), "{:?}", split);
+            assert_eq!(read_comments(split), read_comments(original), "{:?}", split);
+        }
+    }
+}
@@ -1,39 +1,181 @@
 use anyhow::{bail, Context};
+use cuesheet::CueSheet;
+use discs::DiscLayout;
+use encoding_rs::Encoding;
+use indexed::IndexedImage;
 use int_conv::Truncate;
+use md5::{Digest, Md5};
 use metaflac::{
-    block::{Picture, PictureType, StreamInfo, VorbisComment},
+    block::{Picture, PictureType, SeekPoint, SeekTable, StreamInfo, VorbisComment},
     Block,
 };
-use more_asserts as ma;
+use rayon::prelude::*;
+use report::{SplitReport, TrackReport};
+use silence::SilenceOptions;
+use split_points::SplitPoints;
 use std::{
     borrow::Cow,
+    collections::HashSet,
     fmt::Debug,
     fs::{create_dir_all, File},
-    io::Write,
+    io::{BufWriter, Seek, Write},
     num::NonZeroU32,
+    ops::Range,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
-use symphonia_bundle_flac::FlacReader;
+use symphonia_bundle_flac::{FlacDecoder, FlacReader};
 use symphonia_core::{
+    audio::{AudioBufferRef, Signal},
     checksum::{Crc16Ansi, Crc8Ccitt},
-    formats::{Cue, FormatReader, Packet},
-    io::{MediaSourceStream, Monitor, ReadBytes},
-    meta::{StandardVisualKey, Tag, Value, Visual},
+    codecs::Decoder,
+    formats::{Cue, FormatReader, Packet, SeekMode, SeekTo},
+    io::{BufReader, MediaSourceStream, Monitor, ReadBytes},
+    meta::{StandardTagKey, StandardVisualKey, Tag, Value, Visual},
 };
+use template::PathTemplate;
 use tracing::{debug, info, instrument};
 
-#[instrument(skip(base_path, metadata_padding), err)]
-pub fn split_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
+pub mod cuesheet;
+pub mod discs;
+mod encode;
+pub mod indexed;
+pub mod inputs;
+pub mod join;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub mod mount;
+pub mod report;
+pub mod silence;
+pub mod split_points;
+pub mod sync;
+pub mod tag_file;
+pub mod template;
+pub mod verify;
+
+/// Options controlling how [split_one_file] splits up a disc image.
+#[derive(Debug, Clone, Default)]
+pub struct SplitOptions {
+    /// Number of 0-byte padding to add to the end of each track's
+    /// metadata blocks.
+    pub metadata_padding: u32,
+
+    /// Cut tracks at the exact sample given by the CUE sheet instead
+    /// of at the end of the FLAC frame containing it. This decodes
+    /// and re-encodes the frames that straddle a track boundary;
+    /// all other frames are still copied verbatim.
+    pub sample_accurate: bool,
+
+    /// Decode each track's audio while splitting, and store the MD5
+    /// signature of its samples in the track's STREAMINFO block (left
+    /// zeroed otherwise, which means "unknown").
+    pub compute_md5: bool,
+
+    /// Write a SEEKTABLE block into each track, with a seek point
+    /// about every so often. Players can then seek in tracks without
+    /// having to bisect the whole file.
+    pub seekpoint_interval: Option<Duration>,
+
+    /// Take the track layout from this CUE sheet file instead of the
+    /// image's embedded CUESHEET block. If unset and the image has no
+    /// embedded cue sheet, a `.cue` file next to the image is used.
+    pub cue_sheet: Option<PathBuf>,
+
+    /// The encoding of cue sheets that aren't valid UTF-8. If unset,
+    /// the encoding is guessed from the sheet's contents.
+    pub cue_encoding: Option<&'static Encoding>,
+
+    /// Which track gets the pregap audio before each track's INDEX 01.
+    pub pregap: PregapMode,
+
+    /// Split at these points instead of at the tracks of a cue sheet.
+    pub split_points: Option<SplitPoints>,
+
+    /// Split at the silent gaps in the audio instead of at the tracks
+    /// of a cue sheet.
+    pub detect_silence: Option<SilenceOptions>,
+
+    /// Name output files after this template instead of
+    /// `<Album Artist>/<Year> - <Album>/<Trackno>.<Title>.flac`.
+    pub path_template: Option<PathTemplate>,
+
+    /// How to keep the discs of a multi-disc release apart in output
+    /// pathnames (unless a [SplitOptions::path_template] is given).
+    pub disc_layout: DiscLayout,
+
+    /// Tags to set on all tracks (or, with a `[n]` suffix, on track
+    /// n), replacing the image's tags of the same name.
+    pub tags: Vec<Tag>,
+
+    /// What to do about output files that already exist.
+    pub on_conflict: ConflictPolicy,
+
+    /// Output files that get replaced whatever the
+    /// [SplitOptions::on_conflict] policy, like the tracks a previous
+    /// split of the same disc image wrote.
+    pub replaceable: Vec<PathBuf>,
+
+    /// Put tracks right into the base path, named like
+    /// `01.Title.flac` (or `2-01.Title.flac` on multi-disc releases),
+    /// instead of into artist and album directories. Used when a
+    /// [SplitOptions::path_template] isn't given.
+    pub flat_output: bool,
+}
+
+/// What [split_one_file] does when a track's output file already
+/// exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Stop with an error.
+    #[default]
+    Fail,
+
+    /// Leave the existing file alone and don't write the track.
+    Skip,
+
+    /// Replace the existing file.
+    Overwrite,
+
+    /// Write the track under a new name, like `01.Title (1).flac`.
+    Rename,
+}
+
+/// Where [split_one_file] puts a track's pregap, the audio between its
+/// INDEX 00 and INDEX 01 (usually silence, but sometimes a count-in or
+/// the end of a live track's applause).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PregapMode {
+    /// Append the pregap to the end of the previous track, like a CD
+    /// player does.
+    #[default]
+    Append,
+
+    /// Prepend the pregap to the start of its own track.
+    Prepend,
+
+    /// Leave the pregap out of all tracks.
+    Discard,
+}
+
+/// What splitting a disc image would write for one of its tracks, as
+/// returned by [plan_one_file].
+#[derive(Debug, Clone)]
+pub struct PlannedTrack {
+    /// The track's output pathname.
+    pub path: PathBuf,
+
+    /// The track's position in the disc image, its tags and pictures.
+    pub track: Track,
+}
+
+/// Opens a disc image and works out its tracks and their output
+/// pathnames under `base_path`, without writing anything.
+pub(crate) fn open_disc_image<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
     input_path: P,
     base_path: B,
-    metadata_padding: u32,
-) -> anyhow::Result<Vec<PathBuf>> {
-    let file = File::open(&input_path).with_context(|| format!("opening {:?}", input_path))?;
-    let file_length = file.metadata().context("file metadata")?.len();
-    let mss = MediaSourceStream::new(Box::new(file), Default::default());
-    let mut reader =
-        FlacReader::try_new(mss, &Default::default()).context("could not create flac reader")?;
+    options: &SplitOptions,
+) -> anyhow::Result<Vec<PlannedTrack>> {
+    let mut reader = open_reader(input_path.as_ref())?;
     debug!("tracks: {:?}", reader.tracks());
     let track = reader.default_track().context("no default track")?;
     let data = match &track.codec_params.extra_data {
@@ -41,7 +183,10 @@ pub fn split_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
         _ => bail!("Unclear track codec params - Not a flac file?"),
     };
     let info = StreamInfo::from_bytes(data);
-    let cues = reader.cues().to_vec();
+    let mut cues = reader.cues().to_vec();
+    if !cues.is_empty() {
+        tag_index_numbers(&mut cues, input_path.as_ref())?;
+    }
     let time_base = track.codec_params.time_base.context("track time base")?;
     if time_base.numer != 1 {
         bail!(
@@ -56,55 +201,570 @@ pub fn split_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
     // symphonia's TimeBase, we can assume that the time stamps are in
     // samples:
     let last_ts: u64 = info.total_samples;
-
-    let mut track_paths = vec![];
-    let mut cue_iter = cues.iter().peekable();
-    let mut audio_buffer = Vec::with_capacity(file_length.try_into().unwrap());
-    while let Some(cue) = cue_iter.next() {
-        audio_buffer.clear();
-        let next = cue_iter.peek();
-        let end_ts = match next {
-            None => last_ts, // no lead-out, fudge it.
-            Some(track) if track.index == LEAD_OUT_TRACK_NUMBER => {
-                // we have a lead-out, capture the whole in the last track.
-                let end_ts = track.start_ts;
-                cue_iter.next();
-                end_ts
-            }
-            Some(track) => track.start_ts,
-        };
-        let track = {
-            let metadata = reader.metadata();
-            let current_metadata = metadata.current().context("track tags")?;
-            let tags = current_metadata.tags();
-            let visuals = current_metadata.visuals();
-            Track::from_tags(&info, cue, end_ts, tags, visuals)
+    let (mut tags, visuals) = {
+        let metadata = reader.metadata();
+        let current_metadata = metadata.current().context("track tags")?;
+        (
+            current_metadata.tags().to_vec(),
+            current_metadata.visuals().to_vec(),
+        )
+    };
+    if let Some(silence) = &options.detect_silence {
+        let detected = silence::detect_silence(&input_path, silence)?;
+        info!(tracks = detected.starts.len() + 1, "detected silent gaps");
+        cues = SplitPoints::Samples(detected.starts).cues(info.sample_rate, last_ts)?;
+    } else if let Some(points) = &options.split_points {
+        cues = points.cues(info.sample_rate, last_ts)?;
+    } else {
+        let external_sheet = match &options.cue_sheet {
+            Some(path) => Some(CueSheet::read(path, options.cue_encoding)?),
+            None if cues.is_empty() => match find_cue_sheet(input_path.as_ref()) {
+                Some(path) => {
+                    info!(?path, "using cue sheet next to disc image");
+                    Some(CueSheet::read(path, options.cue_encoding)?)
+                }
+                None => None,
+            },
+            None => None,
         };
-        debug!(number = track.number, output = ?track.pathname(), "Track");
-        let pathbuf = base_path.as_ref().join(track.pathname());
-        let path = &pathbuf;
-        if let Some(parent) = path.parent() {
-            create_dir_all(parent).context("creating album dir")?;
+        let cue_sheet = external_sheet.or_else(|| cue_sheet_comment(&tags));
+        if let Some(sheet) = &cue_sheet {
+            if options.cue_sheet.is_some() || cues.is_empty() {
+                cues = sheet.cues(info.sample_rate);
+            } else {
+                // The sheet came from the CUESHEET comment; the track
+                // layout still comes from the CUESHEET block.
+                merge_cue_sheet_tags(&mut cues, sheet);
+            }
+            merge_missing_tags(&mut tags, sheet.album_tags());
         }
-        let mut f = File::create(path).unwrap();
-        let sample_count = track
-            .write_audio(&mut reader, &mut audio_buffer)
-            .with_context(|| format!("buffering track {:?} audio", path))?;
+    }
+    let overridden: HashSet<&str> = options.tags.iter().map(|tag| tag.key.as_str()).collect();
+    tags.retain(|tag| !overridden.contains(tag.key.as_str()));
+    tags.extend(options.tags.iter().cloned());
+
+    let tracks = track_cues(&cues, last_ts, options.pregap)
+        .into_iter()
+        .map(|(cue, end_ts)| {
+            let track = Track::from_tags(&info, &cue, end_ts, &tags, &visuals);
+            let pathname = match &options.path_template {
+                Some(template) => track.pathname_with(template),
+                None if options.flat_output => track
+                    .pathname_in(DiscLayout::Prefix)
+                    .file_name()
+                    .expect("track pathnames end in a file name")
+                    .into(),
+                None => track.pathname_in(options.disc_layout),
+            };
+            debug!(number = track.number, output = ?pathname, "Track");
+            PlannedTrack {
+                path: base_path.as_ref().join(pathname),
+                track,
+            }
+        })
+        .collect();
+    Ok(tracks)
+}
+
+/// Opens a FLAC file for reading its frames.
+fn open_reader(path: &Path) -> anyhow::Result<FlacReader> {
+    let file = File::open(path).with_context(|| format!("opening {:?}", path))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    FlacReader::try_new(mss, &Default::default()).context("could not create flac reader")
+}
+
+/// Works out which tracks [split_one_file] would write, where to and
+/// with which tags, without creating any files.
+#[instrument(skip(base_path, options), err)]
+pub fn plan_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
+    input_path: P,
+    base_path: B,
+    options: &SplitOptions,
+) -> anyhow::Result<Vec<PlannedTrack>> {
+    open_disc_image(input_path, base_path, options)
+}
 
-        track
-            .write_metadata(sample_count, metadata_padding, &mut f)
+/// Splits a disc image into one FLAC file per track under
+/// `base_path`, and reports what was written.
+#[instrument(skip(base_path, options), err)]
+pub fn split_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
+    input_path: P,
+    base_path: B,
+    options: &SplitOptions,
+) -> anyhow::Result<SplitReport> {
+    let mut report = SplitReport {
+        input: input_path.as_ref().to_path_buf(),
+        ..Default::default()
+    };
+    let tracks = open_disc_image(&input_path, base_path, options)?;
+    let input_path = input_path.as_ref();
+
+    // Tracks made of whole frames get them copied straight out of the
+    // image, once its frames are found:
+    let indexed = if options.sample_accurate || options.compute_md5 {
+        None
+    } else {
+        match IndexedImage::open(input_path) {
+            Ok(image) => {
+                let frames = image.assign_frames(tracks.iter().map(|planned| &planned.track))?;
+                Some((image, frames))
+            }
+            Err(error) => {
+                info!(
+                    error = format!("{:#}", error),
+                    "Can't index disc image, reading its frames instead"
+                );
+                None
+            }
+        }
+    };
+
+    // Each track reads the image on its own, so they can all be
+    // written at the same time:
+    let written = tracks
+        .par_iter()
+        .enumerate()
+        .map(|(index, PlannedTrack { path, track })| {
+            let mut frames = match &indexed {
+                Some((image, assigned)) => TrackFrames::Indexed(image, assigned[index].clone()),
+                None => TrackFrames::Read(Box::new(frames_for_track(
+                    input_path, &tracks, index, options,
+                )?)),
+            };
+            let on_conflict = if options.replaceable.contains(path) {
+                ConflictPolicy::Overwrite
+            } else {
+                options.on_conflict
+            };
+            let Some(path) = resolve_conflict(path, on_conflict)? else {
+                info!(output = ?path, "Skipping track, output file exists");
+                // Its frames still get counted, to report on them:
+                let audio = match &mut frames {
+                    TrackFrames::Indexed(image, range) => {
+                        Ok(image.skip_track(track, range.clone()))
+                    }
+                    TrackFrames::Read(frames) => track.skip_audio(frames),
+                }
+                .with_context(|| format!("reading track {:?} audio", path))?;
+                return Ok(TrackReport::new(track, path.clone(), &audio, None));
+            };
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            create_dir_all(parent).context("creating album dir")?;
+            let mut f = temp_file_in(parent)
+                .with_context(|| format!("creating temporary file for track {:?}", path))?;
+            let audio = match &mut frames {
+                TrackFrames::Indexed(image, range) => {
+                    image.write_track(track, range.clone(), options, f.as_file_mut())
+                }
+                TrackFrames::Read(frames) => {
+                    track.write_seekable(frames, options.metadata_padding, f.as_file_mut())
+                }
+            }
             .with_context(|| format!("writing track {:?}", path))?;
-        f.write_all(&audio_buffer)
-            .with_context(|| format!("writing track {:?} audio", path))?;
-        track_paths.push(pathbuf);
+            let bytes_written = f
+                .stream_position()
+                .with_context(|| format!("writing track {:?}", path))?;
+            let persisted = match on_conflict {
+                ConflictPolicy::Overwrite => f.persist(&path),
+                _ => f.persist_noclobber(&path),
+            };
+            persisted.with_context(|| format!("moving track {:?} into place", path))?;
+            Ok(TrackReport::new(track, path, &audio, Some(bytes_written)))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for track in written {
+        if track.skipped {
+            report
+                .warnings
+                .push(format!("skipped {:?}: the file already exists", track.path));
+        }
+        report.tracks.push(track);
     }
     info!("Done with disc image");
-    Ok(track_paths)
+    Ok(report)
+}
+
+/// Where [split_one_file] gets a track's frames from.
+enum TrackFrames<'a> {
+    /// A range of whole frames of the disc image, copied as they are.
+    Indexed(&'a IndexedImage, Range<usize>),
+
+    /// Frames read (and decoded, if need be) from the disc image.
+    Read(Box<Frames>),
+}
+
+/// Rewrites the tags and pictures of the tracks that were split from a
+/// disc image earlier (see [Track::rewrite_metadata]), e.g. after
+/// fixing the image's tags. Tracks without an output file are
+/// skipped. Returns the pathnames of the files that were updated.
+#[instrument(skip(base_path, options), err)]
+pub fn retag_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
+    input_path: P,
+    base_path: B,
+    options: &SplitOptions,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut retagged = vec![];
+    for PlannedTrack { path, track } in open_disc_image(input_path, base_path, options)? {
+        if !path.is_file() {
+            info!(output = ?path, "Track file doesn't exist, not retagging it");
+            continue;
+        }
+        if track.rewrite_metadata(&path, options.metadata_padding)? {
+            info!(output = ?path, "Rewrote track, new metadata didn't fit");
+        }
+        retagged.push(path);
+    }
+    info!("Done with disc image");
+    Ok(retagged)
+}
+
+/// Writes one track of a disc image to `to` as a complete FLAC
+/// stream, the same one [split_one_file] writes for it (with the same
+/// options), without reading the tracks before it: the image's
+/// SEEKTABLE (or else a binary search over its frames) locates the
+/// track's first frame. The audio is streamed into `to`, whose
+/// metadata then gets rewritten (see [Track::write_seekable]). Returns
+/// the track.
+#[instrument(skip(options, to), err)]
+pub fn extract_track<P: AsRef<Path> + Debug, W: Write + Seek>(
+    input_path: P,
+    track_number: u32,
+    options: &SplitOptions,
+    mut to: W,
+) -> anyhow::Result<Track> {
+    let tracks = open_disc_image(&input_path, "", options)?;
+    let Some(index) = tracks
+        .iter()
+        .position(|planned| planned.track.number == track_number)
+    else {
+        bail!("{:?} has no track {}", input_path, track_number);
+    };
+    let track = tracks[index].track.clone();
+    let mut frames = frames_for_track(input_path.as_ref(), &tracks, index, options)?;
+
+    track
+        .write_seekable(&mut frames, options.metadata_padding, &mut to)
+        .with_context(|| format!("writing track {}", track_number))?;
+    Ok(track)
+}
+
+/// Opens a disc image's frames for the track at `index` of its
+/// planned `tracks`, positioned where [Track::write_audio] would find
+/// them after writing all the tracks before it: at the track's first
+/// frame, which the image's SEEKTABLE (or else a binary search over
+/// its frames) locates.
+fn frames_for_track(
+    input_path: &Path,
+    tracks: &[PlannedTrack],
+    index: usize,
+    options: &SplitOptions,
+) -> anyhow::Result<Frames> {
+    let mut frames = Frames::new(open_reader(input_path)?, options)?;
+    // Unless splitting sample-accurately, the frame that straddles a
+    // track boundary belongs to the track before it, so a run of
+    // tracks shorter than a frame or two can each end up a frame
+    // later than their cue sheet position. Start following the frames
+    // after the last track that is too long for that:
+    let mut first = index;
+    if !options.sample_accurate {
+        let long = 2 * frames.max_block_size as u64;
+        while first > 0 && tracks[first - 1].track.end_ts - tracks[first - 1].track.start_ts < long
+        {
+            first -= 1;
+        }
+    }
+    let keep_from = match first.checked_sub(1) {
+        Some(previous) if !options.sample_accurate => tracks[previous].track.end_ts,
+        _ => tracks[first].track.start_ts,
+    };
+    if keep_from > 0 {
+        let track_id = frames
+            .reader
+            .default_track()
+            .context("no default track")?
+            .id;
+        let seeked = frames
+            .reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: keep_from,
+                    track_id,
+                },
+            )
+            .with_context(|| format!("seeking to sample {}", keep_from))?;
+        debug!(?seeked, "seeked to track");
+    }
+    if !options.sample_accurate {
+        // Hand the frames to the tracks the way split_one_file would,
+        // until we get to ours:
+        let mut current = first;
+        loop {
+            let packet = frames.next_packet().with_context(|| {
+                format!("reading frames of track {}", tracks[index].track.number)
+            })?;
+            let end = packet.ts + packet.dur;
+            let planned = &tracks[current].track;
+            if packet.ts < keep_from || end <= planned.start_ts {
+                continue;
+            }
+            if current == index {
+                frames.hold(packet);
+                break;
+            }
+            if end >= planned.end_ts {
+                current += 1;
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Returns the offset of the first audio frame in a FLAC file, right
+/// after its metadata blocks.
+fn metadata_end<R: std::io::Read + Seek>(file: &mut R) -> anyhow::Result<u64> {
+    file.seek(std::io::SeekFrom::Start(0))?;
+    let mut marker = [0u8; 4];
+    file.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        bail!("not a FLAC file");
+    }
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        let end = file.seek(std::io::SeekFrom::Current(i64::from(length)))?;
+        if header[0] & 0x80 != 0 {
+            return Ok(end);
+        }
+    }
+}
+
+/// Decides where to write a track whose output pathname is `path`:
+/// `None` means the track should be skipped.
+fn resolve_conflict(path: &Path, policy: ConflictPolicy) -> anyhow::Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(Some(path.to_path_buf()));
+    }
+    match policy {
+        ConflictPolicy::Fail => bail!("{:?} already exists", path),
+        ConflictPolicy::Skip => Ok(None),
+        ConflictPolicy::Overwrite => Ok(Some(path.to_path_buf())),
+        ConflictPolicy::Rename => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path.extension().map(|ext| ext.to_string_lossy());
+            Ok((1..)
+                .map(|n| {
+                    let name = match &extension {
+                        Some(ext) => format!("{} ({}).{}", stem, n, ext),
+                        None => format!("{} ({})", stem, n),
+                    };
+                    path.with_file_name(name)
+                })
+                .find(|candidate| !candidate.exists()))
+        }
+    }
+}
+
+/// Creates a hidden temporary file in `dir` that a track gets written
+/// to before it's renamed into place, so that no half-written tracks
+/// are left behind if splitting gets interrupted.
+fn temp_file_in(dir: &Path) -> std::io::Result<tempfile::NamedTempFile> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".").suffix(".flac.part");
+    // Like File::create, leave the permissions up to the umask:
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    builder.tempfile_in(dir)
+}
+
+/// Looks for a CUE sheet belonging to a disc image: `album.cue` or
+/// `album.flac.cue` next to `album.flac`.
+pub(crate) fn find_cue_sheet(input_path: &Path) -> Option<PathBuf> {
+    let mut with_suffix = input_path.as_os_str().to_owned();
+    with_suffix.push(".cue");
+    [input_path.with_extension("cue"), PathBuf::from(with_suffix)]
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+}
+
+/// Tags the points of cues read from a FLAC CUESHEET block with their
+/// [cuesheet::INDEX_TAG] numbers, which symphonia doesn't keep.
+fn tag_index_numbers(cues: &mut [Cue], input_path: &Path) -> anyhow::Result<()> {
+    let tag = metaflac::Tag::read_from_path(input_path).context("reading CUESHEET block")?;
+    for block in tag.blocks() {
+        let Block::CueSheet(sheet) = block else {
+            continue;
+        };
+        for track in &sheet.tracks {
+            let Some(cue) = cues
+                .iter_mut()
+                .find(|cue| cue.index == u32::from(track.number))
+            else {
+                continue;
+            };
+            for (point, index) in cue.points.iter_mut().zip(&track.indices) {
+                point.tags.push(Tag::new(
+                    None,
+                    cuesheet::INDEX_TAG,
+                    Value::UnsignedInt(index.point_num.into()),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the positions of a cue's pregap (INDEX 00), if it has one,
+/// and of the start of the track proper (INDEX 01). Cues whose points
+/// carry no index numbers are assumed to start at INDEX 01.
+fn index_positions(cue: &Cue) -> (Option<u64>, u64) {
+    let index = |number: u64| {
+        cue.points.iter().find_map(|point| {
+            point
+                .tags
+                .iter()
+                .any(|tag| {
+                    tag.key == cuesheet::INDEX_TAG
+                        && matches!(tag.value, Value::UnsignedInt(n) if n == number)
+                })
+                .then_some(cue.start_ts + point.start_offset_ts)
+        })
+    };
+    let start = index(1).unwrap_or(cue.start_ts);
+    let pregap = index(0).filter(|pregap| *pregap < start);
+    (pregap, start)
+}
+
+/// Works out which part of the disc image goes into each track,
+/// returning each track's cue with its `start_ts` adjusted for
+/// `pregap`, along with the track's end. With [PregapMode::Append]
+/// and [PregapMode::Discard], hidden audio before track 1's INDEX 01
+/// becomes its own "track 0".
+fn track_cues(cues: &[Cue], last_ts: u64, pregap: PregapMode) -> Vec<(Cue, u64)> {
+    let (tracks, lead_out): (Vec<&Cue>, Vec<&Cue>) = cues.iter().partition(|cue| {
+        !matches!(
+            cue.index,
+            LEAD_OUT_TRACK_NUMBER | NON_CD_LEAD_OUT_TRACK_NUMBER
+        )
+    });
+    // With a lead-out, capture the whole rest in the last track;
+    // without one, fudge it.
+    let disc_end = lead_out.first().map_or(last_ts, |cue| cue.start_ts);
+    let positions: Vec<_> = tracks.iter().map(|cue| index_positions(cue)).collect();
+
+    let mut result = vec![];
+    if let (Some(first), Some((Some(htoa), start))) = (tracks.first(), positions.first()) {
+        if pregap != PregapMode::Prepend {
+            let hidden = Cue {
+                index: 0,
+                start_ts: *htoa,
+                tags: vec![
+                    Tag::new(
+                        Some(StandardTagKey::TrackNumber),
+                        "TRACKNUMBER",
+                        Value::from("0"),
+                    ),
+                    Tag::new(
+                        Some(StandardTagKey::TrackTitle),
+                        "TITLE",
+                        Value::from("Hidden Track"),
+                    ),
+                ],
+                points: vec![],
+            };
+            debug!(
+                track = first.index,
+                "writing hidden track one audio as track 0"
+            );
+            result.push((hidden, *start));
+        }
+    }
+    for (i, cue) in tracks.iter().enumerate() {
+        let (own_pregap, start) = positions[i];
+        let start_ts = match pregap {
+            PregapMode::Prepend => own_pregap.unwrap_or(start),
+            PregapMode::Append | PregapMode::Discard => start,
+        };
+        let end_ts = match positions.get(i + 1) {
+            None => disc_end,
+            Some((next_pregap, next_start)) => match pregap {
+                PregapMode::Append => *next_start,
+                PregapMode::Prepend | PregapMode::Discard => next_pregap.unwrap_or(*next_start),
+            },
+        };
+        result.push((
+            Cue {
+                start_ts,
+                ..(*cue).clone()
+            },
+            end_ts,
+        ));
+    }
+    result
+}
+
+/// Parses the cue sheet text that rippers often store in a CUESHEET
+/// vorbis comment.
+fn cue_sheet_comment(tags: &[Tag]) -> Option<CueSheet> {
+    let text = tags.iter().find_map(|tag| match &tag.value {
+        Value::String(text) if tag.key.eq_ignore_ascii_case("CUESHEET") => Some(text),
+        _ => None,
+    })?;
+    match text.parse() {
+        Ok(sheet) => Some(sheet),
+        Err(error) => {
+            info!(%error, "ignoring unparseable CUESHEET comment");
+            None
+        }
+    }
+}
+
+/// Fills in the tags that `sheet` has for each of the `cues`' tracks,
+/// where the cue has no (or a blank) value for them.
+fn merge_cue_sheet_tags(cues: &mut [Cue], sheet: &CueSheet) {
+    for cue in cues {
+        if let Some(track) = sheet.tracks.iter().find(|track| track.number == cue.index) {
+            cue.tags.retain(|tag| !is_blank_tag(tag));
+            merge_missing_tags(&mut cue.tags, sheet.track_tags(track));
+        }
+    }
+}
+
+/// Whether a tag carries no useful value, like the all-blank ISRC
+/// that FLAC CUESHEET blocks store for tracks without one.
+fn is_blank_tag(tag: &Tag) -> bool {
+    match &tag.value {
+        Value::String(value) => value
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .is_empty(),
+        _ => false,
+    }
+}
+
+/// Adds the tags from `fallback` whose keys aren't present in `tags` yet.
+fn merge_missing_tags(tags: &mut Vec<Tag>, fallback: impl IntoIterator<Item = Tag>) {
+    let present: HashSet<String> = tags.iter().map(|tag| tag.key.clone()).collect();
+    tags.extend(
+        fallback
+            .into_iter()
+            .filter(|tag| !is_blank_tag(tag) && !present.contains(&tag.key)),
+    );
 }
 
 /// The track number used to identify a lead-out track on a cue sheet.
 pub const LEAD_OUT_TRACK_NUMBER: u32 = 170;
 
+/// The track number used to identify the lead-out track on the cue
+/// sheet of something that isn't a CD.
+pub const NON_CD_LEAD_OUT_TRACK_NUMBER: u32 = 255;
+
 /// Metadata identifying a track in a FLAC file that has an embedded CUE sheet.
 #[derive(Clone)]
 pub struct Track {
@@ -133,6 +793,10 @@ impl Track {
     }
 
     /// Create a [Track] from a file's embedded FLAC&vorbis comments and CUE sheet.
+    ///
+    /// Per-track comments (like `TITLE[3]`) take precedence over tags
+    /// on the [Cue] itself (e.g. a track's title from a CUE sheet),
+    /// which in turn take precedence over the album-wide comments.
     pub fn from_tags(
         streaminfo: &StreamInfo,
         cue: &Cue,
@@ -141,19 +805,18 @@ impl Track {
         visuals: &[Visual],
     ) -> Self {
         let suffix = format!("[{}]", cue.index);
-        let tags = tags
-            .iter()
-            .filter_map(|tag| {
-                let tag_name = if tag.key.ends_with(&suffix) {
-                    Some(&tag.key[0..(tag.key.len() - suffix.len())])
-                } else if Self::interesting_tag(&tag.key) {
-                    Some(tag.key.as_str())
-                } else {
-                    None
-                };
-                tag_name.map(|key| Tag::new(tag.std_key, key, tag.value.clone()))
-            })
-            .collect();
+        let mut per_track = vec![];
+        let mut general = vec![];
+        for tag in tags {
+            if let Some(key) = tag.key.strip_suffix(&suffix) {
+                per_track.push(Tag::new(tag.std_key, key, tag.value.clone()));
+            } else if Self::interesting_tag(&tag.key) {
+                general.push(tag.clone());
+            }
+        }
+        let mut tags = per_track;
+        merge_missing_tags(&mut tags, cue.tags.iter().cloned());
+        merge_missing_tags(&mut tags, general);
         let visuals = visuals.to_vec();
         Self {
             streaminfo: StreamInfo {
@@ -169,6 +832,18 @@ impl Track {
         }
     }
 
+    /// Returns the sample rate of the track's audio.
+    pub fn sample_rate(&self) -> u32 {
+        self.streaminfo.sample_rate
+    }
+
+    /// Returns how long the track plays.
+    pub fn duration(&self) -> Duration {
+        let nanos = u128::from(self.end_ts - self.start_ts) * 1_000_000_000
+            / u128::from(self.sample_rate());
+        Duration::from_nanos(nanos as u64)
+    }
+
     /// Return the tag value for a given tag name.
     pub fn tag_value(&self, name: &str) -> Option<&Value> {
         self.tags
@@ -188,7 +863,7 @@ impl Track {
         }
     }
 
-    fn sanitize_pathname(name: &str) -> Cow<str> {
+    fn sanitize_pathname(name: &str) -> Cow<'_, str> {
         if name.contains(Self::is_risky_char) {
             Cow::Owned(name.replace(Self::is_risky_char, "_"))
         } else {
@@ -198,6 +873,12 @@ impl Track {
 
     /// Return the output pathname for a track.
     pub fn pathname(&self) -> PathBuf {
+        self.pathname_in(DiscLayout::default())
+    }
+
+    /// Return the output pathname for a track, keeping the discs of a
+    /// multi-disc release apart according to `disc_layout`.
+    pub fn pathname_in(&self, disc_layout: DiscLayout) -> PathBuf {
         let mut buf = PathBuf::new();
         if let Some(Value::String(artist)) = self.tag_value("ALBUMARTIST") {
             buf.push(Self::sanitize_pathname(artist).as_ref());
@@ -217,22 +898,70 @@ impl Track {
             buf.push("Unknown Album");
         }
 
-        if let (Some(Value::String(track)), Some(Value::String(title))) =
-            (self.tag_value("TRACKNUMBER"), self.tag_value("TITLE"))
-        {
-            if let Ok(trackno) = <usize as FromStr>::from_str(track) {
-                buf.push(format!(
-                    "{:02}.{}.flac",
-                    trackno,
-                    Self::sanitize_pathname(title)
-                ));
-            } else {
-                buf.push(format!("99.{}.flac", Self::sanitize_pathname(title)));
+        let disc_prefix = match (self.disc_number(), disc_layout) {
+            (Some(disc), DiscLayout::Subdirectory) => {
+                buf.push(format!("Disc {}", disc));
+                String::new()
             }
+            (Some(disc), DiscLayout::Prefix) => format!("{}-", disc),
+            (None, _) => String::new(),
+        };
+
+        let title = match self.tag_value("TITLE") {
+            Some(Value::String(title)) => Self::sanitize_pathname(title),
+            // Tracks split at explicit points usually have no title:
+            _ => Cow::Owned(format!("Track {:02}", self.number)),
+        };
+        match self.tag_value("TRACKNUMBER") {
+            Some(Value::String(track)) => {
+                if let Ok(trackno) = <usize as FromStr>::from_str(track) {
+                    buf.push(format!("{}{:02}.{}.flac", disc_prefix, trackno, title));
+                } else {
+                    buf.push(format!("{}99.{}.flac", disc_prefix, title));
+                }
+            }
+            _ => buf.push(format!("{}{:02}.{}.flac", disc_prefix, self.number, title)),
         }
         buf
     }
 
+    /// Returns the track's disc number if it is part of a multi-disc
+    /// release, going by its DISCNUMBER and TOTALDISCS (or DISCTOTAL)
+    /// tags.
+    pub fn disc_number(&self) -> Option<u32> {
+        let string = |name| match self.tag_value(name) {
+            Some(Value::String(value)) => Some(value.as_str()),
+            _ => None,
+        };
+        let (number, total) = discs::parse_disc_number(string("DISCNUMBER")?)?;
+        let total = total.or_else(|| {
+            ["TOTALDISCS", "DISCTOTAL"]
+                .iter()
+                .find_map(|name| string(name)?.trim().parse().ok())
+        });
+        (number > 1 || total.unwrap_or(1) > 1).then_some(number)
+    }
+
+    /// Return the output pathname for a track, according to a
+    /// template. A missing `TRACKNUMBER` tag falls back to the track's
+    /// number on the cue sheet.
+    pub fn pathname_with(&self, template: &PathTemplate) -> PathBuf {
+        template.render(|key| {
+            let value = self
+                .tags
+                .iter()
+                .find(|tag| tag.key.eq_ignore_ascii_case(key))
+                .map(|tag| match &tag.value {
+                    Value::String(value) => Cow::Borrowed(value.as_str()),
+                    value => Cow::Owned(value.to_string()),
+                });
+            match value {
+                None if key == "TRACKNUMBER" => Some(Cow::Owned(self.number.to_string())),
+                value => value,
+            }
+        })
+    }
+
     /// Write a track's
     /// [STREAM](https://xiph.org/flac/format.html#stream) metadata
     /// blocks - first STREAMINFO, then the remainder containing
@@ -240,42 +969,13 @@ impl Track {
     #[instrument(skip(self, to), fields(number = self.number, path = ?self.pathname()), err)]
     pub fn write_metadata<S: Write>(
         &self,
-        total_samples: u64,
+        audio: &AudioSummary,
         metadata_padding: u32,
         mut to: S,
     ) -> anyhow::Result<()> {
         to.write_all(b"fLaC")?;
-        let comment = VorbisComment {
-            vendor_string: "flac-tracksplit".to_string(),
-            comments: self
-                .tags
-                .iter()
-                .map(|tag| (tag.key.to_string(), vec![tag.value.to_string()]))
-                .collect(),
-        };
-        let pictures: Vec<Block> = self
-            .visuals
-            .iter()
-            .map(|visual| {
-                Block::Picture(Picture {
-                    picture_type: translate_visual_key(
-                        visual.usage.unwrap_or(StandardVisualKey::OtherIcon),
-                    ),
-                    mime_type: visual.media_type.to_string(),
-                    description: "".to_string(),
-                    width: visual.dimensions.map(|s| s.width).unwrap_or(0),
-                    height: visual.dimensions.map(|s| s.height).unwrap_or(0),
-                    depth: visual.bits_per_pixel.map(NonZeroU32::get).unwrap_or(0),
-                    num_colors: match visual.color_mode {
-                        Some(symphonia_core::meta::ColorMode::Discrete) => 0,
-                        Some(symphonia_core::meta::ColorMode::Indexed(n)) => n.get(),
-                        None => 0,
-                    },
-                    data: visual.data.to_vec(),
-                })
-            })
-            .collect();
         let mut streaminfo = self.streaminfo.clone();
+        let total_samples = audio.total_samples;
         if total_samples != streaminfo.total_samples {
             // This is a pretty peaceful condition (difference is
             // about less than 1/10s), but let's let curious users
@@ -290,8 +990,23 @@ impl Track {
             );
         }
         streaminfo.total_samples = total_samples;
-        let headers = vec![Block::StreamInfo(streaminfo), Block::VorbisComment(comment)];
-        for block in headers.into_iter().chain(pictures.into_iter()) {
+        if let Some(md5) = audio.md5 {
+            streaminfo.md5 = md5.to_vec();
+        }
+        // The disc image's block and frame size bounds don't
+        // necessarily hold for the track, so use what we wrote:
+        streaminfo.min_block_size = audio.min_block_size;
+        streaminfo.max_block_size = audio.max_block_size;
+        streaminfo.min_frame_size = audio.min_frame_size;
+        streaminfo.max_frame_size = audio.max_frame_size;
+        let mut headers = vec![Block::StreamInfo(streaminfo)];
+        if !audio.seek_points.is_empty() {
+            headers.push(Block::SeekTable(SeekTable {
+                seekpoints: audio.seek_points.clone(),
+            }));
+        }
+        headers.extend(self.tag_blocks());
+        for block in headers {
             block
                 .write_to(false, &mut to)
                 .with_context(|| format!("writing block {:?}", block))?;
@@ -302,44 +1017,545 @@ impl Track {
         Ok(())
     }
 
-    /// Write a STREAM's
-    /// [FRAME](https://xiph.org/flac/format.html#frame) sequence,
-    /// containing compressed audio samples. Returns the number of samples actually processed.
-    #[instrument(skip(self, from, to), fields(number = self.number, path = ?self.pathname()), err)]
-    pub fn write_audio<S: Write>(&self, from: &mut FlacReader, mut to: S) -> anyhow::Result<u64> {
-        // TODO: Seek to the track start. Currently, this is only
-        // called in sequence (we're parallel per-file), so no need to
-        // do that rn, but it would be nice!
-
-        let mut last_end: u64 = 0;
-        let mut frame = OffsetFrame::default();
+    /// Writes the track as a complete FLAC stream, streaming its
+    /// audio from `from` straight into `file`: The metadata gets
+    /// written first with space reserved for as many SEEKTABLE points
+    /// as the track can need, and rewritten in place once the audio
+    /// it describes is known. The stream starts wherever `file` is
+    /// positioned, and `file` is left at its end.
+    pub fn write_seekable<F: Write + Seek>(
+        &self,
+        from: &mut Frames,
+        metadata_padding: u32,
+        file: &mut F,
+    ) -> anyhow::Result<AudioSummary> {
+        let reserved_seek_points = self.reserved_seek_points(from.seekpoint_spacing)?;
+        let placeholder = seek_point(u64::MAX, 0, 0);
+        let reserved = AudioSummary {
+            seek_points: vec![placeholder; reserved_seek_points],
+            ..Default::default()
+        };
+        let start = file.stream_position()?;
+        self.write_metadata(&reserved, metadata_padding, &mut *file)?;
+        let audio_start = file.stream_position()? - start;
+
+        let mut writer = BufWriter::new(&mut *file);
+        let mut audio = self.write_audio(from, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        let mut seek_points = audio.seek_points.clone();
+        seek_points.resize(reserved_seek_points, placeholder);
+        let mut metadata = vec![];
+        self.write_metadata(
+            &AudioSummary {
+                seek_points,
+                ..audio.clone()
+            },
+            metadata_padding,
+            &mut metadata,
+        )?;
+        if metadata.len() as u64 != audio_start {
+            bail!(
+                "metadata changed size from {} to {} bytes",
+                audio_start,
+                metadata.len()
+            );
+        }
+        let end = file.stream_position()?;
+        file.seek(std::io::SeekFrom::Start(start))?;
+        file.write_all(&metadata)?;
+        file.seek(std::io::SeekFrom::Start(end))?;
+        Vec::truncate(&mut audio.seek_points, reserved_seek_points);
+        Ok(audio)
+    }
+
+    /// Returns how many seek points [Track::write_seekable] reserves
+    /// space for in the track's SEEKTABLE.
+    fn reserved_seek_points(&self, seekpoint_spacing: Option<u64>) -> anyhow::Result<usize> {
+        // A seek point is recorded at most once per spacing, and the
+        // track can end up to a frame longer at either end than the
+        // cue sheet says:
+        match seekpoint_spacing.filter(|spacing| *spacing > 0) {
+            Some(spacing) => {
+                let longest =
+                    self.end_ts - self.start_ts + 2 * u64::from(self.streaminfo.max_block_size);
+                Ok(usize::try_from(longest / spacing + 1)?)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Returns the track's VORBIS_COMMENT block, followed by a
+    /// PICTURE block for each of its pictures.
+    fn tag_blocks(&self) -> Vec<Block> {
+        let comment = VorbisComment {
+            vendor_string: "flac-tracksplit".to_string(),
+            comments: self
+                .tags
+                .iter()
+                .map(|tag| (tag.key.to_string(), vec![tag.value.to_string()]))
+                .collect(),
+        };
+        let pictures = self.visuals.iter().map(|visual| {
+            Block::Picture(Picture {
+                picture_type: translate_visual_key(
+                    visual.usage.unwrap_or(StandardVisualKey::OtherIcon),
+                ),
+                mime_type: visual.media_type.to_string(),
+                description: "".to_string(),
+                width: visual.dimensions.map(|s| s.width).unwrap_or(0),
+                height: visual.dimensions.map(|s| s.height).unwrap_or(0),
+                depth: visual.bits_per_pixel.map(NonZeroU32::get).unwrap_or(0),
+                num_colors: match visual.color_mode {
+                    Some(symphonia_core::meta::ColorMode::Discrete) => 0,
+                    Some(symphonia_core::meta::ColorMode::Indexed(n)) => n.get(),
+                    None => 0,
+                },
+                data: visual.data.to_vec(),
+            })
+        });
+        [vorbis_comment_block(&comment)]
+            .into_iter()
+            .chain(pictures)
+            .collect()
+    }
+
+    /// Replaces the tags and pictures of a track file that was split
+    /// earlier with this track's, keeping its STREAMINFO, SEEKTABLE
+    /// and audio. The new metadata goes into the space of the old
+    /// metadata and padding if it fits; otherwise, the file gets
+    /// rewritten with `metadata_padding` bytes of padding.
+    ///
+    /// Returns whether the file had to be rewritten.
+    #[instrument(skip(self), fields(number = self.number), err)]
+    pub fn rewrite_metadata(&self, path: &Path, metadata_padding: u32) -> anyhow::Result<bool> {
+        let existing = metaflac::Tag::read_from_path(path)
+            .with_context(|| format!("reading metadata of {:?}", path))?;
+        let mut blocks: Vec<Block> = existing
+            .blocks()
+            .filter(|block| {
+                !matches!(
+                    block,
+                    Block::VorbisComment(_) | Block::Picture(_) | Block::Padding(_)
+                )
+            })
+            .cloned()
+            .collect();
+        blocks.extend(self.tag_blocks());
+        let mut metadata = vec![];
+        let mut last_block_start = 0;
+        for block in &blocks {
+            last_block_start = metadata.len();
+            block
+                .write_to(false, &mut metadata)
+                .with_context(|| format!("encoding block {:?}", block))?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("opening {:?}", path))?;
+        let audio_start = metadata_end(&mut file).with_context(|| format!("reading {:?}", path))?;
+        // The space between the "fLaC" marker and the audio:
+        let space = usize::try_from(audio_start)? - 4;
+        const BLOCK_HEADER_LEN: usize = 4;
+        if metadata.len() == space || metadata.len() + BLOCK_HEADER_LEN <= space {
+            if metadata.len() == space {
+                // An exact fit; mark the last block as the last one:
+                metadata[last_block_start] |= 0x80;
+            } else {
+                let padding = u32::try_from(space - metadata.len() - BLOCK_HEADER_LEN)?;
+                Block::Padding(padding).write_to(true, &mut metadata)?;
+            }
+            file.seek(std::io::SeekFrom::Start(4))?;
+            file.write_all(&metadata)
+                .with_context(|| format!("writing metadata of {:?}", path))?;
+            return Ok(false);
+        }
+
+        debug!(space, needed = metadata.len(), "metadata doesn't fit");
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut rewritten =
+            temp_file_in(dir).with_context(|| format!("creating temporary file for {:?}", path))?;
+        rewritten.write_all(b"fLaC")?;
+        rewritten.write_all(&metadata)?;
+        Block::Padding(metadata_padding).write_to(true, &mut rewritten)?;
+        file.seek(std::io::SeekFrom::Start(audio_start))?;
+        std::io::copy(&mut file, &mut rewritten)
+            .with_context(|| format!("copying audio of {:?}", path))?;
+        rewritten
+            .persist(path)
+            .with_context(|| format!("moving rewritten {:?} into place", path))?;
+        Ok(true)
+    }
+
+    /// Write a STREAM's
+    /// [FRAME](https://xiph.org/flac/format.html#frame) sequence,
+    /// containing compressed audio samples. Returns a summary of the
+    /// audio actually written, for use in [Track::write_metadata].
+    #[instrument(skip(self, from, to), fields(number = self.number, path = ?self.pathname()), err)]
+    pub fn write_audio<S: Write>(
+        &self,
+        from: &mut Frames,
+        mut to: S,
+    ) -> anyhow::Result<AudioSummary> {
+        // This reads on from wherever `from` is; see extract_track for
+        // seeking to a track's start first.
+
+        let mut last_end: u64 = 0;
+        let mut frame = OffsetFrame::new(from.seekpoint_spacing);
+        // Samples cut from the start of a boundary frame that are too
+        // few to make up a frame of their own; they get merged into
+        // the next frame.
+        let mut short_head: Option<Vec<Vec<i32>>> = None;
+        let mut md5 = from.compute_md5.then(Md5::new);
+        let mut warnings = vec![];
+        loop {
+            let packet = from
+                .next_packet()
+                .with_context(|| format!("last end: {:?} vs {:?}", last_end, self.end_ts))?;
+
+            let ts = packet.ts;
+            let dur = packet.dur;
+            last_end = ts + dur;
+            if last_end <= self.start_ts {
+                // Audio that belongs to no track, like a discarded
+                // pregap:
+                continue;
+            }
+            let straddles_boundary = ts < self.start_ts || last_end > self.end_ts;
+            if !(from.sample_accurate && (straddles_boundary || short_head.is_some())) {
+                if ts < self.start_ts {
+                    // Only happens after a gap between tracks; the
+                    // previous track ended at or after our start
+                    // otherwise.
+                    warnings.push(self.mid_frame_start_warning(ts));
+                }
+
+                // Adjust the frame header:
+                // * Adjust sample/frame number such that each track starts at frame/sample 0. This should fix seeking.
+                // * Recompute the 8-bit header CRC
+                // * Recompute the 16-bit footer CRC
+
+                if let Some(md5) = &mut md5 {
+                    let samples = from
+                        .decode(&packet)
+                        .with_context(|| format!("decoding frame at ts {}", ts))?;
+                    update_md5(md5, &samples, from.bits_per_sample);
+                }
+                let updated_buf = frame
+                    .process(packet)
+                    .with_context(|| format!("processing frame at ts {}", ts))?;
+                to.write_all(&updated_buf)?;
+            } else {
+                // Only part of this frame belongs to the track: decode
+                // it, cut out our part and re-encode that.
+                let decoded = from
+                    .decode(&packet)
+                    .with_context(|| format!("decoding frame at ts {}", ts))?;
+                let cut_start = usize::try_from(self.start_ts.saturating_sub(ts))?;
+                let cut_end = usize::try_from(last_end.min(self.end_ts) - ts)?;
+                if last_end > self.end_ts {
+                    // The rest of the frame belongs to the next track:
+                    from.hold(packet);
+                }
+                let mut samples: Vec<Vec<i32>> = decoded
+                    .into_iter()
+                    .map(|channel| channel[cut_start..cut_end].to_vec())
+                    .collect();
+                if let Some(head) = short_head.take() {
+                    samples = head
+                        .into_iter()
+                        .zip(samples)
+                        .map(|(mut head, tail)| {
+                            head.extend(tail);
+                            head
+                        })
+                        .collect();
+                }
+                let block_size = samples.first().map(Vec::len).unwrap_or(0);
+                if block_size < MIN_BLOCK_SIZE && last_end < self.end_ts {
+                    short_head = Some(samples);
+                    continue;
+                }
+                if let Some(md5) = &mut md5 {
+                    update_md5(md5, &samples, from.bits_per_sample);
+                }
+                if block_size > from.max_block_size {
+                    // A short head merged into a full frame; split
+                    // the result in two so it doesn't exceed the
+                    // stream's maximum block size.
+                    let (first, second): (Vec<_>, Vec<_>) = samples
+                        .into_iter()
+                        .map(|mut channel| {
+                            let second = channel.split_off(block_size / 2);
+                            (channel, second)
+                        })
+                        .unzip();
+                    to.write_all(&frame.encode(&first, from.bits_per_sample, false)?)?;
+                    to.write_all(&frame.encode(
+                        &second,
+                        from.bits_per_sample,
+                        last_end >= self.end_ts,
+                    )?)?;
+                } else {
+                    to.write_all(&frame.encode(
+                        &samples,
+                        from.bits_per_sample,
+                        last_end >= self.end_ts,
+                    )?)?;
+                }
+            }
+
+            if last_end >= self.end_ts {
+                return Ok(AudioSummary {
+                    md5: md5.map(|md5| md5.finalize().into()),
+                    warnings,
+                    ..frame.summary()
+                });
+            }
+        }
+    }
+
+    /// Reads past the track's frames in `from` the way
+    /// [Track::write_audio] would, without rewriting, decoding or
+    /// writing them. Returns a summary of just the number of frames
+    /// and samples, counting each frame that straddles a
+    /// sample-accurate boundary once.
+    pub fn skip_audio(&self, from: &mut Frames) -> anyhow::Result<AudioSummary> {
+        let mut audio = AudioSummary::default();
         loop {
             let packet = from
                 .next_packet()
-                .with_context(|| format!("last end: {:?} vs {:?}", last_end, self.end_ts))?;
+                .with_context(|| format!("skipping track {} audio", self.number))?;
+            let end = packet.ts + packet.dur;
+            if end <= self.start_ts {
+                continue;
+            }
+            if from.sample_accurate {
+                audio.total_samples += end.min(self.end_ts) - packet.ts.max(self.start_ts);
+            } else {
+                if packet.ts < self.start_ts {
+                    audio.warnings.push(self.mid_frame_start_warning(packet.ts));
+                }
+                audio.total_samples += packet.dur;
+            }
+            audio.frames += 1;
+            if end >= self.end_ts {
+                if from.sample_accurate && end > self.end_ts {
+                    // The rest of the frame belongs to the next track:
+                    from.hold(packet);
+                }
+                return Ok(audio);
+            }
+        }
+    }
 
-            let ts = packet.ts;
-            let dur = packet.dur;
-            ma::assert_ge!(
-                ts,
-                self.start_ts,
-                "Packet timestamp is not >= this track's start ts. Potential bug exposed by the previous track.",
-            );
+    /// Describes a track starting in the middle of the frame at `ts`,
+    /// all of which the track keeps.
+    fn mid_frame_start_warning(&self, ts: u64) -> String {
+        debug!(
+            ts,
+            start_ts = self.start_ts,
+            "track starts in the middle of a frame; keeping all of it"
+        );
+        format!(
+            "track starts in the middle of the frame at sample {}; \
+             kept {} samples of the previous track",
+            ts,
+            self.start_ts - ts
+        )
+    }
+}
+
+/// Returns the number of samples between the seek points of tracks
+/// written with the given options, from audio at `sample_rate`.
+fn seekpoint_spacing(options: &SplitOptions, sample_rate: u32) -> Option<u64> {
+    options
+        .seekpoint_interval
+        .map(|interval| (interval.as_secs_f64() * f64::from(sample_rate)) as u64)
+}
 
-            // Adjust the frame header:
-            // * Adjust sample/frame number such that each track starts at frame/sample 0. This should fix seeking.
-            // * Recompute the 8-bit header CRC
-            // * Recompute the 16-bit footer CRC
+/// What [Track::write_audio] actually wrote, which the track's
+/// STREAMINFO block needs to reflect.
+#[derive(Debug, Clone, Default)]
+pub struct AudioSummary {
+    /// The number of samples (per channel) in the track.
+    pub total_samples: u64,
 
-            let updated_buf = frame
-                .process(packet)
-                .with_context(|| format!("processing frame at ts {}", ts))?;
-            to.write_all(&updated_buf)?;
+    /// The smallest block size (in samples) of the track's frames,
+    /// not counting the last frame.
+    pub min_block_size: u16,
 
-            last_end = ts + dur;
-            if last_end >= self.end_ts {
-                return Ok(frame.samples_processed);
+    /// The largest block size (in samples) of the track's frames.
+    pub max_block_size: u16,
+
+    /// The size (in bytes) of the track's smallest frame.
+    pub min_frame_size: u32,
+
+    /// The size (in bytes) of the track's largest frame.
+    pub max_frame_size: u32,
+
+    /// Whether the track's frames have a variable block size, which
+    /// happens when its first frame was cut at a track boundary.
+    pub variable_block_size: bool,
+
+    /// The MD5 signature of the track's decoded samples, if
+    /// [SplitOptions::compute_md5] was set.
+    pub md5: Option<[u8; 16]>,
+
+    /// Points for the track's SEEKTABLE, if
+    /// [SplitOptions::seekpoint_interval] was set.
+    pub seek_points: Vec<SeekPoint>,
+
+    /// The number of frames in the track.
+    pub frames: u64,
+
+    /// Things about the track's audio that might deserve a look.
+    pub warnings: Vec<String>,
+}
+
+/// Creates a SEEKTABLE point for the frame starting at `sample`,
+/// `offset` bytes after the first frame.
+fn seek_point(sample: u64, offset: u64, block_samples: u64) -> SeekPoint {
+    // metaflac doesn't let us construct these field by field:
+    let mut bytes = Vec::with_capacity(18);
+    bytes.extend(sample.to_be_bytes());
+    bytes.extend(offset.to_be_bytes());
+    bytes.extend(<u64 as Truncate<u16>>::truncate(block_samples).to_be_bytes());
+    SeekPoint::from_bytes(&bytes)
+}
+
+/// The metadata block type of VORBIS_COMMENT blocks.
+const VORBIS_COMMENT_BLOCK_TYPE: u8 = 4;
+
+/// Returns a VORBIS_COMMENT block with the given comments, in the
+/// order of their keys: metaflac writes them in the order of a hash
+/// map, which differs every time, so the same track wouldn't always
+/// come out the same.
+fn vorbis_comment_block(comment: &VorbisComment) -> Block {
+    let mut keys: Vec<&String> = comment.comments.keys().collect();
+    keys.sort();
+    let comments: Vec<String> = keys
+        .into_iter()
+        .flat_map(|key| {
+            comment.comments[key]
+                .iter()
+                .map(move |value| format!("{}={}", key, value))
+        })
+        .collect();
+    let mut bytes = vec![];
+    bytes.extend((comment.vendor_string.len() as u32).to_le_bytes());
+    bytes.extend(comment.vendor_string.as_bytes());
+    bytes.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        bytes.extend((comment.len() as u32).to_le_bytes());
+        bytes.extend(comment.as_bytes());
+    }
+    Block::Unknown((VORBIS_COMMENT_BLOCK_TYPE, bytes))
+}
+
+/// Feeds decoded samples into an MD5 signature the way FLAC's
+/// STREAMINFO expects: Interleaved, signed little-endian, in as few
+/// bytes per sample as the bit depth allows.
+fn update_md5(md5: &mut Md5, channels: &[Vec<i32>], bits_per_sample: u32) {
+    let bytes_per_sample = bits_per_sample.div_ceil(8) as usize;
+    let block_size = channels.first().map(Vec::len).unwrap_or(0);
+    let mut buf = Vec::with_capacity(block_size * channels.len() * bytes_per_sample);
+    for i in 0..block_size {
+        for channel in channels {
+            buf.extend_from_slice(&channel[i].to_le_bytes()[..bytes_per_sample]);
+        }
+    }
+    md5.update(&buf);
+}
+
+/// The smallest number of samples that a FLAC frame may hold, except
+/// for the last frame in a stream.
+const MIN_BLOCK_SIZE: usize = 16;
+
+/// The sequence of FLAC frames in a disc image, handed out to
+/// consecutive [Track]s in order.
+///
+/// When splitting sample-accurately, a frame that straddles a track
+/// boundary belongs to two tracks: The frame source holds on to it,
+/// so the next track can pick up the remaining samples.
+pub struct Frames {
+    reader: FlacReader,
+    sample_accurate: bool,
+    compute_md5: bool,
+    seekpoint_spacing: Option<u64>,
+    held: Option<Packet>,
+    decoder: Option<FlacDecoder>,
+    bits_per_sample: u32,
+    max_block_size: usize,
+}
+
+impl Frames {
+    /// Creates a frame source reading from a FLAC stream, for tracks
+    /// written with the given options.
+    pub fn new(reader: FlacReader, options: &SplitOptions) -> anyhow::Result<Self> {
+        let params = &reader
+            .default_track()
+            .context("no default track")?
+            .codec_params;
+        let info = StreamInfo::from_bytes(
+            params
+                .extra_data
+                .as_ref()
+                .context("Unclear track codec params - Not a flac file?")?,
+        );
+        let bits_per_sample = info.bits_per_sample.into();
+        let max_block_size = info.max_block_size.into();
+        Ok(Self {
+            reader,
+            sample_accurate: options.sample_accurate,
+            compute_md5: options.compute_md5,
+            seekpoint_spacing: seekpoint_spacing(options, info.sample_rate),
+            held: None,
+            decoder: None,
+            bits_per_sample,
+            max_block_size,
+        })
+    }
+
+    fn next_packet(&mut self) -> symphonia_core::errors::Result<Packet> {
+        match self.held.take() {
+            Some(packet) => Ok(packet),
+            None => self.reader.next_packet(),
+        }
+    }
+
+    /// Keeps a packet around to be returned by the next call to
+    /// [Frames::next_packet].
+    fn hold(&mut self, packet: Packet) {
+        debug_assert!(self.held.is_none(), "can only hold one packet");
+        self.held = Some(packet);
+    }
+
+    /// Decodes a packet into its per-channel samples.
+    fn decode(&mut self, packet: &Packet) -> anyhow::Result<Vec<Vec<i32>>> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None => {
+                let track = self.reader.default_track().context("no default track")?;
+                let decoder = FlacDecoder::try_new(&track.codec_params, &Default::default())
+                    .context("creating decoder")?;
+                self.decoder.insert(decoder)
             }
+        };
+        // The decoder scales all samples up to 32 bits, so scale them back down:
+        let shift = 32 - self.bits_per_sample;
+        match decoder.decode(packet)? {
+            AudioBufferRef::S32(buf) => Ok((0..buf.spec().channels.count())
+                .map(|channel| buf.chan(channel).iter().map(|s| s >> shift).collect())
+                .collect()),
+            _ => bail!("FLAC decoder returned non-integer samples"),
         }
     }
 }
@@ -377,71 +1593,274 @@ fn translate_visual_key(key: StandardVisualKey) -> PictureType {
 /// all the frames making up that track.
 #[derive(Default)]
 pub struct OffsetFrame {
-    initial_offset: Option<u64>,
+    /// Whether the track's frames are numbered by their first sample
+    /// (variable block size) instead of by frame number. Decided by
+    /// the first frame of the track.
+    variable_block_size: Option<bool>,
+    frames_processed: u64,
     samples_processed: u64,
+    bytes_processed: u64,
+    /// Block size bounds of the frames processed so far, except for
+    /// the most recent one (the last frame of a stream may be shorter
+    /// than the minimum).
+    block_size_bounds: Option<(u64, u64)>,
+    last_block_size: u64,
+    frame_size_bounds: Option<(u64, u64)>,
+    /// Number of samples between seek points, if we should record any.
+    seekpoint_spacing: Option<u64>,
+    next_seekpoint: u64,
+    seek_points: Vec<SeekPoint>,
 }
 
 impl OffsetFrame {
+    /// Creates an offset frame that records a seek point every
+    /// `seekpoint_spacing` samples, if given.
+    pub fn new(seekpoint_spacing: Option<u64>) -> Self {
+        Self {
+            seekpoint_spacing,
+            ..Default::default()
+        }
+    }
+
+    /// Makes the frames number themselves by their first sample
+    /// (variable block size) no matter how the first frame did, as
+    /// frames from several streams have to when they're put into one.
+    pub fn with_variable_block_size(mut self) -> Self {
+        self.variable_block_size = Some(true);
+        self
+    }
+
+    /// Summarizes the frames processed so far.
+    pub fn summary(&self) -> AudioSummary {
+        // STREAMINFO can't describe blocks shorter than the minimum
+        // (only the last one may be), so clamp to that:
+        let (mut min_block_size, max_block_size) = match self.block_size_bounds {
+            Some((min, max)) => (min, max.max(self.last_block_size)),
+            None => (self.last_block_size, self.last_block_size),
+        };
+        min_block_size = min_block_size.max(MIN_BLOCK_SIZE as u64);
+        let max_block_size = max_block_size.max(MIN_BLOCK_SIZE as u64);
+        let variable_block_size = self.variable_block_size.unwrap_or(false);
+        if variable_block_size && min_block_size == max_block_size {
+            // Equal bounds would mark the stream as fixed-blocksize,
+            // so fudge the minimum downwards:
+            min_block_size = MIN_BLOCK_SIZE as u64;
+        }
+        let (min_frame_size, max_frame_size) = self.frame_size_bounds.unwrap_or_default();
+        AudioSummary {
+            total_samples: self.samples_processed,
+            min_block_size: min_block_size.truncate(),
+            max_block_size: max_block_size.truncate(),
+            min_frame_size: min_frame_size.truncate(),
+            max_frame_size: max_frame_size.truncate(),
+            variable_block_size,
+            md5: None,
+            seek_points: self.seek_points.clone(),
+            frames: self.frames_processed,
+            warnings: vec![],
+        }
+    }
+
+    /// Accounts for a frame that was just emitted, recording a seek
+    /// point for it if it contains the next sample we want one for.
+    fn count_frame(&mut self, frame_len: usize, block_samples: u64) {
+        let frame_end = self.samples_processed + block_samples;
+        if let Some(spacing) = self.seekpoint_spacing.filter(|spacing| *spacing > 0) {
+            if self.next_seekpoint < frame_end {
+                self.seek_points.push(seek_point(
+                    self.samples_processed,
+                    self.bytes_processed,
+                    block_samples,
+                ));
+                self.next_seekpoint = frame_end.div_ceil(spacing) * spacing;
+            }
+        }
+        if self.frames_processed > 0 {
+            let previous = self.last_block_size;
+            self.block_size_bounds = Some(match self.block_size_bounds {
+                Some((min, max)) => (min.min(previous), max.max(previous)),
+                None => (previous, previous),
+            });
+        }
+        self.last_block_size = block_samples;
+        let frame_len = frame_len as u64;
+        self.frame_size_bounds = Some(match self.frame_size_bounds {
+            Some((min, max)) => (min.min(frame_len), max.max(frame_len)),
+            None => (frame_len, frame_len),
+        });
+        self.frames_processed += 1;
+        self.samples_processed = frame_end;
+        self.bytes_processed += frame_len;
+    }
+
+    /// The number that goes into the next frame's header.
+    fn next_number(&self) -> u64 {
+        if self.variable_block_size == Some(true) {
+            self.samples_processed
+        } else {
+            self.frames_processed
+        }
+    }
+
+    /// Encodes a new frame from per-channel samples, numbered such
+    /// that it follows the frames processed so far.
+    ///
+    /// A track whose first frame is re-encoded like this can't
+    /// have a fixed block size (unless that frame is also its
+    /// `last` one), so it switches the track to numbering frames by
+    /// their sample offset.
+    pub fn encode(
+        &mut self,
+        channels: &[Vec<i32>],
+        bits_per_sample: u32,
+        last: bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let variable = *self.variable_block_size.get_or_insert(!last);
+        let frame_out =
+            encode::encode_frame(self.next_number(), variable, bits_per_sample, channels)?;
+        self.count_frame(
+            frame_out.len(),
+            channels.first().map(Vec::len).unwrap_or(0) as u64,
+        );
+        Ok(frame_out)
+    }
+
     /// Processes a FLAC frame by rewriting its sample/frame offset
     /// and CRC checksums, and emits that frame in an updated byte
     /// buffer.
     ///
     /// Returns a byte buffer containing the updated frame.
     pub fn process(&mut self, packet: Packet) -> anyhow::Result<Vec<u8>> {
-        let mut frame_reader = packet.as_buf_reader();
-        let mut header_crc = Crc8Ccitt::new(0);
+        let frame = self.rewrite(packet.buf())?;
+
+        // The subframes stay as they are, but the footer CRC covers
+        // the new header too:
         let mut footer_crc = Crc16Ansi::new(0);
-        let mut frame_out = Vec::with_capacity(packet.buf().len());
+        footer_crc.process_buf_bytes(frame.header());
+        footer_crc.process_buf_bytes(frame.subframes);
+        let mut frame_out = Vec::with_capacity(frame.size());
+        frame_out.write_all(frame.header())?;
+        frame_out.write_all(frame.subframes)?;
+        frame_out.write_all(&footer_crc.crc().to_be_bytes())?;
+        Ok(frame_out)
+    }
 
-        // FLAC frame magic number / reserved bits
-        let sync = frame_reader.read_be_u16().context("reading frame sync")?;
-        let sync_u8 = sync.to_be_bytes();
-        header_crc.process_double_bytes(sync_u8);
-        footer_crc.process_double_bytes(sync_u8);
-        frame_out.write_all(&sync_u8)?;
+    /// Processes a FLAC frame like [OffsetFrame::process], but without
+    /// copying it: only its header and footer are rewritten, and the
+    /// subframes in between are borrowed from `frame` (e.g. a batch of
+    /// frames read from the disc image, see [indexed]).
+    ///
+    /// The new footer CRC is derived from the frame's old one and the
+    /// change to the header, instead of being computed over the whole
+    /// frame again.
+    pub fn process_borrowed<'a>(&mut self, frame: &'a [u8]) -> anyhow::Result<BorrowedFrame<'a>> {
+        let mut rewritten = self.rewrite(frame)?;
+        let (rest, original_footer) = frame.split_at(frame.len() - 2);
+        let original_header = &rest[..rest.len() - rewritten.subframes.len()];
+        let header_change = crc16(original_header) ^ crc16(rewritten.header());
+        let footer_crc = u16::from_be_bytes([original_footer[0], original_footer[1]])
+            ^ crc16_shift(header_change, rewritten.subframes.len());
+        rewritten.footer = footer_crc.to_be_bytes();
+        Ok(rewritten)
+    }
+
+    /// Rewrites a frame's header so the frame follows the frames
+    /// processed so far, and accounts for the frame. The footer is
+    /// left for the caller to fill in.
+    fn rewrite<'a>(&mut self, frame: &'a [u8]) -> anyhow::Result<BorrowedFrame<'a>> {
+        let parsed = FrameHeader::parse(frame)?;
+        let subframes = frame
+            .get(parsed.len..frame.len().saturating_sub(2))
+            .context("frame too short for its footer")?;
+        let mut header = [0; MAX_FRAME_HEADER_LEN];
+
+        // FLAC frame magic number / reserved bits, and the blocking
+        // strategy bit, which we may have to switch to variable,
+        // followed by the frame description:
+        let variable = *self
+            .variable_block_size
+            .get_or_insert(parsed.variable_block_size);
+        header[..4].copy_from_slice(&frame[..4]);
+        if variable {
+            header[1] |= 1;
+        }
 
-        // FLAC frame description
+        // Next up is the frame/sample number, here we munge some data:
+        let offset_u8 =
+            utf8_encode_be_u64(self.next_number()).context("encoding the new offset")?;
+        let mut len = 4 + offset_u8.len();
+        header[4..len].copy_from_slice(&offset_u8);
+
+        // The block size & sample rate (if the frame description says
+        // they're there) stay as they are:
+        let sizes = &frame[parsed.number_end..parsed.len - 1];
+        header[len..len + sizes.len()].copy_from_slice(sizes);
+        len += sizes.len();
+
+        // What follows is the header CRC, computed over the new header:
+        let mut header_crc = Crc8Ccitt::new(0);
+        header_crc.process_buf_bytes(&header[..len]);
+        header[len] = header_crc.crc();
+        len += 1;
+
+        let rewritten = BorrowedFrame {
+            header,
+            header_len: len,
+            subframes,
+            footer: [0; 2],
+        };
+        self.count_frame(rewritten.size(), parsed.block_samples);
+        Ok(rewritten)
+    }
+}
+
+/// The longest a frame header can be: sync and description, a 7-byte
+/// sample number, 16-bit block size and sample rate, and the CRC.
+const MAX_FRAME_HEADER_LEN: usize = 16;
+
+/// What [OffsetFrame] needs to know about a frame's header.
+pub(crate) struct FrameHeader {
+    /// Whether the frame is numbered by its first sample instead of
+    /// by frame number.
+    pub(crate) variable_block_size: bool,
+
+    /// The frame or sample number of the frame.
+    pub(crate) number: u64,
+
+    /// The number of samples (per channel) in the frame.
+    pub(crate) block_samples: u64,
+
+    /// Where the frame/sample number ends.
+    number_end: usize,
+
+    /// The length of the header, including its CRC.
+    pub(crate) len: usize,
+}
+
+impl FrameHeader {
+    /// Parses the header at the start of a frame, without checking
+    /// its CRC.
+    pub(crate) fn parse(frame: &[u8]) -> anyhow::Result<Self> {
+        let mut frame_reader = BufReader::new(frame);
+        let sync = frame_reader.read_be_u16().context("reading frame sync")?;
         let desc = frame_reader.read_be_u16().context("reading frame desc")?;
-        let desc_u8 = desc.to_be_bytes();
-        header_crc.process_double_bytes(desc_u8);
-        footer_crc.process_double_bytes(desc_u8);
-        frame_out.write_all(&desc_u8)?;
         let block_size_enc = u32::from((desc & 0xf000) >> 12);
         let sample_rate_enc = u32::from((desc & 0x0f00) >> 8);
-
-        // Next up is the frame/sample number, here we munge some data:
-        let (orig_sample_offset, _sample_n_bytes) =
+        let (number, _number_n_bytes) =
             utf8_decode_be_u64(&mut frame_reader).context("decoding the sample offset")?;
-        if self.initial_offset.is_none() {
-            debug!(orig_sample_offset, "first sample offset");
-            self.initial_offset.replace(orig_sample_offset);
-        }
-        let sample_offset = orig_sample_offset - self.initial_offset.unwrap();
-        let offset_u8 = utf8_encode_be_u64(sample_offset).context("encoding the new offset")?;
-        header_crc.process_buf_bytes(&offset_u8);
-        footer_crc.process_buf_bytes(&offset_u8);
-        frame_out.write_all(&offset_u8)?;
+        let number_end = usize::try_from(frame_reader.pos())?;
 
         // Now, some gymnastics to read the sample rate & block size
         // if necessary (behavior dictated by the appropriate bits in
         // the `desc` fields).
         let block_samples: u64 = match block_size_enc & 0b1111 {
             0b0110 => {
-                // block size is given in the next 8 bits:
-                let bs_u8 = frame_reader.read_u8().context("8bit block size")?;
-                header_crc.process_byte(bs_u8);
-                footer_crc.process_byte(bs_u8);
-                frame_out.write_all(&[bs_u8])?;
-                bs_u8.into()
+                // block size (minus one) is given in the next 8 bits:
+                u64::from(frame_reader.read_u8().context("8bit block size")?) + 1
             }
             0b0111 => {
-                // block size given in the next 16 bits:
-                let bs = frame_reader.read_be_u16().context("8bit block size")?;
-                let bs_u8 = bs.to_be_bytes();
-                header_crc.process_double_bytes(bs_u8);
-                footer_crc.process_double_bytes(bs_u8);
-                frame_out.write_all(&bs_u8)?;
-                bs.into()
+                // block size (minus one) given in the next 16 bits:
+                u64::from(frame_reader.read_be_u16().context("16bit block size")?) + 1
             }
             0b0001 => 192,
             0b0000 => bail!("reserved sample count"),
@@ -451,43 +1870,104 @@ impl OffsetFrame {
         match sample_rate_enc & 0b1111 {
             0b1100 => {
                 // sample rate is given in the next 8 bits:
-                let sr_u8 = frame_reader.read_u8().context("8bit block size")?;
-                header_crc.process_byte(sr_u8);
-                footer_crc.process_byte(sr_u8);
-                frame_out.write_all(&[sr_u8])?;
+                frame_reader.read_u8().context("8bit sample rate")?;
             }
             0b1101 | 0b1110 => {
                 // sample rate given in the next 16 bits:
-                let bs = frame_reader.read_be_u16().context("8bit block size")?;
-                let sr_u8 = bs.to_be_bytes();
-                header_crc.process_double_bytes(sr_u8);
-                footer_crc.process_double_bytes(sr_u8);
-                frame_out.write_all(&sr_u8)?;
+                frame_reader.read_be_u16().context("16bit sample rate")?;
             }
             0b1111 => anyhow::bail!("invalid sample rate: sync-fooling string of 1s"),
             _ => {
                 // No bits used for the field otherwise
             }
         }
+        frame_reader.read_u8().context("reading header CRC")?;
+        Ok(Self {
+            variable_block_size: sync & 1 == 1,
+            number,
+            block_samples,
+            number_end,
+            len: usize::try_from(frame_reader.pos())?,
+        })
+    }
+}
+
+/// A frame rewritten by [OffsetFrame::process_borrowed]: a new header
+/// and footer around the original frame's subframes.
+#[derive(Debug, Clone, Copy)]
+pub struct BorrowedFrame<'a> {
+    header: [u8; MAX_FRAME_HEADER_LEN],
+    header_len: usize,
+    subframes: &'a [u8],
+    footer: [u8; 2],
+}
 
-        // What follows is the header CRC. We write out the one computed above:
-        let _original_header_crc = frame_reader.read_u8().context("reading header CRC")?;
-        let my_header_crc = header_crc.crc();
-        footer_crc.process_byte(my_header_crc);
-        frame_out.write_all(&[my_header_crc])?;
+impl<'a> BorrowedFrame<'a> {
+    /// The frame's new header, including its CRC.
+    pub fn header(&self) -> &[u8] {
+        &self.header[..self.header_len]
+    }
 
-        // Next, the subframes; we do not touch them, but we do rewrite the footer CRC:
-        let remainder = frame_reader.read_buf_bytes_available_ref();
-        let subframes = &remainder[..remainder.len() - 2];
-        footer_crc.process_buf_bytes(subframes);
-        frame_out.write_all(subframes)?;
+    /// The frame's subframes, borrowed from the original frame.
+    pub fn subframes(&self) -> &'a [u8] {
+        self.subframes
+    }
 
-        let my_footer_crc = footer_crc.crc();
-        let my_footer_crc_u8 = my_footer_crc.to_be_bytes();
-        frame_out.write_all(&my_footer_crc_u8)?;
-        self.samples_processed += block_samples;
-        Ok(frame_out)
+    /// The frame's new footer CRC.
+    pub fn footer(&self) -> &[u8] {
+        &self.footer
+    }
+
+    /// The size of the whole frame, in bytes.
+    pub fn size(&self) -> usize {
+        self.header_len + self.subframes.len() + self.footer.len()
+    }
+}
+
+/// The CRC-16 polynomial of FLAC frame footers, x^16 + x^15 + x^2 + 1
+/// (without the x^16 term).
+const CRC16_POLYNOMIAL: u16 = 0x8005;
+
+/// Computes the CRC-16 of `bytes`, the way a FLAC frame footer has it.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = Crc16Ansi::new(0);
+    crc.process_buf_bytes(bytes);
+    crc.crc()
+}
+
+/// Returns what the CRC-16 `crc` of some bytes turns into when `len`
+/// more bytes follow them, minus the CRC of just those bytes. That is
+/// `crc` * x^(8 * `len`) modulo the polynomial: with an initial value
+/// of 0, the CRC of A followed by B is the CRC of A multiplied that
+/// way, plus the CRC of B.
+fn crc16_shift(crc: u16, len: usize) -> u16 {
+    let mut shifted = crc;
+    let mut power = 1 << 8; // x^8, for one byte
+    let mut len = len;
+    while len > 0 {
+        if len & 1 == 1 {
+            shifted = crc16_multiply(shifted, power);
+        }
+        power = crc16_multiply(power, power);
+        len >>= 1;
+    }
+    shifted
+}
+
+/// Multiplies two polynomials over GF(2), modulo the CRC-16 polynomial.
+fn crc16_multiply(a: u16, b: u16) -> u16 {
+    let mut product = 0u16;
+    for bit in (0..16).rev() {
+        let carry = product & 0x8000 != 0;
+        product <<= 1;
+        if carry {
+            product ^= CRC16_POLYNOMIAL;
+        }
+        if (b >> bit) & 1 == 1 {
+            product ^= a;
+        }
     }
+    product
 }
 
 /// Decodes a big-endian unsigned integer encoded via extended UTF8. In this context, extended UTF8
@@ -495,7 +1975,9 @@ impl OffsetFrame {
 /// 36-bits. Returns the number of bytes that contain the encoded number as the second value.
 ///
 // Taken from symphonia.
-fn utf8_decode_be_u64<B: symphonia_core::io::ReadBytes>(src: &mut B) -> anyhow::Result<(u64, u32)> {
+pub(crate) fn utf8_decode_be_u64<B: symphonia_core::io::ReadBytes>(
+    src: &mut B,
+) -> anyhow::Result<(u64, u32)> {
     // Read the first byte of the UTF8 encoded integer.
     let mut state = u64::from(src.read_u8()?);
 
@@ -585,7 +2067,7 @@ mod test {
     use super::*;
     use proptest::{prop_assert_eq, proptest};
     use std::fmt;
-    use symphonia_core::io::BufReader;
+    use symphonia_core::{formats::CuePoint, io::BufReader};
 
     struct V<'a>(&'a [u8]);
 
@@ -609,6 +2091,715 @@ mod test {
         }
     }
 
+    /// Returns a source of pseudo-random noise samples, with an RMS
+    /// of about -29 dBFS at 16 bits.
+    pub(crate) fn noise() -> impl FnMut() -> i32 {
+        let mut state = 1u32;
+        move || {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            i32::from((state >> 16) as i16) >> 4
+        }
+    }
+
+    /// Writes a 44.1kHz 16-bit stereo disc image of pseudo-random
+    /// noise in frames of 4096 samples (the last one shorter), with a
+    /// track starting at each of `starts`.
+    pub(crate) fn write_test_image(path: &Path, total_samples: u64, starts: &[u64]) {
+        let mut noise = noise();
+        write_test_image_from(path, total_samples, starts, 16, |_| noise());
+    }
+
+    /// Writes a disc image like [write_test_image] does, with samples
+    /// of the given size and the given sample (for both channels) at
+    /// each sample number.
+    pub(crate) fn write_test_image_from(
+        path: &Path,
+        total_samples: u64,
+        starts: &[u64],
+        bits_per_sample: u8,
+        mut sample_at: impl FnMut(u64) -> i32,
+    ) {
+        use metaflac::block::{CueSheet as CueSheetBlock, CueSheetTrack, CueSheetTrackIndex};
+        let mut audio = vec![];
+        for (number, start) in (0..total_samples).step_by(4096).enumerate() {
+            let end = (start + 4096).min(total_samples);
+            let channels: Vec<Vec<i32>> = (0..2)
+                .map(|_| (start..end).map(&mut sample_at).collect())
+                .collect();
+            audio.extend(
+                encode::encode_frame(number as u64, false, bits_per_sample.into(), &channels)
+                    .unwrap(),
+            );
+        }
+        let streaminfo = StreamInfo {
+            min_block_size: 4096,
+            max_block_size: 4096,
+            sample_rate: 44100,
+            num_channels: 2,
+            bits_per_sample,
+            total_samples,
+            md5: vec![0; 16],
+            ..StreamInfo::new()
+        };
+        let index = |point_num| CueSheetTrackIndex {
+            offset: 0,
+            point_num,
+        };
+        let mut cue_tracks: Vec<CueSheetTrack> = starts
+            .iter()
+            .zip(1..)
+            .map(|(start, number)| CueSheetTrack {
+                offset: *start,
+                number,
+                indices: vec![index(1)],
+                ..CueSheetTrack::new()
+            })
+            .collect();
+        cue_tracks.push(CueSheetTrack {
+            offset: total_samples,
+            number: LEAD_OUT_TRACK_NUMBER as u8,
+            ..CueSheetTrack::new()
+        });
+        let mut file = File::create(path).unwrap();
+        file.write_all(b"fLaC").unwrap();
+        Block::StreamInfo(streaminfo)
+            .write_to(false, &mut file)
+            .unwrap();
+        Block::CueSheet(CueSheetBlock {
+            is_cd: false,
+            tracks: cue_tracks,
+            ..CueSheetBlock::new()
+        })
+        .write_to(true, &mut file)
+        .unwrap();
+        file.write_all(&audio).unwrap();
+    }
+
+    /// Splits a disc image twice, once cutting tracks at frame
+    /// boundaries and once sample-accurately, into
+    /// `<image>.split-false` and `<image>.split-true`, with `options`
+    /// otherwise. Returns the options and report of each split.
+    pub(crate) fn split_both_ways(
+        image: &Path,
+        options: SplitOptions,
+    ) -> Vec<(SplitOptions, SplitReport)> {
+        [false, true]
+            .into_iter()
+            .map(|sample_accurate| {
+                let options = SplitOptions {
+                    sample_accurate,
+                    ..options.clone()
+                };
+                let out = image.with_extension(format!("split-{}", sample_accurate));
+                let report = split_one_file(image, &out, &options).unwrap();
+                (options, report)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 30_100, 71_000]);
+        let options = SplitOptions {
+            seekpoint_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        for (options, report) in split_both_ways(&image, options) {
+            for track in &report.tracks {
+                // The track goes wherever the writer is, here after
+                // something else:
+                let mut extracted = std::io::Cursor::new(b"before".to_vec());
+                extracted.seek(std::io::SeekFrom::End(0)).unwrap();
+                extract_track(&image, track.number, &options, &mut extracted).unwrap();
+                assert_eq!(extracted.position(), extracted.get_ref().len() as u64);
+                let extracted = extracted.into_inner();
+                assert_eq!(&extracted[..6], b"before");
+                assert_eq!(
+                    extracted[6..],
+                    std::fs::read(&track.path).unwrap(),
+                    "track {} (sample accurate: {})",
+                    track.number,
+                    options.sample_accurate
+                );
+            }
+        }
+        let mut extracted = std::io::Cursor::new(vec![]);
+        assert!(extract_track(&image, 5, &Default::default(), &mut extracted).is_err());
+    }
+
+    #[test]
+    fn test_split_tracks_in_parallel() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        // Tracks 2 and 3 are shorter than a frame:
+        write_test_image(&image, 200_000, &[0, 40_000, 40_100, 40_200, 123_456]);
+        for (options, report) in split_both_ways(&image, Default::default()) {
+            let out = image.with_extension(format!("split-{}", options.sample_accurate));
+            let numbers: Vec<u32> = report.tracks.iter().map(|track| track.number).collect();
+            assert_eq!(numbers, vec![1, 2, 3, 4, 5]);
+            let verification = verify::verify_one_file(&image, &out, &options).unwrap();
+            assert!(verification.is_ok(), "{:?}", verification);
+        }
+    }
+
+    #[test]
+    fn test_resolve_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01.Title.flac");
+        assert_eq!(
+            resolve_conflict(&path, ConflictPolicy::Fail).unwrap(),
+            Some(path.clone())
+        );
+        File::create(&path).unwrap();
+        File::create(dir.path().join("01.Title (1).flac")).unwrap();
+        assert!(resolve_conflict(&path, ConflictPolicy::Fail).is_err());
+        assert_eq!(resolve_conflict(&path, ConflictPolicy::Skip).unwrap(), None);
+        assert_eq!(
+            resolve_conflict(&path, ConflictPolicy::Overwrite).unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(
+            resolve_conflict(&path, ConflictPolicy::Rename).unwrap(),
+            Some(dir.path().join("01.Title (2).flac"))
+        );
+    }
+
+    #[test]
+    fn test_skip_existing_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 30_100, 71_000]);
+        for (sample_accurate, compute_md5) in [(false, false), (false, true), (true, false)] {
+            let options = SplitOptions {
+                sample_accurate,
+                compute_md5,
+                on_conflict: ConflictPolicy::Skip,
+                ..Default::default()
+            };
+            let out = dir
+                .path()
+                .join(format!("split-{}-{}", sample_accurate, compute_md5));
+            let written = split_one_file(&image, &out, &options).unwrap();
+            std::fs::write(&written.tracks[1].path, b"keep me").unwrap();
+            let skipped = split_one_file(&image, &out, &options).unwrap();
+            assert_eq!(std::fs::read(&written.tracks[1].path).unwrap(), b"keep me");
+            for (written, skipped) in written.tracks.iter().zip(&skipped.tracks) {
+                assert!(skipped.skipped);
+                assert_eq!(skipped.path, written.path);
+                assert_eq!(skipped.actual_samples, written.actual_samples);
+                assert_eq!(skipped.warnings, written.warnings);
+                if !sample_accurate {
+                    assert_eq!(skipped.frames, written.frames);
+                }
+            }
+            assert_eq!(skipped.warnings.len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_plan_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 71_000]);
+        let out = dir.path().join("out");
+        let options = SplitOptions {
+            tags: vec![
+                Tag::new(None, "ALBUMARTIST", Value::from("Band")),
+                Tag::new(None, "ALBUM", Value::from("Album")),
+            ],
+            ..Default::default()
+        };
+        let planned = plan_one_file(&image, &out, &options).unwrap();
+        assert!(!out.exists());
+        let paths: Vec<PathBuf> = planned.iter().map(|track| track.path.clone()).collect();
+        assert_eq!(
+            paths,
+            ["01.Track 01.flac", "02.Track 02.flac", "03.Track 03.flac"]
+                .iter()
+                .map(|name| out.join("Band/Album").join(name))
+                .collect::<Vec<_>>()
+        );
+        let spans: Vec<(u64, u64)> = planned
+            .iter()
+            .map(|planned| (planned.track.start_ts, planned.track.end_ts))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![(0, 30_000), (30_000, 71_000), (71_000, 100_000)]
+        );
+
+        let report = split_one_file(&image, &out, &options).unwrap();
+        assert_eq!(report.written_paths().cloned().collect::<Vec<_>>(), paths);
+    }
+
+    #[test]
+    fn test_split_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 100_000, &[0, 30_000, 30_100, 71_000]);
+        let options = SplitOptions {
+            tags: vec![Tag::new(None, "ARTIST", Value::from("Band"))],
+            ..Default::default()
+        };
+        let report = split_one_file(&image, dir.path(), &options).unwrap();
+        assert_eq!(report.input, image);
+        assert!(report.warnings.is_empty());
+        // (number, start, end, frames, samples written)
+        let expected = [
+            (1, 0, 30_000, 8, 8 * 4096),
+            (2, 30_000, 30_100, 1, 4096),
+            (3, 30_100, 71_000, 9, 9 * 4096),
+            (4, 71_000, 100_000, 7, 100_000 - 18 * 4096),
+        ];
+        for (track, (number, start, end, frames, samples)) in report.tracks.iter().zip(expected) {
+            assert_eq!(track.number, number);
+            assert!(!track.skipped);
+            assert_eq!((track.start_sample, track.end_sample), (start, end));
+            assert_eq!(track.frames, frames);
+            assert_eq!(track.inferred_samples, end - start);
+            assert_eq!(track.actual_samples, samples);
+            assert_eq!(
+                track.samples_difference,
+                samples as i64 - (end - start) as i64
+            );
+            assert_eq!(
+                track.bytes_written,
+                std::fs::metadata(&track.path).unwrap().len()
+            );
+            assert_eq!(track.tags["ARTIST"], vec!["Band"]);
+            assert!(track.warnings.is_empty());
+        }
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["tracks"][1]["samples_difference"], 3996);
+    }
+
+    #[test]
+    fn test_metadata_is_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let mut track = open_disc_image(&image, dir.path(), &Default::default())
+            .unwrap()
+            .remove(0)
+            .track;
+        for (key, value) in [("TITLE", "One"), ("ARTIST", "Band"), ("ALBUM", "Album")] {
+            track.tags.push(Tag::new(None, key, Value::from(value)));
+        }
+        let audio = AudioSummary {
+            total_samples: 20_480,
+            ..Default::default()
+        };
+        let metadata = || {
+            let mut metadata = vec![];
+            track.write_metadata(&audio, 0, &mut metadata).unwrap();
+            metadata
+        };
+        let first = metadata();
+        for _ in 0..10 {
+            assert!(metadata() == first);
+        }
+
+        let tag = metaflac::Tag::read_from(&mut std::io::Cursor::new(first)).unwrap();
+        let comments = &tag.vorbis_comments().unwrap().comments;
+        assert_eq!(comments["TITLE"], vec!["One"]);
+        assert_eq!(comments["ARTIST"], vec!["Band"]);
+        assert_eq!(comments["ALBUM"], vec!["Album"]);
+    }
+
+    #[test]
+    fn test_write_seekable() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 200_000, &[0, 30_000, 30_100, 110_000]);
+        let options = SplitOptions {
+            compute_md5: true,
+            seekpoint_interval: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        for (options, report) in split_both_ways(&image, options) {
+            let tracks = open_disc_image(&image, dir.path(), &options).unwrap();
+            for index in 0..tracks.len() {
+                let track = &tracks[index].track;
+                let mut frames = frames_for_track(&image, &tracks, index, &options).unwrap();
+                let mut file = tempfile::tempfile().unwrap();
+                let audio = track
+                    .write_seekable(&mut frames, options.metadata_padding, &mut file)
+                    .unwrap();
+                let length = file.stream_position().unwrap();
+                assert_eq!(length, file.metadata().unwrap().len());
+
+                // It's the same track that splitting writes:
+                let mut contents = vec![];
+                file.seek(std::io::SeekFrom::Start(0)).unwrap();
+                std::io::Read::read_to_end(&mut file, &mut contents).unwrap();
+                assert!(
+                    contents == std::fs::read(&report.tracks[index].path).unwrap(),
+                    "track {} (sample accurate: {})",
+                    track.number,
+                    options.sample_accurate
+                );
+
+                // Go over the frames that were written:
+                file.seek(std::io::SeekFrom::Start(0)).unwrap();
+                let mss =
+                    MediaSourceStream::new(Box::new(file.try_clone().unwrap()), Default::default());
+                let mut written = Frames::new(
+                    FlacReader::try_new(mss, &Default::default()).unwrap(),
+                    &options,
+                )
+                .unwrap();
+                let mut md5 = Md5::new();
+                let mut sizes = vec![];
+                let mut starts = vec![];
+                let mut decoded_samples = 0;
+                while let Ok(packet) = written.next_packet() {
+                    decoded_samples += packet.dur;
+                    starts.push((packet.ts, sizes.iter().sum::<u64>()));
+                    sizes.push(packet.buf().len() as u64);
+                    update_md5(&mut md5, &written.decode(&packet).unwrap(), 16);
+                }
+                let audio_start = metadata_end(&mut file).unwrap();
+                assert_eq!(audio_start + sizes.iter().sum::<u64>(), length);
+
+                file.seek(std::io::SeekFrom::Start(0)).unwrap();
+                let tag = metaflac::Tag::read_from(&mut file).unwrap();
+                let streaminfo = tag.get_streaminfo().unwrap();
+                assert_eq!(streaminfo.total_samples, decoded_samples);
+                assert_eq!(streaminfo.total_samples, audio.total_samples);
+                assert_eq!(streaminfo.md5, md5.finalize().to_vec());
+                assert_eq!(
+                    u64::from(streaminfo.min_frame_size),
+                    *sizes.iter().min().unwrap()
+                );
+                assert_eq!(
+                    u64::from(streaminfo.max_frame_size),
+                    *sizes.iter().max().unwrap()
+                );
+
+                // All reserved seek points are there, the ones in use
+                // pointing at frames:
+                let table = tag
+                    .blocks()
+                    .find_map(|block| match block {
+                        Block::SeekTable(table) => Some(table.seekpoints.clone()),
+                        _ => None,
+                    })
+                    .unwrap();
+                assert_eq!(
+                    table.len(),
+                    track
+                        .reserved_seek_points(frames.seekpoint_spacing)
+                        .unwrap()
+                );
+                let points: Vec<(u64, u64)> = table
+                    .iter()
+                    .map(|point| {
+                        let bytes = point.to_bytes();
+                        (
+                            u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+                            u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+                        )
+                    })
+                    .filter(|(sample, _)| *sample != u64::MAX)
+                    .collect();
+                assert_eq!(points.len(), audio.seek_points.len());
+                assert!(!points.is_empty());
+                for point in points {
+                    assert!(starts.contains(&point), "{:?}", point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewrite_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01.Title.flac");
+        let audio = b"not really audio frames";
+        let cue = Cue {
+            index: 1,
+            start_ts: 0,
+            tags: vec![],
+            points: vec![],
+        };
+        let track = |title: &str| {
+            let tags = [Tag::new(None, "TITLE", Value::from(title))];
+            let streaminfo = StreamInfo {
+                sample_rate: 44100,
+                num_channels: 2,
+                bits_per_sample: 16,
+                ..StreamInfo::new()
+            };
+            Track::from_tags(&streaminfo, &cue, 100, &tags, &[])
+        };
+        let mut file = File::create(&path).unwrap();
+        track("Old")
+            .write_metadata(&AudioSummary::default(), 100, &mut file)
+            .unwrap();
+        file.write_all(audio).unwrap();
+        drop(file);
+        let original_len = std::fs::metadata(&path).unwrap().len();
+        let title = |path: &Path| {
+            let tag = metaflac::Tag::read_from_path(path).unwrap();
+            let title = tag.get_vorbis("TITLE").unwrap().next().unwrap().to_string();
+            title
+        };
+        let audio_of = |path: &Path| {
+            let contents = std::fs::read(path).unwrap();
+            let start = metadata_end(&mut std::fs::File::open(path).unwrap()).unwrap();
+            contents[start as usize..].to_vec()
+        };
+
+        // Fits into the padding:
+        assert!(!track("New").rewrite_metadata(&path, 100).unwrap());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), original_len);
+        assert_eq!(title(&path), "New");
+        assert_eq!(audio_of(&path), audio);
+
+        // Fits exactly, leaving no room for a padding block:
+        let exact = "x".repeat(3 + 4 + 100);
+        assert!(!track(&exact).rewrite_metadata(&path, 100).unwrap());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), original_len);
+        assert_eq!(title(&path), exact);
+        assert_eq!(audio_of(&path), audio);
+
+        // Doesn't fit:
+        let long = "x".repeat(500);
+        assert!(track(&long).rewrite_metadata(&path, 100).unwrap());
+        assert_eq!(title(&path), long);
+        assert_eq!(audio_of(&path), audio);
+    }
+
+    #[test]
+    fn test_cue_sheet_comment_tags() {
+        let text = "TITLE \"Album\"\nPERFORMER \"Band\"\nFILE \"a.wav\" WAVE\n\
+                    TRACK 01 AUDIO\nTITLE \"One\"\nPERFORMER \"Guest\"\nINDEX 01 00:00:00\n\
+                    TRACK 02 AUDIO\nTITLE \"Two\"\nINDEX 01 00:10:00\n";
+        let tags = vec![
+            Tag::new(None, "ARTIST", Value::from("Band")),
+            Tag::new(None, "TITLE[2]", Value::from("Second")),
+            Tag::new(None, "CUESHEET", Value::from(text)),
+        ];
+        let mut cues: Vec<Cue> = [1, 2]
+            .into_iter()
+            .map(|index| Cue {
+                index,
+                start_ts: 0,
+                tags: vec![Tag::new(None, "ISRC", Value::from(""))],
+                points: vec![],
+            })
+            .collect();
+        let sheet = cue_sheet_comment(&tags).expect("parsing comment");
+        merge_cue_sheet_tags(&mut cues, &sheet);
+
+        let info = StreamInfo::default();
+        let track = |cue| Track::from_tags(&info, cue, 0, &tags, &[]);
+        let value = |track: &Track, name| track.tag_value(name).map(|v| v.to_string());
+        let first = track(&cues[0]);
+        assert_eq!(value(&first, "TITLE").as_deref(), Some("One"));
+        assert_eq!(value(&first, "ARTIST").as_deref(), Some("Guest"));
+        assert_eq!(value(&first, "ISRC"), None);
+        let second = track(&cues[1]);
+        assert_eq!(value(&second, "TITLE").as_deref(), Some("Second"));
+        assert_eq!(value(&second, "ARTIST").as_deref(), Some("Band"));
+        assert_eq!(value(&second, "CUESHEET"), None);
+    }
+
+    #[test]
+    fn test_track_cues_pregaps() {
+        let point = |offset, number: u64| CuePoint {
+            start_offset_ts: offset,
+            tags: vec![Tag::new(
+                None,
+                cuesheet::INDEX_TAG,
+                Value::UnsignedInt(number),
+            )],
+        };
+        let cue = |index, start_ts, points| Cue {
+            index,
+            start_ts,
+            tags: vec![],
+            points,
+        };
+        // Track 1 has hidden audio before it, track 2 a pregap:
+        let cues = vec![
+            cue(1, 0, vec![point(0, 0), point(100, 1)]),
+            cue(2, 500, vec![point(0, 0), point(50, 1)]),
+            cue(3, 800, vec![point(0, 1)]),
+            cue(LEAD_OUT_TRACK_NUMBER, 1000, vec![]),
+        ];
+        let spans = |mode| {
+            track_cues(&cues, 2000, mode)
+                .into_iter()
+                .map(|(cue, end)| (cue.index, cue.start_ts, end))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            spans(PregapMode::Append),
+            vec![(0, 0, 100), (1, 100, 550), (2, 550, 800), (3, 800, 1000)]
+        );
+        assert_eq!(
+            spans(PregapMode::Prepend),
+            vec![(1, 0, 500), (2, 500, 800), (3, 800, 1000)]
+        );
+        assert_eq!(
+            spans(PregapMode::Discard),
+            vec![(0, 0, 100), (1, 100, 500), (2, 550, 800), (3, 800, 1000)]
+        );
+    }
+
+    #[test]
+    fn test_summary_size_bounds() {
+        let mut frame = OffsetFrame::default();
+        frame.count_frame(100, 4096);
+        frame.count_frame(90, 4096);
+        // The last frame doesn't count towards the minimum block size:
+        frame.count_frame(20, 100);
+        let summary = frame.summary();
+        assert_eq!(
+            (summary.min_block_size, summary.max_block_size),
+            (4096, 4096)
+        );
+        assert_eq!((summary.min_frame_size, summary.max_frame_size), (20, 100));
+        assert_eq!(summary.total_samples, 8292);
+    }
+
+    /// Decodes a FLAC file into its per-channel samples.
+    pub(crate) fn decode_file(path: &Path) -> Vec<Vec<i32>> {
+        let mut frames = Frames::new(open_reader(path).unwrap(), &Default::default()).unwrap();
+        let mut channels: Vec<Vec<i32>> = vec![];
+        while let Ok(packet) = frames.next_packet() {
+            let decoded = frames.decode(&packet).unwrap();
+            channels.resize(decoded.len(), vec![]);
+            for (channel, samples) in channels.iter_mut().zip(decoded) {
+                channel.extend(samples);
+            }
+        }
+        channels
+    }
+
+    #[test]
+    fn test_track_md5() {
+        let dir = tempfile::tempdir().unwrap();
+        for bits_per_sample in [16, 24] {
+            let image = dir.path().join(format!("image-{}.flac", bits_per_sample));
+            // Noise in the upper 16 bits, and something in the lower
+            // ones that gets lost if samples are cut down to 16 bits:
+            let mut noise = noise();
+            write_test_image_from(
+                &image,
+                100_000,
+                &[0, 30_000, 30_100, 71_000],
+                bits_per_sample,
+                |n| match bits_per_sample {
+                    16 => noise(),
+                    _ => noise() << 8 | (n % 256) as i32,
+                },
+            );
+            let options = SplitOptions {
+                compute_md5: true,
+                ..Default::default()
+            };
+            for (options, report) in split_both_ways(&image, options) {
+                for track in &report.tracks {
+                    let channels = decode_file(&track.path);
+                    let bytes_per_sample = usize::from(bits_per_sample / 8);
+                    let mut pcm = vec![];
+                    for i in 0..channels[0].len() {
+                        for channel in &channels {
+                            pcm.extend_from_slice(&channel[i].to_le_bytes()[..bytes_per_sample]);
+                        }
+                    }
+                    let tag = metaflac::Tag::read_from_path(&track.path).unwrap();
+                    let streaminfo = tag.get_streaminfo().unwrap();
+                    assert_eq!(streaminfo.bits_per_sample, bits_per_sample);
+                    assert_eq!(streaminfo.total_samples, channels[0].len() as u64);
+                    assert_eq!(
+                        streaminfo.md5,
+                        Md5::digest(&pcm).to_vec(),
+                        "track {} ({} bits, sample accurate: {})",
+                        track.number,
+                        bits_per_sample,
+                        options.sample_accurate
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_seektable() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 200_000, &[0, 30_000, 30_100, 110_000]);
+        let spacing = 22_050;
+        let options = SplitOptions {
+            seekpoint_interval: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        for (_, report) in split_both_ways(&image, options) {
+            for track in &report.tracks {
+                let contents = std::fs::read(&track.path).unwrap();
+                let audio_start = metadata_end(&mut File::open(&track.path).unwrap()).unwrap();
+                let tag = metaflac::Tag::read_from_path(&track.path).unwrap();
+                let total_samples = tag.get_streaminfo().unwrap().total_samples;
+                // metaflac doesn't let us read these field by field:
+                let points: Vec<(u64, u64, u64)> = tag
+                    .blocks()
+                    .find_map(|block| match block {
+                        Block::SeekTable(table) => Some(table.seekpoints.clone()),
+                        _ => None,
+                    })
+                    .unwrap()
+                    .iter()
+                    .map(|point| {
+                        let bytes = point.to_bytes();
+                        (
+                            u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+                            u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+                            u64::from(u16::from_be_bytes(bytes[16..18].try_into().unwrap())),
+                        )
+                    })
+                    .filter(|(sample, _, _)| *sample != u64::MAX)
+                    .collect();
+
+                // Each point leads to the frame holding its samples:
+                for &(sample, offset, num_samples) in &points {
+                    let frame = &contents[(audio_start + offset) as usize..];
+                    let header = FrameHeader::parse(frame).unwrap();
+                    let first_sample = if header.variable_block_size {
+                        header.number
+                    } else {
+                        header.number * 4096
+                    };
+                    assert_eq!(first_sample, sample, "track {}", track.number);
+                    assert_eq!(header.block_samples, num_samples);
+                }
+                // ...and there's one for every multiple of the spacing:
+                let covered: Vec<u64> = (0..total_samples)
+                    .step_by(spacing)
+                    .map(|sample| {
+                        points
+                            .iter()
+                            .position(|(start, _, num_samples)| {
+                                (*start..start + num_samples).contains(&sample)
+                            })
+                            .unwrap_or_else(|| panic!("no seek point for sample {}", sample))
+                            as u64
+                    })
+                    .collect();
+                let mut distinct = covered.clone();
+                distinct.dedup();
+                assert_eq!(distinct.len(), points.len(), "track {}", track.number);
+                assert_eq!(points[0].0, 0);
+                assert_eq!(points[0].1, 0);
+            }
+        }
+    }
+
     proptest! {
         #[test]
         fn test_encoding(input in 0..(2u64.pow(35))) {
@@ -619,5 +2810,27 @@ mod test {
                 "received:\n{:#064b} but wanted:\n{:#064b}",
                 decoded.0, input);
         }
+
+        #[test]
+        fn test_process_borrowed(
+            number in 0..(2u64.pow(30)),
+            preceding in 0usize..3,
+            samples in proptest::collection::vec(-2048i32..2048, 1..300),
+        ) {
+            let frame = encode::encode_frame(number, true, 16, &[samples]).expect("encoding");
+            let mut copying = OffsetFrame::new(None);
+            let mut borrowing = OffsetFrame::new(None);
+            for _ in 0..preceding {
+                copying.process(Packet::new_from_slice(0, 0, 0, &frame)).expect("processing");
+                borrowing.process_borrowed(&frame).expect("processing");
+            }
+            let copied = copying.process(Packet::new_from_slice(0, 0, 0, &frame)).expect("processing");
+            let borrowed = borrowing.process_borrowed(&frame).expect("processing");
+            prop_assert_eq!(borrowed.subframes().as_ptr(), frame[frame.len() - borrowed.subframes().len() - 2..].as_ptr());
+            prop_assert_eq!(
+                copied,
+                [borrowed.header(), borrowed.subframes(), borrowed.footer()].concat()
+            );
+        }
     }
 }
@@ -1,20 +1,180 @@
-use std::path::PathBuf;
+use std::{
+    any::Any,
+    collections::HashMap,
+    fs,
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, bail, Context};
 use bytesize::ByteSize;
-use clap::Parser;
-use flac_tracksplit::split_one_file;
+use clap::{Parser, Subcommand, ValueEnum};
+use encoding_rs::Encoding;
+use flac_tracksplit::{
+    discs::{synthesize_disc_tags, DiscLayout},
+    inputs::{find_disc_images, InputFilter},
+    join::{find_tracks, join_tracks},
+    plan_one_file,
+    report::SplitReport,
+    retag_one_file,
+    silence::{self, SilenceOptions},
+    split_one_file,
+    split_points::SplitPoints,
+    sync::{Freshness, ImageState, Manifest},
+    tag_file,
+    template::PathTemplate,
+    verify::{verify_one_file, Verification},
+    ConflictPolicy, PlannedTrack, PregapMode, SplitOptions,
+};
 use rayon::prelude::*;
-use tracing::error;
+use symphonia_core::meta::Tag;
+use tracing::{error, info, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
 #[derive(Debug, Parser)]
-#[command(author, version, about, long_about=None)]
+#[command(author, version, about, long_about=None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Check that the tracks split from these files earlier (with the
+    /// same options) hold exactly the files' audio, and that their
+    /// frames and STREAMINFO blocks are intact. Exits with an error
+    /// status if any of them aren't.
+    Verify(Args),
+
+    /// Join split tracks back into one disc image, with a CUE sheet
+    /// and per-track tags embedded.
+    Join(JoinArgs),
+
+    /// Mount a read-only filesystem of the tracks in a directory of
+    /// disc images, laid out like splitting them would, with each
+    /// track file put together from its disc image when it is read.
+    /// Serves the filesystem until it gets unmounted (or interrupted).
+    #[cfg(feature = "mount")]
+    Mount(MountArgs),
+}
+
+#[cfg(feature = "mount")]
+#[derive(Debug, clap::Args)]
+struct MountArgs {
+    /// The directory of disc images to present the tracks of.
+    archive: PathBuf,
+
+    /// Where to mount the filesystem.
+    mountpoint: PathBuf,
+
+    /// Only present the tracks of disc images whose pathname
+    /// (relative to the archive) matches this glob. Can be given
+    /// several times.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Leave out the tracks of disc images whose pathname (relative to
+    /// the archive) matches this glob. Can be given several times.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Name tracks according to this template, like when splitting.
+    #[arg(long, value_parser = PathTemplate::from_str)]
+    template: Option<PathTemplate>,
+
+    /// How to keep the discs of a multi-disc release apart.
+    #[arg(long, value_enum, default_value_t = Discs::Subdirectory)]
+    disc_layout: Discs,
+
+    /// Where to put the pregap audio between a track's INDEX 00 and
+    /// INDEX 01.
+    #[arg(long, value_enum, default_value_t = Pregap::Append)]
+    pregap: Pregap,
+
+    /// Number of 0-byte padding to add to the end of each track's
+    /// metadata block.
+    #[arg(long, default_value = "2kB")]
+    metadata_padding: ByteSize,
+
+    /// How far apart the points of each track's SEEKTABLE should be
+    /// (e.g. "10s"); "0s" writes no SEEKTABLE.
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    seektable_interval: Duration,
+
+    /// Let other users than the one mounting read the filesystem
+    /// (which takes `user_allow_other` in /etc/fuse.conf, unless
+    /// mounting as root).
+    #[arg(long)]
+    allow_other: bool,
+
+    /// Where to remember the sizes of track files between mounts, so
+    /// that disc images don't all need reading to list them. Defaults
+    /// to `flac-tracksplit/track-sizes.json` in the user's cache
+    /// directory.
+    #[arg(long, value_name = "FILE")]
+    size_cache: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct JoinArgs {
+    /// A directory of tracks (joined in the order of their track
+    /// numbers), or the track files to join, in order.
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// Pathname of the disc image to write.
+    #[arg(long, short)]
+    output: PathBuf,
+
+    /// What to do if the disc image already exists.
+    #[arg(long, value_enum, default_value_t = Conflict::Fail)]
+    on_conflict: Conflict,
+
+    /// Number of 0-byte padding to add to the end of the metadata
+    /// block.
+    #[arg(long, default_value = "2kB")]
+    metadata_padding: ByteSize,
+
+    /// How far apart the points of the image's SEEKTABLE should be
+    /// (e.g. "10s"); "0s" writes no SEEKTABLE.
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    seektable_interval: Duration,
+}
+
+#[derive(Debug, clap::Args)]
 struct Args {
-    /// Pathnames of .flac files (with embedded CUE sheets) to split into tracks.
+    /// Pathnames of .flac files (with embedded CUE sheets, or .cue files next to them) to split into tracks.
+    /// Directories are searched for such files recursively.
     paths: Vec<PathBuf>,
 
+    /// Only split the files in directories whose pathname (relative
+    /// to the directory given) matches this glob, like "*/Live/**".
+    /// Can be given several times.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Don't split the files in directories whose pathname (relative
+    /// to the directory given) matches this glob. Can be given
+    /// several times.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Put each disc image's tracks into a directory named after the
+    /// image, at the same place under the output directory as the
+    /// image is under the directory given, instead of sorting them by
+    /// artist and album.
+    #[arg(long)]
+    mirror_input: bool,
+
     /// Output directory into which to sort resulting per-track FLAC files.
     /// Tracks will be named according to this template:
     ///
@@ -22,11 +182,275 @@ struct Args {
     #[arg(long, default_value = "./")]
     output_dir: PathBuf,
 
+    /// Name tracks according to this template instead, e.g.
+    /// "{albumartist,artist}/{date|year} - {album}{discnumber: (Disc {discnumber})}/{tracknumber:02}. {title}.flac".
+    /// Placeholders are tag names; "{a,b,"text"}" falls back to tag b
+    /// or literal text, "{tag|filter}" applies a filter (year,
+    /// number, upper, lower, first, trim), "{tag:02}" zero-pads, and
+    /// "{tag:text}" only includes text if the tag is set.
+    #[arg(long, value_parser = PathTemplate::from_str)]
+    template: Option<PathTemplate>,
+
+    /// How to keep the discs of a multi-disc release apart: in a
+    /// "Disc N" subdirectory of the album, or with an "N-" prefix on
+    /// each track's filename.
+    #[arg(long, value_enum, default_value_t = Discs::Subdirectory)]
+    disc_layout: Discs,
+
+    /// What to do if a track's output file already exists.
+    #[arg(long, value_enum, default_value_t = Conflict::Fail)]
+    on_conflict: Conflict,
+
     /// Number of 0-byte padding to add to the end of the metadata
     /// block. More padding allows larger additions to metadata
     /// without having to rewrite the whole file.
     #[arg(long, default_value = "2kB")]
     metadata_padding: ByteSize,
+
+    /// Cut tracks at the exact sample where the CUE sheet says they
+    /// start, instead of at the next FLAC frame boundary. Only the
+    /// frames straddling a track boundary get re-encoded.
+    #[arg(long)]
+    sample_accurate: bool,
+
+    /// Compute the MD5 signature of each track's audio and store it
+    /// in the track's STREAMINFO block. This decodes all the audio,
+    /// so it makes splitting slower.
+    #[arg(long)]
+    md5: bool,
+
+    /// How far apart the points of each track's SEEKTABLE should be
+    /// (e.g. "10s"); "0s" writes no SEEKTABLE.
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    seektable_interval: Duration,
+
+    /// Read the track layout from this .cue file instead of the
+    /// image's embedded CUE sheet. Only valid with a single input
+    /// file; without it, images that have no embedded CUE sheet use
+    /// a .cue file of the same name next to them.
+    #[arg(long)]
+    cue: Option<PathBuf>,
+
+    /// The encoding of .cue files that aren't UTF-8 (e.g.
+    /// "windows-1252" or "shift_jis"). Guessed if not given.
+    #[arg(long, value_parser = parse_encoding)]
+    cue_encoding: Option<&'static Encoding>,
+
+    /// Where to put the pregap audio between a track's INDEX 00 and
+    /// INDEX 01. Unless prepended, hidden audio before the first
+    /// track becomes its own track 0.
+    #[arg(long, value_enum, default_value_t = Pregap::Append)]
+    pregap: Pregap,
+
+    /// Split at these comma-separated times (e.g. "3:21.4,7:02")
+    /// instead of at the tracks of a CUE sheet. Only valid with a
+    /// single input file.
+    #[arg(long, value_parser = SplitPoints::parse_list, conflicts_with_all = ["split_at_file", "range", "cue"])]
+    split_at: Option<SplitPoints>,
+
+    /// Like --split-at, but read the times from a file with one time
+    /// per line.
+    #[arg(long, conflicts_with_all = ["range", "cue"])]
+    split_at_file: Option<PathBuf>,
+
+    /// Extract only the audio between two times (e.g. "1:00..2:30",
+    /// or "1:00.." for everything from 1:00 on) as a single track.
+    /// Only valid with a single input file.
+    #[arg(long, value_parser = SplitPoints::parse_range, conflicts_with = "cue")]
+    range: Option<SplitPoints>,
+
+    /// Set a tag on the resulting tracks, replacing the disc image's
+    /// (e.g. "ALBUM=Live at the Roxy", or "TITLE[2]=Intro" for a
+    /// single track). Can be given multiple times.
+    #[arg(long = "tag", value_parser = tag_file::parse_tag, value_name = "KEY=VALUE")]
+    tags: Vec<Tag>,
+
+    /// Read tags to set on the resulting tracks from a file with one
+    /// KEY=VALUE tag per line. Tags given with --tag take precedence.
+    #[arg(long)]
+    tags_file: Option<PathBuf>,
+
+    /// Split at the silent gaps in the audio instead of at the tracks
+    /// of a CUE sheet. This decodes the whole disc image first.
+    #[arg(long, conflicts_with_all = ["split_at", "split_at_file", "range", "cue"])]
+    detect_silence: bool,
+
+    /// With --detect-silence: How quiet audio has to be to count as
+    /// silence, in dB relative to full scale.
+    #[arg(long, default_value = "-60dB", value_parser = parse_decibels, allow_hyphen_values = true)]
+    silence_threshold: f64,
+
+    /// With --detect-silence: How long a silent gap between two
+    /// tracks has to be.
+    #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+    min_silence: Duration,
+
+    /// Don't split anything; print each track's output pathname,
+    /// start and end, tags and pictures instead. With
+    /// --detect-silence, print the track layout it found as a CUE
+    /// sheet.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Don't split anything; update the tags and pictures of the
+    /// tracks split from these files earlier (with the same options)
+    /// instead, keeping their audio. Files only get rewritten if the
+    /// new metadata doesn't fit into their padding.
+    #[arg(long, conflicts_with_all = ["dry_run", "sync"])]
+    retag: bool,
+
+    /// Print a report of what was written to standard output once
+    /// all files are split: each track's pathname, sample range,
+    /// frame count, size, tags and warnings.
+    #[arg(long, value_enum)]
+    report: Option<ReportFormat>,
+
+    /// Keep splitting (or verifying, or retagging) the remaining files
+    /// when one of them fails, and print a summary of all files at the
+    /// end. Exits with an error status if any file failed.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Remember which files were split into the output directory
+    /// (in a manifest there), and skip the ones that haven't changed
+    /// since. Files whose tags or audio changed get split again,
+    /// replacing their old tracks.
+    #[arg(long)]
+    sync: bool,
+
+    /// With --sync, remove the tracks of files that no longer exist.
+    #[arg(long, requires = "sync")]
+    prune: bool,
+}
+
+fn parse_decibels(value: &str) -> anyhow::Result<f64> {
+    let number = value.trim().trim_end_matches("dB").trim_end_matches("db");
+    number
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid loudness {:?}, should look like -60dB", value))
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    /// A JSON array with one object per disc image.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Pregap {
+    /// Append the pregap to the previous track.
+    Append,
+    /// Prepend the pregap to the track it belongs to.
+    Prepend,
+    /// Drop pregap audio.
+    Discard,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Discs {
+    /// Album/Disc 2/01.Title.flac
+    Subdirectory,
+    /// Album/2-01.Title.flac
+    Prefix,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Conflict {
+    /// Leave the existing file alone and don't write the track.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Write the track as "01.Title (1).flac" instead.
+    Rename,
+    /// Stop with an error.
+    Fail,
+}
+
+impl From<Conflict> for ConflictPolicy {
+    fn from(conflict: Conflict) -> Self {
+        match conflict {
+            Conflict::Skip => ConflictPolicy::Skip,
+            Conflict::Overwrite => ConflictPolicy::Overwrite,
+            Conflict::Rename => ConflictPolicy::Rename,
+            Conflict::Fail => ConflictPolicy::Fail,
+        }
+    }
+}
+
+impl From<Discs> for DiscLayout {
+    fn from(discs: Discs) -> Self {
+        match discs {
+            Discs::Subdirectory => DiscLayout::Subdirectory,
+            Discs::Prefix => DiscLayout::Prefix,
+        }
+    }
+}
+
+impl From<Pregap> for PregapMode {
+    fn from(pregap: Pregap) -> Self {
+        match pregap {
+            Pregap::Append => PregapMode::Append,
+            Pregap::Prepend => PregapMode::Prepend,
+            Pregap::Discard => PregapMode::Discard,
+        }
+    }
+}
+
+/// Prints what splitting a disc image would do, for --dry-run.
+fn print_plan(input_path: &Path, tracks: &[PlannedTrack]) {
+    println!("{}:", input_path.display());
+    for PlannedTrack { path, track } in tracks {
+        let exists = if path.exists() { " (exists)" } else { "" };
+        println!("  {}{}", path.display(), exists);
+        let rate = u128::from(track.sample_rate());
+        let at = |ts: u64| {
+            format_timestamp(Duration::from_nanos(
+                (u128::from(ts) * 1_000_000_000 / rate) as u64,
+            ))
+        };
+        println!(
+            "    {} - {} ({})",
+            at(track.start_ts),
+            at(track.end_ts),
+            format_timestamp(track.duration())
+        );
+        for tag in &track.tags {
+            println!("    {}={}", tag.key, tag.value);
+        }
+        for visual in &track.visuals {
+            let usage = visual
+                .usage
+                .map_or_else(|| "picture".to_string(), |usage| format!("{:?}", usage));
+            println!(
+                "    {}: {}, {} bytes",
+                usage,
+                visual.media_type,
+                visual.data.len()
+            );
+        }
+    }
+}
+
+/// Formats a time like the split points given on the command line,
+/// e.g. `3:21.400` or `1:02:03.000`.
+fn format_timestamp(time: Duration) -> String {
+    let seconds = time.as_secs();
+    let millis = time.subsec_millis();
+    match seconds / 3600 {
+        0 => format!("{}:{:02}.{:03}", seconds / 60, seconds % 60, millis),
+        hours => format!(
+            "{}:{:02}:{:02}.{:03}",
+            hours,
+            seconds / 60 % 60,
+            seconds % 60,
+            millis
+        ),
+    }
+}
+
+fn parse_encoding(label: &str) -> anyhow::Result<&'static Encoding> {
+    Encoding::for_label(label.as_bytes()).with_context(|| format!("unknown encoding {:?}", label))
 }
 
 fn main() -> anyhow::Result<()> {
@@ -46,26 +470,565 @@ fn main() -> anyhow::Result<()> {
         .with(indicatif_layer)
         .init();
 
-    let args = Args::parse();
-    let base_path = args.output_dir.as_path();
+    let cli = Cli::parse();
+    let (args, verify) = match cli.command {
+        Some(Command::Verify(args)) => (args, true),
+        Some(Command::Join(args)) => return join(args),
+        #[cfg(feature = "mount")]
+        Some(Command::Mount(args)) => return mount(args),
+        None => (cli.args, false),
+    };
+    if verify && (args.dry_run || args.retag || args.sync) {
+        bail!("verify can't be combined with --dry-run, --retag or --sync");
+    }
+    let filter = InputFilter::new(&args.include, &args.exclude)?;
+    let mut inputs = vec![];
+    for path in &args.paths {
+        if path.is_dir() {
+            for image in find_disc_images(path, &filter)? {
+                let relative = image.strip_prefix(path).unwrap_or(&image).to_path_buf();
+                inputs.push((image, relative));
+            }
+        } else {
+            let relative = PathBuf::from(path.file_name().unwrap_or_default());
+            inputs.push((path.clone(), relative));
+        }
+    }
+    // Where each image's tracks go:
+    let inputs: Vec<(PathBuf, PathBuf)> = inputs
+        .into_iter()
+        .map(|(image, relative)| {
+            let base_path = if args.mirror_input {
+                args.output_dir.join(relative.with_extension(""))
+            } else {
+                args.output_dir.clone()
+            };
+            (image, base_path)
+        })
+        .collect();
+    let paths: Vec<PathBuf> = inputs.iter().map(|(path, _)| path.clone()).collect();
     let metadata_padding: u32 = args
         .metadata_padding
         .as_u64()
         .try_into()
         .context("--metadata-padding should fit into a 32-bit unsigned int")?;
-    if let Err(err) = args
-        .paths
+    if args.cue.is_some() && paths.len() != 1 {
+        bail!("--cue can only be used when splitting a single file");
+    }
+    let split_points = match (args.split_at, args.split_at_file, args.range) {
+        (Some(points), _, _) | (_, _, Some(points)) => Some(points),
+        (_, Some(path), _) => Some(SplitPoints::read(path)?),
+        (None, None, None) => None,
+    };
+    if split_points.is_some() && paths.len() != 1 {
+        bail!(
+            "--split-at, --split-at-file and --range can only be used when splitting a single file"
+        );
+    }
+    let mut tags = match &args.tags_file {
+        Some(path) => tag_file::read_tags_file(path)?,
+        None => vec![],
+    };
+    tags.retain(|tag| !args.tags.iter().any(|given| given.key == tag.key));
+    tags.extend(args.tags);
+    let options = SplitOptions {
+        metadata_padding,
+        sample_accurate: args.sample_accurate,
+        compute_md5: args.md5,
+        seekpoint_interval: Some(args.seektable_interval).filter(|interval| !interval.is_zero()),
+        cue_sheet: args.cue,
+        cue_encoding: args.cue_encoding,
+        pregap: args.pregap.into(),
+        split_points,
+        detect_silence: args.detect_silence.then_some(SilenceOptions {
+            threshold_db: args.silence_threshold,
+            min_duration: args.min_silence,
+        }),
+        path_template: args.template,
+        disc_layout: args.disc_layout.into(),
+        tags,
+        on_conflict: args.on_conflict.into(),
+        flat_output: args.mirror_input,
+        ..Default::default()
+    };
+    let disc_tags = synthesize_disc_tags(&paths);
+    let with_disc_tags = |disc_tags: Vec<Tag>| {
+        let mut options = options.clone();
+        for tag in disc_tags {
+            if !options.tags.iter().any(|given| given.key == tag.key) {
+                options.tags.push(tag);
+            }
+        }
+        options
+    };
+    // With --sync, the manifest and the state of each image that gets
+    // split (by its pathname on the command line):
+    let mut sync = None;
+    let mut inputs: Vec<_> = inputs.into_iter().zip(disc_tags).collect();
+    if args.sync {
+        let mut manifest = Manifest::load(&args.output_dir)?;
+        if args.prune && !args.dry_run {
+            manifest.prune(&args.output_dir)?;
+        }
+        let mut states = HashMap::new();
+        let mut pending = vec![];
+        for ((image, base_path), disc_tags) in inputs {
+            let options = with_disc_tags(disc_tags.clone());
+            let state = fs::canonicalize(&image)
+                .with_context(|| format!("resolving {:?}", image))
+                .and_then(|key| Ok((key, ImageState::read(&image, &base_path, &options)?)));
+            match state {
+                Ok((key, state)) => {
+                    match manifest.freshness(&key, &state, &args.output_dir) {
+                        Freshness::UpToDate => {
+                            info!(?image, "up to date, skipping");
+                            continue;
+                        }
+                        Freshness::New => {}
+                        Freshness::MetadataChanged if !args.dry_run => {
+                            let outputs = manifest.outputs(&key, &args.output_dir);
+                            match retag_in_place(&image, &base_path, &options, &outputs) {
+                                Ok(Some(retagged)) => {
+                                    info!(?image, "metadata changed, retagged its tracks");
+                                    manifest.record(key, state, &retagged, &args.output_dir)?;
+                                    continue;
+                                }
+                                Ok(None) => info!(
+                                    ?image,
+                                    "metadata changed the track names, splitting again"
+                                ),
+                                Err(error) => warn!(
+                                    ?image,
+                                    error = format!("{:#}", error),
+                                    "can't retag, splitting again"
+                                ),
+                            }
+                        }
+                        // The earlier tracks go once the image has been
+                        // split again, see Manifest::record:
+                        freshness => info!(?image, ?freshness, "splitting again"),
+                    }
+                    states.insert(image.clone(), (key, state));
+                }
+                // Splitting will most likely fail with a better error:
+                Err(error) => warn!(?image, error = format!("{:#}", error), "can't sync"),
+            }
+            pending.push(((image, base_path), disc_tags));
+        }
+        inputs = pending;
+        sync = Some((Mutex::new(manifest), states));
+    }
+    let save_manifest = || -> anyhow::Result<()> {
+        if let Some((manifest, _)) = &sync {
+            let manifest = manifest.lock().expect("manifest lock poisoned");
+            manifest.save(&args.output_dir)?;
+        }
+        Ok(())
+    };
+    let (inputs, disc_tags): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    let paths: Vec<PathBuf> = inputs.iter().map(|(path, _)| path.clone()).collect();
+    if args.dry_run {
+        if let Some(silence) = &options.detect_silence {
+            for path in &paths {
+                let detected = silence::detect_silence(path, silence)
+                    .with_context(|| format!("detecting silence in {:?}", path))?;
+                let file = path.file_name().unwrap_or_default().to_string_lossy();
+                print!("{}", detected.cue_sheet(&file));
+            }
+            return Ok(());
+        }
+    }
+    if args.dry_run {
+        for ((path, base_path), disc_tags) in inputs.iter().zip(disc_tags) {
+            let tracks = plan_one_file(path, base_path, &with_disc_tags(disc_tags))
+                .with_context(|| format!("planning {:?}", path))?;
+            print_plan(path, &tracks);
+        }
+        return Ok(());
+    }
+    let inputs: Vec<_> = inputs
+        .into_iter()
+        .zip(disc_tags)
+        .map(|((path, base_path), disc_tags)| (path, (base_path, disc_tags)))
+        .collect();
+    if verify {
+        let (verifications, failed) = for_each_input(
+            inputs,
+            args.keep_going,
+            |path, (base_path, disc_tags)| {
+                catch_panic(|| verify_one_file(path, &base_path, &with_disc_tags(disc_tags)))
+                    .with_context(|| format!("verifying {:?}", path))
+            },
+            |verification| match verification.is_ok() {
+                true => "ok".to_string(),
+                false => "doesn't match its tracks".to_string(),
+            },
+        )?;
+        for verification in &verifications {
+            print_verification(verification);
+        }
+        let mismatched = verifications
+            .iter()
+            .filter(|verification| !verification.is_ok())
+            .count();
+        if failed + mismatched > 0 {
+            bail!(
+                "{} of {} files failed verification",
+                failed + mismatched,
+                failed + verifications.len()
+            );
+        }
+        return Ok(());
+    }
+    if args.retag {
+        let (retagged, failed) = for_each_input(
+            inputs,
+            args.keep_going,
+            |path, (base_path, disc_tags)| {
+                catch_panic(|| retag_one_file(path, &base_path, &with_disc_tags(disc_tags)))
+                    .with_context(|| format!("retagging {:?}", path))
+            },
+            |retagged| format!("ok, {} tracks", retagged.len()),
+        )?;
+        if failed > 0 {
+            bail!(
+                "{} of {} files failed to retag",
+                failed,
+                failed + retagged.len()
+            );
+        }
+        return Ok(());
+    }
+    let split = |path: &Path, base_path: &Path, disc_tags: Vec<Tag>| {
+        let mut options = with_disc_tags(disc_tags);
+        let synced = sync
+            .as_ref()
+            .and_then(|(manifest, states)| Some((manifest, states.get(path)?)));
+        if let Some((manifest, (key, _))) = synced {
+            // Splitting again replaces the image's earlier tracks:
+            options.replaceable = manifest
+                .lock()
+                .expect("manifest lock poisoned")
+                .outputs(key, &args.output_dir);
+        }
+        let report = catch_panic(|| split_one_file(path, base_path, &options))
+            .with_context(|| format!("splitting {:?}", path))?;
+        if let Some((manifest, (key, state))) = synced {
+            manifest
+                .lock()
+                .expect("manifest lock poisoned")
+                .record(
+                    key.clone(),
+                    state.clone(),
+                    report.written_paths(),
+                    &args.output_dir,
+                )
+                .with_context(|| format!("removing the earlier tracks of {:?}", path))?;
+        }
+        Ok(report)
+    };
+    let result = for_each_input(
+        inputs,
+        args.keep_going,
+        |path, (base_path, disc_tags)| split(path, &base_path, disc_tags),
+        |report| format!("ok, {} tracks", report.written_paths().count()),
+    );
+    let (reports, failed) = match result {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            save_manifest()?;
+            return Err(err);
+        }
+    };
+    if failed > 0 {
+        save_manifest()?;
+        write_report(args.report, &reports)?;
+        bail!(
+            "{} of {} files failed to split",
+            failed,
+            failed + reports.len()
+        );
+    }
+    save_manifest()?;
+    write_report(args.report, &reports)
+}
+
+/// Prints the report of a split to standard output, if one was
+/// requested.
+fn write_report(format: Option<ReportFormat>, reports: &[SplitReport]) -> anyhow::Result<()> {
+    match format {
+        Some(ReportFormat::Json) => {
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, reports).context("writing report")?;
+            writeln!(stdout).context("writing report")?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Joins tracks into a disc image, for the join subcommand.
+fn join(args: JoinArgs) -> anyhow::Result<()> {
+    let paths = match &args.paths[..] {
+        [dir] if dir.is_dir() => find_tracks(dir)?,
+        paths => paths.to_vec(),
+    };
+    let options = SplitOptions {
+        metadata_padding: args
+            .metadata_padding
+            .as_u64()
+            .try_into()
+            .context("--metadata-padding should fit into a 32-bit unsigned int")?,
+        seekpoint_interval: Some(args.seektable_interval).filter(|interval| !interval.is_zero()),
+        on_conflict: args.on_conflict.into(),
+        ..Default::default()
+    };
+    let (joined, failed) = for_each_input(
+        vec![(args.output.clone(), paths)],
+        true,
+        |output, paths| {
+            let written = catch_panic(|| join_tracks(&paths, output, &options))
+                .with_context(|| format!("joining into {:?}", output))?;
+            if let Some(written) = &written {
+                info!(?written, tracks = paths.len(), "joined");
+            }
+            Ok(written)
+        },
+        |written| match written {
+            Some(written) => format!("ok, written to {}", written.display()),
+            None => "skipped, it already exists".to_string(),
+        },
+    )?;
+    if failed > 0 {
+        bail!(
+            "{} of {} images failed to join",
+            failed,
+            failed + joined.len()
+        );
+    }
+    Ok(())
+}
+
+/// Serves a filesystem of an archive's tracks, for the mount
+/// subcommand.
+#[cfg(feature = "mount")]
+fn mount(args: MountArgs) -> anyhow::Result<()> {
+    let options = SplitOptions {
+        metadata_padding: args
+            .metadata_padding
+            .as_u64()
+            .try_into()
+            .context("--metadata-padding should fit into a 32-bit unsigned int")?,
+        seekpoint_interval: Some(args.seektable_interval).filter(|interval| !interval.is_zero()),
+        path_template: args.template,
+        disc_layout: args.disc_layout.into(),
+        pregap: args.pregap.into(),
+        ..Default::default()
+    };
+    let filter = InputFilter::new(&args.include, &args.exclude)?;
+    let mut fs = flac_tracksplit::mount::Filesystem::new(&args.archive, &filter, &options)
+        .with_context(|| format!("reading {:?}", args.archive))?;
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")));
+    let size_cache = args
+        .size_cache
+        .or_else(|| cache_dir.map(|dir| dir.join("flac-tracksplit").join("track-sizes.json")));
+    if let Some(size_cache) = size_cache {
+        fs = fs.with_size_cache(size_cache);
+    }
+    flac_tracksplit::mount::mount(fs, &args.mountpoint, args.allow_other)
+}
+
+/// Prints whether a file's tracks hold its audio, and what's wrong
+/// with them otherwise.
+fn print_verification(verification: &Verification) {
+    if verification.is_ok() {
+        println!(
+            "{}: ok, {} tracks",
+            verification.input.display(),
+            verification.tracks.len()
+        );
+        return;
+    }
+    println!("{}: FAILED", verification.input.display());
+    for track in &verification.tracks {
+        for problem in &track.problems {
+            println!("  {}: {}", track.path.display(), problem);
+        }
+    }
+    for (start, end) in &verification.uncovered {
+        println!("  samples {}..{} are in no track", start, end);
+    }
+}
+
+/// Retags the tracks that were split from a disc image earlier (the
+/// `outputs` a sync manifest remembers), if its tracks would still be
+/// written to the same files. Returns the retagged files, or `None` if
+/// the image needs splitting again.
+fn retag_in_place(
+    image: &Path,
+    base_path: &Path,
+    options: &SplitOptions,
+    outputs: &[PathBuf],
+) -> anyhow::Result<Option<Vec<PathBuf>>> {
+    let mut planned: Vec<PathBuf> = plan_one_file(image, base_path, options)?
+        .into_iter()
+        .map(|PlannedTrack { path, .. }| path)
+        .collect();
+    let mut outputs = outputs.to_vec();
+    planned.sort();
+    outputs.sort();
+    if planned != outputs {
+        return Ok(None);
+    }
+    retag_one_file(image, base_path, options).map(Some)
+}
+
+/// Runs `f` on each input (splitting, verifying or retagging it).
+/// With `keep_going`, carries on after failures and prints a summary
+/// of all inputs (see [split_keeping_going]); otherwise stops at the
+/// first failure and returns it. Returns the results of the inputs
+/// that succeeded, and how many failed.
+fn for_each_input<T: Send, R: Send>(
+    inputs: Vec<(PathBuf, T)>,
+    keep_going: bool,
+    f: impl Fn(&Path, T) -> anyhow::Result<R> + Sync,
+    describe: impl Fn(&R) -> String,
+) -> anyhow::Result<(Vec<R>, usize)> {
+    if !keep_going {
+        let results = inputs
+            .into_par_iter()
+            .map(|(path, input)| f(&path, input))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .inspect_err(|err| error!(error = %err))?;
+        return Ok((results, 0));
+    }
+    let results = split_keeping_going(inputs, f);
+    print_summary(&results, describe);
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    let succeeded = results
+        .into_iter()
+        .filter_map(|(_, result)| result.ok())
+        .collect();
+    Ok((succeeded, failed))
+}
+
+/// Runs `split` on each input, carrying on after failures, for
+/// --keep-going. Returns how each input went.
+fn split_keeping_going<T: Send, R: Send>(
+    inputs: Vec<(PathBuf, T)>,
+    split: impl Fn(&Path, T) -> anyhow::Result<R> + Sync,
+) -> Vec<(PathBuf, anyhow::Result<R>)> {
+    inputs
         .into_par_iter()
-        .panic_fuse()
-        .try_for_each(|path| {
-            split_one_file(&path, base_path, metadata_padding)
-                .map(|_| ())
-                .with_context(|| format!("splitting {:?}", path))
+        .map(|(path, input)| {
+            let result = split(&path, input);
+            if let Err(err) = &result {
+                error!(error = format!("{:#}", err));
+            }
+            (path, result)
         })
-    {
-        error!(error = %err);
-        Err(err)
+        .collect()
+}
+
+/// Runs `f`, turning a panic into an error, so that one disc image
+/// that trips a bug doesn't take the others down with it.
+fn catch_panic<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|panic| Err(anyhow!("panicked: {}", panic_message(&*panic))))
+}
+
+/// Returns the message a panic was started with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
     } else {
-        Ok(())
+        "(no message)"
+    }
+}
+
+/// Prints a table of how each file went, for --keep-going, with
+/// `describe` saying what happened to the files that succeeded.
+fn print_summary<R>(results: &[(PathBuf, anyhow::Result<R>)], describe: impl Fn(&R) -> String) {
+    let width = results
+        .iter()
+        .map(|(path, _)| path.display().to_string().chars().count())
+        .max()
+        .unwrap_or(0);
+    eprintln!();
+    for (path, result) in results {
+        let outcome = match result {
+            Ok(result) => describe(result),
+            Err(err) => format!("FAILED: {:#}", err),
+        };
+        eprintln!(
+            "{:width$}  {}",
+            path.display().to_string(),
+            outcome,
+            width = width
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keep_going_after_panic() {
+        let inputs = ["a.flac", "bad.flac", "c.flac"]
+            .into_iter()
+            .map(|name| (PathBuf::from(name), ()))
+            .collect();
+        let results = split_keeping_going(inputs, |path, ()| {
+            catch_panic(|| {
+                if path == Path::new("bad.flac") {
+                    panic!("bug in {:?}", path);
+                }
+                Ok(SplitReport {
+                    input: path.to_path_buf(),
+                    ..Default::default()
+                })
+            })
+            .with_context(|| format!("splitting {:?}", path))
+        });
+
+        assert_eq!(results.len(), 3);
+        for (path, result) in &results {
+            match result {
+                Ok(report) => assert_eq!(&report.input, path),
+                Err(err) => {
+                    assert_eq!(path, Path::new("bad.flac"));
+                    assert_eq!(
+                        format!("{:#}", err),
+                        "splitting \"bad.flac\": panicked: bug in \"bad.flac\""
+                    );
+                }
+            }
+        }
+        assert_eq!(results.iter().filter(|(_, r)| r.is_err()).count(), 1);
+    }
+
+    #[test]
+    fn test_for_each_input() {
+        let inputs = || {
+            ["a.flac", "bad.flac", "c.flac"]
+                .into_iter()
+                .map(|name| (PathBuf::from(name), name.len()))
+                .collect()
+        };
+        let f = |path: &Path, len: usize| match path == Path::new("bad.flac") {
+            true => Err(anyhow!("can't read {:?}", path)),
+            false => Ok(len),
+        };
+
+        let err = for_each_input(inputs(), false, f, usize::to_string).unwrap_err();
+        assert_eq!(err.to_string(), "can't read \"bad.flac\"");
+        let (succeeded, failed) = for_each_input(inputs(), true, f, usize::to_string).unwrap();
+        assert_eq!(succeeded, vec![6, 6]);
+        assert_eq!(failed, 1);
     }
 }
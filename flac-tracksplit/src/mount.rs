@@ -0,0 +1,888 @@
+//! A read-only FUSE filesystem that presents an archive of disc images
+//! as the per-track FLAC files that [crate::split_one_file] would
+//! split them into, laid out the same way, without writing anything:
+//! each track file is put together on the fly from its disc image
+//! (see [TrackFile]), whose frames get found the first time one of its
+//! tracks is looked at.
+//!
+//! The filesystem is served with [fuser], on as many threads as there
+//! are cores; a disc image that's being indexed only holds up the
+//! requests for its own tracks. A disc image that changes while
+//! mounted is noticed the next time one of its tracks is looked at.
+//!
+//! Finding the size of a track file means finding the frames of its
+//! disc image, which reads all of it. So that listing an archive
+//! doesn't have to do that for every disc image each time it's
+//! mounted, the sizes can be remembered in a cache file (see
+//! [Filesystem::with_size_cache]).
+
+use crate::{
+    indexed::{IndexedImage, TrackFile},
+    inputs::{find_disc_images, InputFilter},
+    plan_one_file, seek_point, seekpoint_spacing, AudioSummary, SplitOptions, Track,
+};
+use anyhow::{bail, Context};
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, FopenFlags, Generation, INodeNo, LockOwner,
+    MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyXattr, Request, Session, SessionACL,
+};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    fs, io,
+    io::Write,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info, warn};
+
+/// How many disc images to keep open, with their frames found, at a
+/// time.
+const CACHED_IMAGES: usize = 16;
+
+/// How long the kernel may cache names and attributes, i.e. how long
+/// it may take to notice that a disc image changed.
+const TTL: Duration = Duration::from_secs(1);
+
+/// The inode number of the filesystem's root directory.
+const ROOT_INODE: u64 = 1;
+
+/// A file or directory in the filesystem, by inode number minus one.
+#[derive(Debug)]
+enum Node {
+    Dir {
+        parent: u64,
+        children: BTreeMap<OsString, u64>,
+    },
+    Track {
+        image: usize,
+        track: usize,
+    },
+}
+
+/// What tells whether a disc image file changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileState {
+    size: u64,
+    modified: SystemTime,
+}
+
+impl FileState {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let metadata = fs::metadata(path).with_context(|| format!("reading {:?}", path))?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().context("file modification time")?,
+        })
+    }
+}
+
+/// A disc image whose tracks are in the filesystem.
+struct Image {
+    path: PathBuf,
+    state: FileState,
+    /// The pathnames of the image's tracks in the filesystem.
+    paths: Vec<PathBuf>,
+    tracks: Vec<Track>,
+    /// What the track files are made of, besides the image's audio
+    /// (see [layout_digest]).
+    layout: String,
+    /// The sizes of the track files, as far as they are known.
+    sizes: Vec<Option<u64>>,
+    /// Where the image's frames are, once a track was looked at.
+    index: Option<ImageIndex>,
+    /// How often the image changed while mounted, and as of which of
+    /// those changes each track file was last opened.
+    generation: u64,
+    opened: Vec<u64>,
+}
+
+/// An open disc image, which frames go into each of its tracks, and
+/// the track files made from them so far.
+struct ImageIndex {
+    image: Arc<IndexedImage>,
+    frames: Vec<Range<usize>>,
+    files: Vec<Option<Arc<TrackFile>>>,
+}
+
+impl Image {
+    /// Checks whether the image file changed, and if it did, forgets
+    /// its frames and lays out its tracks again. Returns whether it
+    /// changed, and an error if its tracks would have different
+    /// pathnames now, which needs a remount to show.
+    fn refresh(&mut self, options: &SplitOptions) -> anyhow::Result<bool> {
+        let state = FileState::read(&self.path)?;
+        if state == self.state {
+            return Ok(false);
+        }
+        info!(path = ?self.path, "disc image changed");
+        self.index = None;
+        let planned = plan_one_file(&self.path, "", options)?;
+        if !planned.iter().map(|planned| &planned.path).eq(&self.paths) {
+            bail!("the image's tracks would be named differently now; remount to see them");
+        }
+        self.tracks = planned.into_iter().map(|planned| planned.track).collect();
+        self.layout = layout_digest(&self.tracks, options)?;
+        self.sizes = vec![None; self.tracks.len()];
+        self.state = state;
+        self.generation += 1;
+        Ok(true)
+    }
+
+    /// Returns the file of a track and the disc image to read it from,
+    /// finding the frames of the image first if that wasn't done yet.
+    fn track_file(
+        &mut self,
+        track: usize,
+        options: &SplitOptions,
+    ) -> anyhow::Result<(Arc<IndexedImage>, Arc<TrackFile>)> {
+        let index = match &mut self.index {
+            Some(index) => index,
+            None => {
+                let image = IndexedImage::open(&self.path)?;
+                let frames = image.assign_frames(&self.tracks)?;
+                debug!(path = ?self.path, "indexed disc image");
+                self.index.insert(ImageIndex {
+                    image: Arc::new(image),
+                    files: vec![None; frames.len()],
+                    frames,
+                })
+            }
+        };
+        let file = match &index.files[track] {
+            Some(file) => file.clone(),
+            None => {
+                let file = Arc::new(index.image.track_file(
+                    &self.tracks[track],
+                    index.frames[track].clone(),
+                    options,
+                )?);
+                index.files[track] = Some(file.clone());
+                self.sizes[track] = Some(file.len());
+                file
+            }
+        };
+        Ok((index.image.clone(), file))
+    }
+}
+
+/// A digest of what goes into the track files of a disc image besides
+/// its audio: the tracks' spans, tags and pictures, and the options
+/// that shape their metadata.
+fn layout_digest(tracks: &[Track], options: &SplitOptions) -> anyhow::Result<String> {
+    let mut md5 = Md5::new();
+    for track in tracks {
+        let spacing = seekpoint_spacing(options, track.sample_rate());
+        let audio = AudioSummary {
+            total_samples: track.end_ts - track.start_ts,
+            seek_points: vec![seek_point(u64::MAX, 0, 0); track.reserved_seek_points(spacing)?],
+            ..Default::default()
+        };
+        let mut metadata = vec![];
+        track.write_metadata(&audio, options.metadata_padding, &mut metadata)?;
+        md5.update(format!("{:?}", track));
+        md5.update(&metadata);
+    }
+    Ok(md5
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// The track file sizes remembered between mounts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SizeCache {
+    /// The disc images, by their absolute pathname.
+    images: BTreeMap<PathBuf, CachedSizes>,
+}
+
+/// The track file sizes of a disc image, and what they depend on.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedSizes {
+    state: FileState,
+    layout: String,
+    sizes: Vec<Option<u64>>,
+}
+
+impl SizeCache {
+    /// Loads the cache file at `path`; a missing or unreadable one
+    /// makes an empty cache.
+    fn load(path: &Path) -> Self {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(error) => {
+                warn!(?path, %error, "can't read track size cache");
+                return Self::default();
+            }
+        };
+        serde_json::from_slice(&contents).unwrap_or_else(|error| {
+            warn!(?path, %error, "can't parse track size cache");
+            Self::default()
+        })
+    }
+
+    /// Saves the cache file at `path`, replacing the previous one at
+    /// once.
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir))?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .context("creating temporary track size cache file")?;
+        serde_json::to_writer(&mut file, self).context("writing track size cache")?;
+        writeln!(file).context("writing track size cache")?;
+        file.persist(path)
+            .context("moving track size cache into place")?;
+        Ok(())
+    }
+}
+
+/// The tracks of an archive of disc images, as a tree of directories
+/// and track files.
+pub struct Filesystem {
+    options: SplitOptions,
+    nodes: Vec<Node>,
+    images: Vec<Mutex<Image>>,
+    /// The images that have an index, least recently used first.
+    indexed: Mutex<VecDeque<usize>>,
+    /// Where to remember the sizes of track files.
+    size_cache: Option<PathBuf>,
+    uid: u32,
+    gid: u32,
+}
+
+impl Filesystem {
+    /// Finds the disc images under `archive` that `filter` picks up,
+    /// and lays out their tracks the way [crate::split_one_file] would
+    /// under an output directory. Images that can't be split are left
+    /// out, as are tracks whose pathname another track took already.
+    pub fn new<P: AsRef<Path> + Debug>(
+        archive: P,
+        filter: &InputFilter,
+        options: &SplitOptions,
+    ) -> anyhow::Result<Self> {
+        if options.sample_accurate || options.compute_md5 {
+            bail!("tracks can't be sample accurate or have MD5 signatures when mounted");
+        }
+        let mut fs = Self {
+            options: options.clone(),
+            nodes: vec![Node::Dir {
+                parent: ROOT_INODE,
+                children: BTreeMap::new(),
+            }],
+            images: vec![],
+            indexed: Mutex::new(VecDeque::new()),
+            size_cache: None,
+            // SAFETY: These can't fail.
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        for path in find_disc_images(&archive, filter)? {
+            let planned = match plan_one_file(&path, "", options) {
+                Ok(planned) => planned,
+                Err(error) => {
+                    warn!(?path, error = format!("{:#}", error), "skipping");
+                    continue;
+                }
+            };
+            let path = std::path::absolute(&path).with_context(|| format!("reading {:?}", path))?;
+            let state = FileState::read(&path)?;
+            let image = fs.images.len();
+            let mut paths = vec![];
+            let mut tracks = vec![];
+            for planned in planned {
+                let track = tracks.len();
+                if !fs.add_track(&planned.path, Node::Track { image, track }) {
+                    warn!(?path, track = ?planned.path, "another track has this pathname, leaving it out");
+                }
+                paths.push(planned.path);
+                tracks.push(planned.track);
+            }
+            fs.images.push(Mutex::new(Image {
+                layout: layout_digest(&tracks, options)?,
+                sizes: vec![None; tracks.len()],
+                opened: vec![0; tracks.len()],
+                generation: 0,
+                path,
+                state,
+                paths,
+                tracks,
+                index: None,
+            }));
+        }
+        info!(
+            images = fs.images.len(),
+            nodes = fs.nodes.len(),
+            "laid out the archive"
+        );
+        Ok(fs)
+    }
+
+    /// Remembers the sizes of track files in the cache file at `path`
+    /// between mounts, taking the sizes remembered there for disc
+    /// images that didn't change since.
+    pub fn with_size_cache(mut self, path: PathBuf) -> Self {
+        let mut cache = SizeCache::load(&path);
+        let mut cached = 0;
+        for image in &mut self.images {
+            let image = image.get_mut().unwrap_or_else(PoisonError::into_inner);
+            if let Some(entry) = cache.images.remove(&image.path) {
+                if entry.state == image.state
+                    && entry.layout == image.layout
+                    && entry.sizes.len() == image.sizes.len()
+                {
+                    image.sizes = entry.sizes;
+                    cached += 1;
+                }
+            }
+        }
+        debug!(?path, images = cached, "loaded track size cache");
+        self.size_cache = Some(path);
+        self
+    }
+
+    /// Saves the sizes of the track files found so far into the cache
+    /// file, if there is one (see [Filesystem::with_size_cache]).
+    /// Happens when the filesystem gets unmounted.
+    pub fn save_size_cache(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.size_cache else {
+            return Ok(());
+        };
+        // Other archives can share the cache file:
+        let mut cache = SizeCache::load(path);
+        cache.images.retain(|image, _| image.exists());
+        for image in &self.images {
+            let image = image.lock().unwrap_or_else(PoisonError::into_inner);
+            if image.sizes.iter().any(Option::is_some) {
+                cache.images.insert(
+                    image.path.clone(),
+                    CachedSizes {
+                        state: image.state,
+                        layout: image.layout.clone(),
+                        sizes: image.sizes.clone(),
+                    },
+                );
+            }
+        }
+        cache
+            .save(path)
+            .with_context(|| format!("saving track sizes to {:?}", path))
+    }
+
+    /// Adds a track file at `path`, and the directories it is in.
+    /// Returns false if something else has that pathname.
+    fn add_track(&mut self, path: &Path, track: Node) -> bool {
+        let names: Vec<&OsStr> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let Some((file_name, dirs)) = names.split_last() else {
+            return false;
+        };
+        let mut dir = ROOT_INODE;
+        for name in dirs {
+            dir = match self.child(dir, name) {
+                Some(child) if matches!(self.node(child), Some(Node::Dir { .. })) => child,
+                Some(_) => return false,
+                None => self.add_node(
+                    dir,
+                    name,
+                    Node::Dir {
+                        parent: dir,
+                        children: BTreeMap::new(),
+                    },
+                ),
+            };
+        }
+        if self.child(dir, file_name).is_some() {
+            return false;
+        }
+        self.add_node(dir, file_name, track);
+        true
+    }
+
+    fn add_node(&mut self, dir: u64, name: &OsStr, node: Node) -> u64 {
+        self.nodes.push(node);
+        let inode = self.nodes.len() as u64;
+        if let Some(Node::Dir { children, .. }) = self.nodes.get_mut(dir as usize - 1) {
+            children.insert(name.to_os_string(), inode);
+        }
+        inode
+    }
+
+    fn node(&self, inode: u64) -> Option<&Node> {
+        self.nodes.get(usize::try_from(inode).ok()?.checked_sub(1)?)
+    }
+
+    /// Returns the inode number of the entry called `name` in the
+    /// directory `dir`.
+    fn child(&self, dir: u64, name: &OsStr) -> Option<u64> {
+        match self.node(dir)? {
+            Node::Dir { children, .. } => children.get(name).copied(),
+            Node::Track { .. } => None,
+        }
+    }
+
+    /// Locks a disc image, after checking whether it changed (see
+    /// [Image::refresh]). Returns whether it did.
+    fn image(&self, image: usize) -> anyhow::Result<(MutexGuard<'_, Image>, bool)> {
+        let mut locked = self.images[image]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let changed = locked.refresh(&self.options)?;
+        Ok((locked, changed))
+    }
+
+    /// Returns the file of a track and the disc image to read it from
+    /// (see [Image::track_file]), keeping only the most recently used
+    /// images indexed.
+    fn track_file(
+        &self,
+        image: usize,
+        track: usize,
+    ) -> anyhow::Result<(Arc<IndexedImage>, Arc<TrackFile>)> {
+        let file = self.image(image)?.0.track_file(track, &self.options)?;
+        let evicted: Vec<usize> = {
+            let mut indexed = self.indexed.lock().unwrap_or_else(PoisonError::into_inner);
+            indexed.retain(|indexed| *indexed != image);
+            indexed.push_back(image);
+            let excess = indexed.len().saturating_sub(CACHED_IMAGES);
+            indexed.drain(..excess).collect()
+        };
+        // Reads in progress keep their image open until they're done:
+        for evicted in evicted {
+            self.images[evicted]
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .index = None;
+        }
+        Ok(file)
+    }
+
+    /// Logs why a disc image can't be read, and returns the errno
+    /// value that tells the kernel.
+    fn read_error(&self, image: usize, error: anyhow::Error) -> Errno {
+        let path = self.images[image]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .path
+            .clone();
+        warn!(
+            ?path,
+            error = format!("{:#}", error),
+            "can't read disc image"
+        );
+        Errno::EIO
+    }
+
+    /// Returns the attributes of a file or directory.
+    fn attr(&self, inode: u64) -> Result<FileAttr, Errno> {
+        let (kind, size, modified) = match self.node(inode) {
+            None => return Err(Errno::ENOENT),
+            Some(Node::Dir { .. }) => (FileType::Directory, 0, UNIX_EPOCH),
+            Some(&Node::Track { image, track }) => {
+                let size = || {
+                    let (mut locked, _) = self.image(image)?;
+                    let size = match locked.sizes[track] {
+                        Some(size) => size,
+                        None => {
+                            drop(locked);
+                            self.track_file(image, track)?;
+                            locked = self.image(image)?.0;
+                            locked.sizes[track].context("the disc image changed")?
+                        }
+                    };
+                    anyhow::Ok((size, locked.state.modified))
+                };
+                let (size, modified) = size().map_err(|error| self.read_error(image, error))?;
+                (FileType::RegularFile, size, modified)
+            }
+        };
+        let directory = kind == FileType::Directory;
+        Ok(FileAttr {
+            ino: INodeNo(inode),
+            size,
+            blocks: size.div_ceil(512),
+            atime: modified,
+            mtime: modified,
+            ctime: modified,
+            crtime: modified,
+            kind,
+            perm: if directory { 0o555 } else { 0o444 },
+            nlink: if directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    /// Returns the attributes of the entry called `name` in the
+    /// directory `dir`.
+    fn entry(&self, dir: u64, name: &OsStr) -> Result<FileAttr, Errno> {
+        self.attr(self.child(dir, name).ok_or(Errno::ENOENT)?)
+    }
+
+    /// Opens a track file, returning the flags to open it with: the
+    /// kernel may keep the pages it cached unless the disc image
+    /// changed since the file was last opened.
+    fn open_track(&self, inode: u64) -> Result<FopenFlags, Errno> {
+        let (image, track) = match self.node(inode) {
+            Some(&Node::Track { image, track }) => (image, track),
+            Some(Node::Dir { .. }) => return Err(Errno::EISDIR),
+            None => return Err(Errno::ENOENT),
+        };
+        let (mut locked, _) = self
+            .image(image)
+            .map_err(|error| self.read_error(image, error))?;
+        let unchanged = locked.opened[track] == locked.generation;
+        locked.opened[track] = locked.generation;
+        Ok(if unchanged {
+            FopenFlags::FOPEN_KEEP_CACHE
+        } else {
+            FopenFlags::empty()
+        })
+    }
+
+    /// Reads up to `size` bytes of a track file from `offset` on.
+    fn read_track(&self, inode: u64, offset: u64, size: usize) -> Result<Vec<u8>, Errno> {
+        let (image, track) = match self.node(inode) {
+            Some(&Node::Track { image, track }) => (image, track),
+            Some(Node::Dir { .. }) => return Err(Errno::EISDIR),
+            None => return Err(Errno::ENOENT),
+        };
+        let read = || {
+            let (indexed, file) = self.track_file(image, track)?;
+            let mut data = vec![0; size];
+            let read = file.read_at(&indexed, offset, &mut data)?;
+            data.truncate(read);
+            anyhow::Ok(data)
+        };
+        read().map_err(|error| self.read_error(image, error))
+    }
+
+    /// Returns the entries of a directory from `offset` on, with the
+    /// offset of the entry after each.
+    fn dir_entries(
+        &self,
+        inode: u64,
+        offset: u64,
+    ) -> Result<Vec<(u64, u64, FileType, &OsStr)>, Errno> {
+        let Some(Node::Dir { parent, children }) = self.node(inode) else {
+            return Err(match self.node(inode) {
+                Some(_) => Errno::ENOTDIR,
+                None => Errno::ENOENT,
+            });
+        };
+        let entries = [(OsStr::new("."), inode), (OsStr::new(".."), *parent)]
+            .into_iter()
+            .chain(
+                children
+                    .iter()
+                    .map(|(name, child)| (name.as_os_str(), *child)),
+            );
+        Ok(entries
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(position, (name, child))| {
+                let kind = match self.node(child) {
+                    Some(Node::Dir { .. }) => FileType::Directory,
+                    _ => FileType::RegularFile,
+                };
+                (child, position as u64 + 1, kind, name)
+            })
+            .collect())
+    }
+}
+
+impl fuser::Filesystem for Filesystem {
+    fn destroy(&mut self) {
+        if let Err(error) = self.save_size_cache() {
+            warn!(error = format!("{:#}", error), "can't save track sizes");
+        }
+    }
+
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        match self.entry(parent.0, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.attr(ino.0) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        match self.open_track(ino.0) {
+            Ok(flags) => reply.opened(FileHandle(0), flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        match self.read_track(ino.0, offset, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn flush(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _lock_owner: LockOwner,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    // There are no extended attributes; telling the kernel so makes it
+    // stop asking.
+    fn getxattr(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _name: &OsStr,
+        _size: u32,
+        reply: ReplyXattr,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn listxattr(&self, _req: &Request, _ino: INodeNo, _size: u32, reply: ReplyXattr) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn opendir(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        match self.node(ino.0) {
+            Some(Node::Dir { .. }) => reply.opened(FileHandle(0), FopenFlags::empty()),
+            Some(Node::Track { .. }) => reply.error(Errno::ENOTDIR),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        match self.dir_entries(ino.0, offset) {
+            Ok(entries) => {
+                for (child, next, kind, name) in entries {
+                    if reply.add(INodeNo(child), next, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        reply.statfs(0, 0, 0, self.nodes.len() as u64, 0, 4096, 255, 4096);
+    }
+}
+
+/// Mounts `fs` on `mountpoint` (read-only; readable by other users
+/// than the one mounting it only with `allow_other`, which unless
+/// mounting as root takes `user_allow_other` in /etc/fuse.conf) and
+/// serves it until it gets unmounted, e.g. with `fusermount -u` or by
+/// interrupting the process.
+pub fn mount<P: AsRef<Path> + Debug>(
+    fs: Filesystem,
+    mountpoint: P,
+    allow_other: bool,
+) -> anyhow::Result<()> {
+    let mountpoint = mountpoint.as_ref();
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::RO,
+        MountOption::NoDev,
+        MountOption::NoSuid,
+        MountOption::DefaultPermissions,
+        MountOption::FSName("flac-tracksplit".to_string()),
+        MountOption::Subtype("flac-tracksplit".to_string()),
+    ];
+    if allow_other {
+        config.acl = SessionACL::All;
+    }
+    config.n_threads = Some(rayon::current_num_threads());
+    config.clone_fd = true;
+    let mut session = Session::new(fs, mountpoint, &config)
+        .with_context(|| format!("mounting on {:?}", mountpoint))?;
+    info!(?mountpoint, "mounted");
+    let mut unmounter = session.unmount_callable();
+    ctrlc::set_handler(move || {
+        if let Err(error) = unmounter.unmount() {
+            warn!(%error, "can't unmount");
+        }
+    })
+    .context("handling interrupts")?;
+    session.run().context("serving the filesystem")?;
+    info!(?mountpoint, "unmounted");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{split_one_file, test::write_test_image};
+
+    /// Looks up a pathname in `fs`, returning its inode number.
+    fn lookup(fs: &Filesystem, path: &Path) -> u64 {
+        path.iter()
+            .fold(ROOT_INODE, |dir, name| fs.entry(dir, name).unwrap().ino.0)
+    }
+
+    /// Reads a whole track file in `chunk`-sized pieces.
+    fn read_all(fs: &Filesystem, inode: u64, chunk: usize) -> Vec<u8> {
+        let mut contents = vec![];
+        loop {
+            let data = fs.read_track(inode, contents.len() as u64, chunk).unwrap();
+            if data.is_empty() {
+                return contents;
+            }
+            contents.extend(data);
+        }
+    }
+
+    #[test]
+    fn test_read_tracks() {
+        let archive = tempfile::tempdir().unwrap();
+        write_test_image(&archive.path().join("a.flac"), 50_000, &[0, 20_000]);
+        let output_dir = tempfile::tempdir().unwrap();
+        let report = split_one_file(
+            archive.path().join("a.flac"),
+            output_dir.path(),
+            &SplitOptions::default(),
+        )
+        .unwrap();
+
+        let filter = InputFilter::new(&[], &[]).unwrap();
+        let fs = Filesystem::new(archive.path(), &filter, &SplitOptions::default()).unwrap();
+        let mut tracks = 0;
+        for written in report.written_paths() {
+            let inode = lookup(&fs, written.strip_prefix(output_dir.path()).unwrap());
+            let expected = fs::read(written).unwrap();
+            assert_eq!(fs.attr(inode).unwrap().size, expected.len() as u64);
+            assert_eq!(read_all(&fs, inode, 1000), expected, "{:?}", written);
+            tracks += 1;
+        }
+        assert_eq!(tracks, 2);
+
+        assert_eq!(
+            fs.entry(ROOT_INODE, OsStr::new("missing")),
+            Err(Errno::ENOENT)
+        );
+        assert_eq!(fs.read_track(ROOT_INODE, 0, 1000), Err(Errno::EISDIR));
+        let entries = fs.dir_entries(ROOT_INODE, 0).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].3, "Unknown Artist");
+        assert_eq!(fs.dir_entries(ROOT_INODE, 2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_changed_image() {
+        let archive = tempfile::tempdir().unwrap();
+        let image = archive.path().join("a.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let filter = InputFilter::new(&[], &[]).unwrap();
+        let fs = Filesystem::new(archive.path(), &filter, &SplitOptions::default()).unwrap();
+        let planned = plan_one_file(&image, "", &SplitOptions::default()).unwrap();
+        let inode = lookup(&fs, &planned[1].path);
+        let before = read_all(&fs, inode, 4096);
+        assert_eq!(fs.open_track(inode), Ok(FopenFlags::FOPEN_KEEP_CACHE));
+
+        // A longer image with the same tracks:
+        write_test_image(&image, 90_000, &[0, 20_000]);
+        let file = fs::File::options().write(true).open(&image).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        drop(file);
+        let output_dir = tempfile::tempdir().unwrap();
+        let report = split_one_file(&image, output_dir.path(), &SplitOptions::default()).unwrap();
+        let expected = fs::read(&report.tracks[1].path).unwrap();
+        assert_ne!(before, expected);
+        assert_eq!(fs.attr(inode).unwrap().size, expected.len() as u64);
+        assert_eq!(fs.open_track(inode), Ok(FopenFlags::empty()));
+        assert_eq!(read_all(&fs, inode, 4096), expected);
+
+        // A truncated image can't be read, but doesn't bring the
+        // filesystem down:
+        let file = fs::File::options().write(true).open(&image).unwrap();
+        file.set_len(1000).unwrap();
+        drop(file);
+        assert_eq!(fs.read_track(inode, 0, 4096), Err(Errno::EIO));
+
+        // Neither can an image whose tracks changed:
+        write_test_image(&image, 90_000, &[0, 20_000, 40_000]);
+        assert_eq!(fs.attr(inode).err(), Some(Errno::EIO));
+    }
+
+    #[test]
+    fn test_size_cache() {
+        let archive = tempfile::tempdir().unwrap();
+        let image = archive.path().join("a.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let cache = archive.path().join("cache").join("sizes.json");
+        let filter = InputFilter::new(&[], &[]).unwrap();
+        let fs = Filesystem::new(archive.path(), &filter, &SplitOptions::default())
+            .unwrap()
+            .with_size_cache(cache.clone());
+        let planned = plan_one_file(&image, "", &SplitOptions::default()).unwrap();
+        let inode = lookup(&fs, &planned[1].path);
+        let size = fs.attr(inode).unwrap().size;
+        fs.save_size_cache().unwrap();
+
+        // The next mount knows the size without finding the frames:
+        let fs = Filesystem::new(archive.path(), &filter, &SplitOptions::default())
+            .unwrap()
+            .with_size_cache(cache.clone());
+        assert_eq!(fs.attr(inode).unwrap().size, size);
+        assert!(fs.images[0].lock().unwrap().index.is_none());
+
+        // Unless the tracks would come out differently:
+        let options = SplitOptions {
+            metadata_padding: 100,
+            ..Default::default()
+        };
+        let fs = Filesystem::new(archive.path(), &filter, &options)
+            .unwrap()
+            .with_size_cache(cache);
+        assert_ne!(fs.attr(inode).unwrap().size, size);
+        assert!(fs.images[0].lock().unwrap().index.is_some());
+    }
+}
@@ -0,0 +1,105 @@
+//! What [crate::split_one_file] did, in a form that other tools can
+//! ingest (e.g. as JSON) instead of scraping log output.
+
+use crate::{AudioSummary, Track};
+use serde::Serialize;
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// The outcome of splitting one disc image.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SplitReport {
+    /// The disc image that was split.
+    pub input: PathBuf,
+
+    /// Each track of the disc image, in order (including skipped
+    /// ones).
+    pub tracks: Vec<TrackReport>,
+
+    /// Things that didn't stop the split, but might deserve a look.
+    pub warnings: Vec<String>,
+}
+
+impl SplitReport {
+    /// Returns the pathnames of the track files that were written.
+    pub fn written_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.tracks
+            .iter()
+            .filter(|track| !track.skipped)
+            .map(|track| &track.path)
+    }
+}
+
+/// The outcome of writing one track.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackReport {
+    /// The track's number on the cue sheet.
+    pub number: u32,
+
+    /// Where the track was written to.
+    pub path: PathBuf,
+
+    /// Whether the track wasn't written because its output file
+    /// already existed.
+    pub skipped: bool,
+
+    /// The sample in the disc image at which the track starts.
+    pub start_sample: u64,
+
+    /// The sample in the disc image at which the next track starts.
+    pub end_sample: u64,
+
+    /// The number of FLAC frames in the track.
+    pub frames: u64,
+
+    /// The size of the track file, metadata included.
+    pub bytes_written: u64,
+
+    /// The number of samples the cue sheet says the track has.
+    pub inferred_samples: u64,
+
+    /// The number of samples actually written (which differs from
+    /// [TrackReport::inferred_samples] when tracks get cut at frame
+    /// boundaries).
+    pub actual_samples: u64,
+
+    /// `actual_samples - inferred_samples`.
+    pub samples_difference: i64,
+
+    /// The track's tags; a tag can have several values.
+    pub tags: BTreeMap<String, Vec<String>>,
+
+    /// Things that didn't stop the track from being written, but
+    /// might deserve a look.
+    pub warnings: Vec<String>,
+}
+
+impl TrackReport {
+    pub(crate) fn new(
+        track: &Track,
+        path: PathBuf,
+        audio: &AudioSummary,
+        bytes_written: Option<u64>,
+    ) -> Self {
+        let inferred_samples = track.end_ts - track.start_ts;
+        let mut tags: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for tag in &track.tags {
+            tags.entry(tag.key.clone())
+                .or_default()
+                .push(tag.value.to_string());
+        }
+        Self {
+            number: track.number,
+            path,
+            skipped: bytes_written.is_none(),
+            start_sample: track.start_ts,
+            end_sample: track.end_ts,
+            frames: audio.frames,
+            bytes_written: bytes_written.unwrap_or(0),
+            inferred_samples,
+            actual_samples: audio.total_samples,
+            samples_difference: audio.total_samples as i64 - inferred_samples as i64,
+            tags,
+            warnings: audio.warnings.clone(),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Finding track boundaries in the silent gaps of a disc image that
+//! has no cue sheet at all.
+
+use crate::{
+    cuesheet::{CueSheet, CueTrack, CD_FRAMES_PER_SECOND},
+    Frames, SplitOptions,
+};
+use anyhow::Context;
+use metaflac::block::StreamInfo;
+use std::{fmt::Debug, fs::File, path::Path, time::Duration};
+use symphonia_bundle_flac::FlacReader;
+use symphonia_core::{formats::FormatReader, io::MediaSourceStream};
+use tracing::{debug, instrument};
+
+/// The length of the windows whose loudness decides whether they're
+/// silent.
+const WINDOW: Duration = Duration::from_millis(10);
+
+/// What counts as a silent gap between two tracks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SilenceOptions {
+    /// The loudness (RMS, in dB relative to full scale) below which
+    /// audio counts as silent.
+    pub threshold_db: f64,
+
+    /// How long audio has to stay silent to count as a gap between
+    /// tracks.
+    pub min_duration: Duration,
+}
+
+impl Default for SilenceOptions {
+    fn default() -> Self {
+        Self {
+            threshold_db: -60.0,
+            min_duration: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The track boundaries found in a disc image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedSplits {
+    /// The sample rate of the disc image.
+    pub sample_rate: u32,
+
+    /// The sample at which each track after the first one starts;
+    /// each is the first sample of a FLAC frame.
+    pub starts: Vec<u64>,
+}
+
+impl DetectedSplits {
+    /// Returns a CUE sheet for the detected tracks, e.g. to review or
+    /// edit them before splitting with it. Track starts are rounded
+    /// to the nearest CD frame.
+    pub fn cue_sheet(&self, file: &str) -> CueSheet {
+        let rate = u64::from(self.sample_rate);
+        let tracks = [0]
+            .iter()
+            .chain(&self.starts)
+            .zip(1..)
+            .map(|(start, number)| CueTrack {
+                number,
+                indices: vec![(1, (start * CD_FRAMES_PER_SECOND + rate / 2) / rate)],
+                ..Default::default()
+            })
+            .collect();
+        CueSheet {
+            file: Some(file.to_string()),
+            tracks,
+            ..Default::default()
+        }
+    }
+}
+
+/// Decodes a whole disc image and finds the silent gaps in it that
+/// are long and quiet enough to separate two tracks. Each track
+/// starts at the frame boundary closest to the middle of a gap;
+/// silence at the very start and end of the image doesn't count.
+#[instrument(skip(options), err)]
+pub fn detect_silence<P: AsRef<Path> + Debug>(
+    input_path: P,
+    options: &SilenceOptions,
+) -> anyhow::Result<DetectedSplits> {
+    let file = File::open(&input_path).with_context(|| format!("opening {:?}", input_path))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let reader =
+        FlacReader::try_new(mss, &Default::default()).context("could not create flac reader")?;
+    let info = StreamInfo::from_bytes(
+        reader
+            .default_track()
+            .and_then(|track| track.codec_params.extra_data.as_ref())
+            .context("Unclear track codec params - Not a flac file?")?,
+    );
+    let mut frames = Frames::new(reader, &SplitOptions::default())?;
+
+    let rate = u64::from(info.sample_rate);
+    let window_len = (WINDOW.as_nanos() * u128::from(rate) / 1_000_000_000).max(1) as u64;
+    let min_len = (options.min_duration.as_nanos() * u128::from(rate) / 1_000_000_000) as u64;
+    let full_scale = f64::from(1u32 << (info.bits_per_sample - 1));
+    let threshold = 10f64.powf(options.threshold_db / 20.0) * full_scale;
+    let threshold_square = threshold * threshold;
+
+    let mut frame_starts = vec![];
+    let mut gaps = vec![];
+    let mut window_start = 0u64;
+    let mut window_filled = 0u64;
+    let mut sum_squares = 0f64;
+    let mut silence_start: Option<u64> = None;
+    let mut position = 0;
+    while position < info.total_samples {
+        let packet = frames
+            .next_packet()
+            .with_context(|| format!("reading frame at sample {}", position))?;
+        frame_starts.push(packet.ts);
+        position = packet.ts + packet.dur;
+        let channels = frames
+            .decode(&packet)
+            .with_context(|| format!("decoding frame at ts {}", packet.ts))?;
+        for i in 0..channels.first().map_or(0, Vec::len) {
+            sum_squares += channels
+                .iter()
+                .map(|channel| f64::from(channel[i]).powi(2))
+                .sum::<f64>()
+                / channels.len() as f64;
+            window_filled += 1;
+            if window_filled < window_len {
+                continue;
+            }
+            let silent = sum_squares / window_filled as f64 <= threshold_square;
+            match (silent, silence_start) {
+                (true, None) => silence_start = Some(window_start),
+                (false, Some(start)) => {
+                    silence_start = None;
+                    if start > 0 && window_start - start >= min_len {
+                        gaps.push((start, window_start));
+                    }
+                }
+                _ => {}
+            }
+            window_start += window_filled;
+            window_filled = 0;
+            sum_squares = 0.0;
+        }
+    }
+
+    let mut starts: Vec<u64> = gaps
+        .into_iter()
+        .map(|(start, end)| {
+            let middle = (start + end) / 2;
+            let closest = match frame_starts.binary_search(&middle) {
+                Ok(i) => i,
+                Err(i) if i == frame_starts.len() => i - 1,
+                Err(0) => 0,
+                Err(i) if middle - frame_starts[i - 1] < frame_starts[i] - middle => i - 1,
+                Err(i) => i,
+            };
+            debug!(
+                start,
+                end,
+                split = frame_starts[closest],
+                "found silent gap"
+            );
+            frame_starts[closest]
+        })
+        .filter(|start| *start > 0)
+        .collect();
+    starts.dedup();
+    Ok(DetectedSplits {
+        sample_rate: info.sample_rate,
+        starts,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{noise, write_test_image_from};
+
+    const RATE: u64 = 44100;
+
+    /// Writes a disc image of loud noise, silence and quiet noise (at
+    /// about -50 dBFS), with each of `segments` (in half seconds)
+    /// being one of them.
+    fn write_image(path: &Path, segments: &[(u64, &str)]) -> Vec<(u64, u64)> {
+        let mut bounds = vec![];
+        let mut at = 0;
+        for (half_seconds, kind) in segments {
+            let end = at + half_seconds * RATE / 2;
+            bounds.push((at, end, *kind));
+            at = end;
+        }
+        let mut noise = noise();
+        write_test_image_from(path, at, &[0], 16, |sample| {
+            let (_, _, kind) = bounds
+                .iter()
+                .find(|(start, end, _)| (*start..*end).contains(&sample))
+                .unwrap();
+            match *kind {
+                "loud" => noise(),
+                "quiet" => noise() / 12,
+                _ => 0,
+            }
+        });
+        bounds
+            .iter()
+            .filter(|(_, _, kind)| *kind != "loud")
+            .map(|(start, end, _)| (*start, *end))
+            .collect()
+    }
+
+    /// The frame start (of the test image's 4096-sample frames) closest
+    /// to the middle of a gap.
+    fn split_in(gap: (u64, u64)) -> u64 {
+        (gap.0 + gap.1 + 4096) / 2 / 4096 * 4096
+    }
+
+    #[test]
+    fn test_detect_silence() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        let gaps = write_image(
+            &image,
+            &[
+                (5, "silent"),
+                (4, "loud"),
+                (5, "silent"),
+                (3, "loud"),
+                (2, "silent"),
+                (2, "loud"),
+                (5, "quiet"),
+                (2, "loud"),
+                (5, "silent"),
+            ],
+        );
+        let [_start, long, short, quiet, _end] = gaps[..] else {
+            panic!("unexpected gaps {:?}", gaps);
+        };
+        let detect = |threshold_db, min_ms| {
+            let options = SilenceOptions {
+                threshold_db,
+                min_duration: Duration::from_millis(min_ms),
+            };
+            let detected = detect_silence(&image, &options).unwrap();
+            assert_eq!(detected.sample_rate, 44100);
+            assert!(detected.starts.iter().all(|start| start % 4096 == 0));
+            detected.starts
+        };
+
+        // The silence at the start and end of the image never counts:
+        assert_eq!(detect(-60.0, 2000), vec![split_in(long)]);
+        assert_eq!(detect(-60.0, 500), vec![split_in(long), split_in(short)]);
+        assert_eq!(detect(-40.0, 2000), vec![split_in(long), split_in(quiet)]);
+        assert_eq!(
+            detect(-40.0, 500),
+            vec![split_in(long), split_in(short), split_in(quiet)]
+        );
+        assert!(detect(-60.0, 3000).is_empty());
+    }
+
+    #[test]
+    fn test_cue_sheet() {
+        let detected = DetectedSplits {
+            sample_rate: 44100,
+            starts: vec![3 * 44100 + 300, 10 * 44100],
+        };
+        let sheet = detected.cue_sheet("image.flac");
+        assert_eq!(sheet.file.as_deref(), Some("image.flac"));
+        let tracks: Vec<_> = sheet
+            .tracks
+            .iter()
+            .map(|track| (track.number, track.indices.clone()))
+            .collect();
+        assert_eq!(
+            tracks,
+            vec![
+                (1, vec![(1, 0)]),
+                // 300 samples are closer to 1 CD frame than to none:
+                (2, vec![(1, 3 * 75 + 1)]),
+                (3, vec![(1, 10 * 75)]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Track layouts given as explicit timestamps, for disc images (like
+//! live recordings or vinyl rips) that don't come with a CUE sheet.
+
+use crate::LEAD_OUT_TRACK_NUMBER;
+use anyhow::{bail, Context};
+use std::{fmt::Debug, fs, path::Path, time::Duration};
+use symphonia_core::{
+    formats::Cue,
+    meta::{StandardTagKey, Tag, Value},
+};
+
+/// Where to split a disc image, instead of at its cue sheet's tracks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitPoints {
+    /// Start a new track at each of these times; the first track
+    /// starts at the beginning of the image, the last one runs to
+    /// its end.
+    At(Vec<Duration>),
+
+    /// Extract a single track from the start time up to the end time,
+    /// or up to the end of the image if there is none.
+    Range(Duration, Option<Duration>),
+
+    /// Start a new track at each of these sample numbers, like
+    /// [SplitPoints::At].
+    Samples(Vec<u64>),
+}
+
+impl SplitPoints {
+    /// Reads split times from a text file with one time per line.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn read<P: AsRef<Path> + Debug>(path: P) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+        let times = text
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(lineno, line)| {
+                parse_timestamp(line).with_context(|| format!("{:?} line {}", path, lineno + 1))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(SplitPoints::At(times))
+    }
+
+    /// Parses a list of comma-separated times, like `3:21.4,7:02`.
+    pub fn parse_list(list: &str) -> anyhow::Result<Self> {
+        let times = list
+            .split(',')
+            .map(str::trim)
+            .filter(|time| !time.is_empty())
+            .map(parse_timestamp)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(SplitPoints::At(times))
+    }
+
+    /// Parses a range of times, like `1:00..2:30` or `1:00..`.
+    pub fn parse_range(range: &str) -> anyhow::Result<Self> {
+        let (start, end) = range
+            .split_once("..")
+            .with_context(|| format!("range {:?} should look like START..END", range))?;
+        let start = match start.trim() {
+            "" => Duration::ZERO,
+            start => parse_timestamp(start)?,
+        };
+        let end = match end.trim() {
+            "" => None,
+            end => Some(parse_timestamp(end)?),
+        };
+        Ok(SplitPoints::Range(start, end))
+    }
+
+    /// Converts the split points into the [Cue]s of an image with
+    /// the given sample rate and length in samples. A range ends in a
+    /// lead-out cue.
+    pub fn cues(&self, sample_rate: u32, total_samples: u64) -> anyhow::Result<Vec<Cue>> {
+        let to_samples = |time: &Duration| -> anyhow::Result<u64> {
+            let samples = u64::try_from(time.as_nanos() * u128::from(sample_rate) / 1_000_000_000)?;
+            if samples >= total_samples {
+                bail!(
+                    "{} is past the end of the audio",
+                    humantime::format_duration(*time)
+                );
+            }
+            Ok(samples)
+        };
+        let starts = match self {
+            SplitPoints::At(times) => track_starts(
+                times.iter().map(to_samples).collect::<Result<_, _>>()?,
+                total_samples,
+            )?,
+            SplitPoints::Samples(samples) => track_starts(samples.clone(), total_samples)?,
+            SplitPoints::Range(start, end) => {
+                let start = to_samples(start)?;
+                if let Some(end) = end {
+                    if to_samples(end)? <= start {
+                        bail!("the end of a range must come after its start");
+                    }
+                }
+                vec![start]
+            }
+        };
+        let mut cues: Vec<Cue> = starts
+            .into_iter()
+            .zip(1..)
+            .map(|(start_ts, index)| Cue {
+                index,
+                start_ts,
+                tags: vec![Tag::new(
+                    Some(StandardTagKey::TrackNumber),
+                    "TRACKNUMBER",
+                    Value::from(index.to_string()),
+                )],
+                points: vec![],
+            })
+            .collect();
+        if let SplitPoints::Range(_, Some(end)) = self {
+            cues.push(Cue {
+                index: LEAD_OUT_TRACK_NUMBER,
+                start_ts: to_samples(end)?,
+                tags: vec![],
+                points: vec![],
+            });
+        }
+        Ok(cues)
+    }
+}
+
+/// Returns the start of each track when splitting at `samples`:
+/// The first track starts at the beginning.
+fn track_starts(samples: Vec<u64>, total_samples: u64) -> anyhow::Result<Vec<u64>> {
+    let mut starts = vec![0];
+    for start in samples {
+        if start >= total_samples {
+            bail!("sample {} is past the end of the audio", start);
+        }
+        if start <= *starts.last().expect("starts is never empty") {
+            if start == 0 {
+                continue;
+            }
+            bail!("split points must be in increasing order");
+        }
+        starts.push(start);
+    }
+    Ok(starts)
+}
+
+/// Parses a timestamp like `7:02`, `3:21.4`, `1:02:03` or `95.5`
+/// (hours and minutes are optional, seconds can have a fraction).
+pub fn parse_timestamp(timestamp: &str) -> anyhow::Result<Duration> {
+    let invalid = || format!("invalid timestamp {:?}", timestamp);
+    if timestamp.matches(':').count() > 2 {
+        bail!(invalid());
+    }
+    let mut parts = timestamp.trim().rsplit(':');
+    let last = parts.next().unwrap_or_default();
+    let (seconds, fraction) = last.split_once('.').unwrap_or((last, ""));
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(seconds) || !(fraction.is_empty() || digits(fraction)) || fraction.len() > 9 {
+        bail!(invalid());
+    }
+    let nanos = format!("{:0<9}", fraction).parse().with_context(invalid)?;
+    let mut total = Duration::new(seconds.parse().with_context(invalid)?, nanos);
+    for (part, unit) in parts.zip([60, 60 * 60]) {
+        if !digits(part) {
+            bail!(invalid());
+        }
+        total += Duration::from_secs(part.parse::<u64>().with_context(invalid)? * unit);
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("7:02").unwrap(), Duration::from_secs(422));
+        assert_eq!(
+            parse_timestamp("3:21.4").unwrap(),
+            Duration::from_millis(201_400)
+        );
+        assert_eq!(
+            parse_timestamp("1:02:03").unwrap(),
+            Duration::from_secs(3723)
+        );
+        assert_eq!(
+            parse_timestamp("95.5").unwrap(),
+            Duration::from_millis(95_500)
+        );
+        assert!(parse_timestamp("1:2:3:4").is_err());
+        assert!(parse_timestamp("-3").is_err());
+        assert!(parse_timestamp("a:10").is_err());
+    }
+
+    #[test]
+    fn test_cues() {
+        let starts = |points: SplitPoints| {
+            points
+                .cues(100, 100_000)
+                .unwrap()
+                .iter()
+                .map(|cue| (cue.index, cue.start_ts))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            starts(SplitPoints::parse_list("3:21.4,7:02").unwrap()),
+            vec![(1, 0), (2, 20140), (3, 42200)]
+        );
+        assert_eq!(
+            starts(SplitPoints::parse_range("1:00..2:30").unwrap()),
+            vec![(1, 6000), (LEAD_OUT_TRACK_NUMBER, 15000)]
+        );
+        assert_eq!(
+            starts(SplitPoints::parse_range("1:00..").unwrap()),
+            vec![(1, 6000)]
+        );
+        assert!(SplitPoints::parse_list("7:02,3:21")
+            .unwrap()
+            .cues(100, 100_000)
+            .is_err());
+        assert!(SplitPoints::parse_list("20:00")
+            .unwrap()
+            .cues(100, 100_000)
+            .is_err());
+    }
+}
@@ -0,0 +1,494 @@
+//! Keeping a directory of split tracks in sync with a library of disc
+//! images, without re-splitting images that haven't changed.
+//!
+//! A manifest in the output directory remembers, for each disc image
+//! that was split, its size, modification time and STREAMINFO MD5
+//! signature, digests of its cue sheets and of the options it was
+//! split with, and the track files that splitting it wrote.
+
+use crate::{find_cue_sheet, SplitOptions};
+use anyhow::Context;
+use md5::{Digest, Md5};
+use metaflac::Block;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::{debug, info};
+
+/// The name of the manifest file in the output directory.
+pub const MANIFEST_NAME: &str = ".flac-tracksplit-sync.json";
+
+/// What identifies a version of a disc image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageState {
+    /// The size of the image file.
+    pub size: u64,
+
+    /// When the image file was last modified.
+    pub modified: SystemTime,
+
+    /// The MD5 signature of the image's audio, from its STREAMINFO
+    /// block, in hex.
+    pub md5: String,
+
+    /// A digest of the cue sheets the image's track layout can come
+    /// from: its CUESHEET block and comment, and the `.cue` file given
+    /// or next to it.
+    #[serde(default)]
+    pub cue_sheet: String,
+
+    /// A digest of the tags given for the image's tracks (e.g. with a
+    /// tags file).
+    #[serde(default)]
+    pub tags: String,
+
+    /// A digest of the options that decide where the image's tracks
+    /// go and what audio they get (e.g. the path template or the
+    /// pregap mode).
+    #[serde(default)]
+    pub layout: String,
+}
+
+impl ImageState {
+    /// Reads the current state of a disc image, to be split into
+    /// `base_path` with `options`.
+    pub fn read<P: AsRef<Path> + Debug>(
+        path: P,
+        base_path: &Path,
+        options: &SplitOptions,
+    ) -> anyhow::Result<Self> {
+        let metadata = fs::metadata(&path).with_context(|| format!("reading {:?}", path))?;
+        let tag = metaflac::Tag::read_from_path(&path)
+            .with_context(|| format!("reading STREAMINFO of {:?}", path))?;
+        let md5 = hex(tag
+            .get_streaminfo()
+            .with_context(|| format!("{:?} has no STREAMINFO block", path))?
+            .md5
+            .as_slice());
+
+        let mut cue_sheet = Md5::new();
+        for block in tag.blocks() {
+            if let Block::CueSheet(sheet) = block {
+                cue_sheet.update(format!("{:?}", sheet));
+            }
+        }
+        for comment in tag.get_vorbis("CUESHEET").into_iter().flatten() {
+            cue_sheet.update(comment);
+        }
+        let sheet_path = match &options.cue_sheet {
+            Some(sheet_path) => Some(sheet_path.clone()),
+            None => find_cue_sheet(path.as_ref()),
+        };
+        if let Some(sheet_path) = sheet_path {
+            let contents =
+                fs::read(&sheet_path).with_context(|| format!("reading {:?}", sheet_path))?;
+            cue_sheet.update(contents);
+        }
+
+        let layout = format!(
+            "{:?}",
+            (
+                base_path,
+                &options.path_template,
+                options.disc_layout,
+                options.flat_output,
+                options.pregap,
+                &options.split_points,
+                &options.detect_silence,
+                options.cue_encoding,
+                options.sample_accurate,
+                options.compute_md5,
+                options.seekpoint_interval,
+            )
+        );
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().context("file modification time")?,
+            md5,
+            cue_sheet: hex(&cue_sheet.finalize()),
+            tags: hex(&Md5::digest(format!("{:?}", options.tags))),
+            layout: hex(&Md5::digest(layout)),
+        })
+    }
+}
+
+/// Formats bytes as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// What the manifest remembers about a disc image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageEntry {
+    /// The state of the image when it was last split.
+    pub state: ImageState,
+
+    /// The track files written when the image was last split,
+    /// relative to the output directory.
+    pub outputs: Vec<PathBuf>,
+}
+
+/// How a disc image compares to what the manifest remembers about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// The image was never split.
+    New,
+
+    /// The image and all its track files are unchanged.
+    UpToDate,
+
+    /// The image's audio and track layout are unchanged, but its
+    /// metadata (e.g. tags) isn't, so retagging its tracks will do.
+    MetadataChanged,
+
+    /// The image's audio or track layout changed, or some of its
+    /// track files are gone.
+    Changed,
+}
+
+/// The disc images split into an output directory, and their tracks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The disc images, by their absolute pathname.
+    pub images: BTreeMap<PathBuf, ImageEntry>,
+}
+
+impl Manifest {
+    /// Loads the manifest from an output directory; a directory
+    /// without one gets an empty manifest.
+    pub fn load<P: AsRef<Path> + Debug>(output_dir: P) -> anyhow::Result<Self> {
+        let path = output_dir.as_ref().join(MANIFEST_NAME);
+        match fs::read(&path) {
+            Ok(contents) => {
+                serde_json::from_slice(&contents).with_context(|| format!("parsing {:?}", path))
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error).with_context(|| format!("reading {:?}", path)),
+        }
+    }
+
+    /// Saves the manifest into an output directory, replacing the
+    /// previous one at once.
+    pub fn save<P: AsRef<Path> + Debug>(&self, output_dir: P) -> anyhow::Result<()> {
+        let output_dir = output_dir.as_ref();
+        fs::create_dir_all(output_dir).context("creating output dir")?;
+        let mut file = tempfile::NamedTempFile::new_in(output_dir)
+            .context("creating temporary manifest file")?;
+        serde_json::to_writer_pretty(&mut file, self).context("writing manifest")?;
+        writeln!(file).context("writing manifest")?;
+        file.persist(output_dir.join(MANIFEST_NAME))
+            .context("moving manifest into place")?;
+        Ok(())
+    }
+
+    /// Compares a disc image (given by its absolute pathname) to what
+    /// the manifest remembers about it.
+    pub fn freshness(&self, image: &Path, state: &ImageState, output_dir: &Path) -> Freshness {
+        let Some(entry) = self.images.get(image) else {
+            return Freshness::New;
+        };
+        let outputs_exist = entry
+            .outputs
+            .iter()
+            .all(|output| output_dir.join(output).is_file());
+        let layout_changed = entry.state.md5 != state.md5
+            || entry.state.cue_sheet != state.cue_sheet
+            || entry.state.layout != state.layout;
+        if layout_changed || !outputs_exist {
+            Freshness::Changed
+        } else if entry.state != *state {
+            Freshness::MetadataChanged
+        } else {
+            Freshness::UpToDate
+        }
+    }
+
+    /// Returns the pathnames of the track files that were written for
+    /// a disc image (given by its absolute pathname) when it was last
+    /// split.
+    pub fn outputs(&self, image: &Path, output_dir: &Path) -> Vec<PathBuf> {
+        self.images
+            .get(image)
+            .map(|entry| {
+                entry
+                    .outputs
+                    .iter()
+                    .map(|output| output_dir.join(output))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remembers that a disc image (given by its absolute pathname)
+    /// was split into the given track files, and deletes the tracks
+    /// that an earlier split of it wrote which weren't written again.
+    ///
+    /// Only call this once the split succeeded, so that a failed split
+    /// leaves the earlier tracks alone.
+    pub fn record<'a>(
+        &mut self,
+        image: PathBuf,
+        state: ImageState,
+        outputs: impl IntoIterator<Item = &'a PathBuf>,
+        output_dir: &Path,
+    ) -> anyhow::Result<()> {
+        let outputs: Vec<PathBuf> = outputs
+            .into_iter()
+            .map(|output| {
+                output
+                    .strip_prefix(output_dir)
+                    .unwrap_or(output)
+                    .to_path_buf()
+            })
+            .collect();
+        let stale: Vec<PathBuf> = self
+            .images
+            .get(&image)
+            .map(|previous| {
+                previous
+                    .outputs
+                    .iter()
+                    .filter(|output| !outputs.contains(output))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.images.insert(image, ImageEntry { state, outputs });
+        for output in stale {
+            remove_output(&output, output_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the track files that were written for a disc image
+    /// (given by its absolute pathname), and forgets about them.
+    pub fn remove_outputs(&mut self, image: &Path, output_dir: &Path) -> anyhow::Result<()> {
+        let Some(entry) = self.images.remove(image) else {
+            return Ok(());
+        };
+        for output in entry.outputs {
+            remove_output(&output, output_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the track files of all disc images that no longer
+    /// exist, and forgets about the images. Returns the pathnames of
+    /// those images.
+    pub fn prune(&mut self, output_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let gone: Vec<PathBuf> = self
+            .images
+            .keys()
+            .filter(|image| !image.exists())
+            .cloned()
+            .collect();
+        for image in &gone {
+            info!(?image, "disc image is gone, removing its tracks");
+            self.remove_outputs(image, output_dir)?;
+        }
+        Ok(gone)
+    }
+}
+
+/// Deletes a track file (given relative to the output directory), and
+/// the directories that only held it.
+fn remove_output(output: &Path, output_dir: &Path) -> anyhow::Result<()> {
+    let path = output_dir.join(output);
+    debug!(?path, "removing track");
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error).with_context(|| format!("removing {:?}", path)),
+    }
+    // Clean up the directories that only held this image's tracks;
+    // removing a directory that isn't empty fails.
+    for dir in path.ancestors().skip(1) {
+        if dir == output_dir || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{split_one_file, test::write_test_image, PregapMode};
+    use symphonia_core::meta::{Tag, Value};
+
+    #[test]
+    fn test_freshness() {
+        let dir = tempfile::tempdir().unwrap();
+        let track = dir.path().join("Album/01.Title.flac");
+        fs::create_dir_all(track.parent().unwrap()).unwrap();
+        fs::File::create(&track).unwrap();
+        let state = ImageState {
+            size: 100,
+            modified: SystemTime::UNIX_EPOCH,
+            md5: "00".to_string(),
+            cue_sheet: "c0".to_string(),
+            tags: "a0".to_string(),
+            layout: "10".to_string(),
+        };
+        let image = Path::new("/archive/album.flac");
+
+        let mut manifest = Manifest::default();
+        assert_eq!(
+            manifest.freshness(image, &state, dir.path()),
+            Freshness::New
+        );
+        manifest
+            .record(image.to_path_buf(), state.clone(), [&track], dir.path())
+            .unwrap();
+        assert_eq!(
+            manifest.images[image].outputs,
+            vec![PathBuf::from("Album/01.Title.flac")]
+        );
+        manifest.save(dir.path()).unwrap();
+        let manifest = Manifest::load(dir.path()).unwrap();
+        assert_eq!(
+            manifest.freshness(image, &state, dir.path()),
+            Freshness::UpToDate
+        );
+        let retagged = ImageState {
+            size: 120,
+            ..state.clone()
+        };
+        assert_eq!(
+            manifest.freshness(image, &retagged, dir.path()),
+            Freshness::MetadataChanged
+        );
+        let retagged = ImageState {
+            tags: "a1".to_string(),
+            ..state.clone()
+        };
+        assert_eq!(
+            manifest.freshness(image, &retagged, dir.path()),
+            Freshness::MetadataChanged
+        );
+        let reencoded = ImageState {
+            md5: "01".to_string(),
+            ..state.clone()
+        };
+        assert_eq!(
+            manifest.freshness(image, &reencoded, dir.path()),
+            Freshness::Changed
+        );
+        let recued = ImageState {
+            cue_sheet: "c1".to_string(),
+            ..state.clone()
+        };
+        assert_eq!(
+            manifest.freshness(image, &recued, dir.path()),
+            Freshness::Changed
+        );
+        let relaid = ImageState {
+            layout: "11".to_string(),
+            ..state.clone()
+        };
+        assert_eq!(
+            manifest.freshness(image, &relaid, dir.path()),
+            Freshness::Changed
+        );
+
+        let mut manifest = manifest;
+        manifest.prune(dir.path()).unwrap();
+        assert!(manifest.images.is_empty());
+        assert!(!track.exists());
+        assert!(!dir.path().join("Album").exists());
+    }
+
+    #[test]
+    fn test_split_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let output_dir = dir.path().join("out");
+        let state = ImageState::read(&image, &output_dir, &SplitOptions::default()).unwrap();
+
+        let mut manifest = Manifest::default();
+        let report = split_one_file(&image, &output_dir, &SplitOptions::default()).unwrap();
+        manifest
+            .record(
+                image.clone(),
+                state.clone(),
+                report.written_paths(),
+                &output_dir,
+            )
+            .unwrap();
+        // A track only an earlier version of the image had:
+        let gone = output_dir.join("Old/99.Gone.flac");
+        fs::create_dir_all(gone.parent().unwrap()).unwrap();
+        fs::File::create(&gone).unwrap();
+        let entry = manifest.images.get_mut(&image).unwrap();
+        entry.outputs.push(PathBuf::from("Old/99.Gone.flac"));
+
+        // Splitting again fails on the existing tracks unless they may
+        // be replaced, and a failed split leaves all of them alone:
+        assert!(split_one_file(&image, &output_dir, &SplitOptions::default()).is_err());
+        let options = SplitOptions {
+            replaceable: manifest.outputs(&image, &output_dir),
+            ..Default::default()
+        };
+        assert_eq!(options.replaceable.len(), 3);
+        assert!(options.replaceable.iter().all(|output| output.is_file()));
+        let report = split_one_file(&image, &output_dir, &options).unwrap();
+        assert!(gone.is_file());
+
+        // Only the tracks that weren't written again go:
+        manifest
+            .record(image.clone(), state, report.written_paths(), &output_dir)
+            .unwrap();
+        assert!(!gone.exists());
+        assert!(!output_dir.join("Old").exists());
+        assert_eq!(manifest.images[&image].outputs.len(), 2);
+        assert!(report.written_paths().all(|output| output.is_file()));
+    }
+
+    #[test]
+    fn test_image_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.flac");
+        write_test_image(&image, 50_000, &[0, 20_000]);
+        let output_dir = dir.path().join("out");
+        let read = |options: &SplitOptions| ImageState::read(&image, &output_dir, options).unwrap();
+        let state = read(&SplitOptions::default());
+        assert_eq!(read(&SplitOptions::default()), state);
+
+        let tagged = read(&SplitOptions {
+            tags: vec![Tag::new(None, "GENRE", Value::from("Jazz"))],
+            ..Default::default()
+        });
+        assert_ne!(tagged.tags, state.tags);
+        assert_eq!(
+            (&tagged.cue_sheet, &tagged.layout),
+            (&state.cue_sheet, &state.layout)
+        );
+
+        let relaid = read(&SplitOptions {
+            pregap: PregapMode::Prepend,
+            ..Default::default()
+        });
+        assert_ne!(relaid.layout, state.layout);
+        let renamed = read(&SplitOptions {
+            path_template: Some("{title}".parse().unwrap()),
+            ..Default::default()
+        });
+        assert_ne!(renamed.layout, state.layout);
+        assert_ne!(renamed.layout, relaid.layout);
+
+        // A cue sheet next to the image counts, as does a changed one:
+        let sheet = dir.path().join("image.cue");
+        fs::write(&sheet, "FILE \"image.flac\" WAVE\n").unwrap();
+        let cued = read(&SplitOptions::default());
+        assert_ne!(cued.cue_sheet, state.cue_sheet);
+        fs::write(&sheet, "FILE \"image.flac\" WAVE\nREM\n").unwrap();
+        assert_ne!(read(&SplitOptions::default()).cue_sheet, cued.cue_sheet);
+    }
+}
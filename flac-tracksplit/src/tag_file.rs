@@ -0,0 +1,35 @@
+//! Tags given on the command line or in a sidecar file, in the
+//! `KEY=VALUE` format that `metaflac --export-tags-to` writes.
+//!
+//! As with the disc image's own vorbis comments, a key with a `[n]`
+//! suffix (like `TITLE[2]=Intro`) only applies to track `n`.
+
+use anyhow::{bail, Context};
+use std::{fmt::Debug, fs, path::Path};
+use symphonia_core::meta::{Tag, Value};
+
+/// Parses a single `KEY=VALUE` tag. Keys are uppercased, like vorbis
+/// comment field names are conventionally written.
+pub fn parse_tag(tag: &str) -> anyhow::Result<Tag> {
+    let (key, value) = tag
+        .split_once('=')
+        .with_context(|| format!("tag {:?} should look like KEY=VALUE", tag))?;
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("tag {:?} has no key", tag);
+    }
+    Ok(Tag::new(None, &key.to_uppercase(), Value::from(value)))
+}
+
+/// Reads tags from a file with one `KEY=VALUE` tag per line. Blank
+/// lines and lines starting with `#` are ignored.
+pub fn read_tags_file<P: AsRef<Path> + Debug>(path: P) -> anyhow::Result<Vec<Tag>> {
+    let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(lineno, line)| {
+            parse_tag(line).with_context(|| format!("{:?} line {}", path, lineno + 1))
+        })
+        .collect()
+}
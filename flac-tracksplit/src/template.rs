@@ -0,0 +1,362 @@
+//! Format strings for output pathnames, like
+//! `{albumartist,artist}/{date|year} - {album}{discnumber: (Disc {discnumber})}/{tracknumber:02}. {title}.flac`.
+//!
+//! A template is literal text with placeholders in braces:
+//!
+//! * `{title}` is replaced by the value of the `TITLE` tag (tag names
+//!   are case-insensitive), or nothing if the tag is missing. `disc`
+//!   and `track` are short for `discnumber` and `tracknumber`.
+//! * `{albumartist,artist,"Unknown Artist"}` falls back to the next
+//!   tag (or quoted literal text) if a tag is missing.
+//! * `{date|year}` passes the value through filters: `year` (the
+//!   first four-digit number), `number` (the leading number, so
+//!   `3/12` becomes `3`), `upper`, `lower`, `first` (the first
+//!   character) and `trim`.
+//! * `{tracknumber:02}` zero-pads the value's leading number to two
+//!   digits.
+//! * `{discnumber: (Disc {discnumber})}` is a conditional segment:
+//!   the text after the colon (which can contain placeholders of its
+//!   own) only appears if the value before it is present.
+//! * `{{` and `}}` stand for literal braces (outside of conditional
+//!   segments, where `}` always ends the segment).
+//!
+//! Each substituted value is made safe for use in a pathname, so
+//! `/` in a tag can't create directories; only the template's own
+//! slashes do.
+
+use crate::Track;
+use anyhow::{bail, Context};
+use std::{borrow::Cow, iter::Peekable, path::PathBuf, str::Chars, str::FromStr};
+
+/// A parsed pathname template. See the [module documentation](self)
+/// for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Value(Placeholder),
+    Conditional(Placeholder, Vec<Segment>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Placeholder {
+    sources: Vec<Source>,
+    filters: Vec<Filter>,
+    zero_pad: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    Tag(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filter {
+    Year,
+    Number,
+    Upper,
+    Lower,
+    First,
+    Trim,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "year" => Filter::Year,
+            "number" => Filter::Number,
+            "upper" => Filter::Upper,
+            "lower" => Filter::Lower,
+            "first" => Filter::First,
+            "trim" => Filter::Trim,
+            _ => bail!("unknown filter {:?}", name),
+        })
+    }
+}
+
+impl Filter {
+    fn apply(self, value: &str) -> String {
+        match self {
+            Filter::Year => value
+                .as_bytes()
+                .windows(4)
+                .position(|digits| digits.iter().all(u8::is_ascii_digit))
+                .map(|start| value[start..start + 4].to_string())
+                .unwrap_or_default(),
+            Filter::Number => leading_number(value).unwrap_or_default().to_string(),
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::First => value.chars().take(1).collect(),
+            Filter::Trim => value.trim().to_string(),
+        }
+    }
+}
+
+/// Returns the digits that a value starts with, if any.
+fn leading_number(value: &str) -> Option<&str> {
+    let value = value.trim_start();
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    (end > 0).then(|| &value[..end])
+}
+
+impl Placeholder {
+    /// Returns the (unsanitized) value of this placeholder, or `None`
+    /// if none of its sources has one.
+    fn value<'a, F>(&self, lookup: &F) -> Option<String>
+    where
+        F: Fn(&str) -> Option<Cow<'a, str>>,
+    {
+        let value = self.sources.iter().find_map(|source| match source {
+            Source::Tag(key) => lookup(key).filter(|value| !value.is_empty()),
+            Source::Literal(text) => Some(Cow::Owned(text.clone())),
+        })?;
+        let mut value = self
+            .filters
+            .iter()
+            .fold(value.into_owned(), |value, filter| filter.apply(&value));
+        if let Some(width) = self.zero_pad {
+            if let Some(number) = leading_number(&value) {
+                value = format!(
+                    "{:0>width$}{}",
+                    number,
+                    &value.trim_start()[number.len()..],
+                    width = width
+                );
+            }
+        }
+        Some(value).filter(|value| !value.is_empty())
+    }
+}
+
+impl PathTemplate {
+    /// Renders the template into a relative pathname, looking up tag
+    /// values with `lookup` (which gets uppercased tag names).
+    pub fn render<'a, F>(&self, lookup: F) -> PathBuf
+    where
+        F: Fn(&str) -> Option<Cow<'a, str>>,
+    {
+        let mut rendered = String::new();
+        render_segments(&self.segments, &lookup, &mut rendered);
+        rendered
+            .split('/')
+            .map(str::trim)
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+}
+
+fn render_segments<'a, F>(segments: &[Segment], lookup: &F, out: &mut String)
+where
+    F: Fn(&str) -> Option<Cow<'a, str>>,
+{
+    for segment in segments {
+        match segment {
+            Segment::Literal(text) => out.push_str(text),
+            Segment::Value(placeholder) => {
+                if let Some(value) = placeholder.value(lookup) {
+                    out.push_str(&sanitize_value(&value));
+                }
+            }
+            Segment::Conditional(condition, body) => {
+                if condition.value(lookup).is_some() {
+                    render_segments(body, lookup, out);
+                }
+            }
+        }
+    }
+}
+
+/// Makes a tag value safe to use as (part of) a path component.
+fn sanitize_value(value: &str) -> Cow<'_, str> {
+    if value.chars().all(|c| c == '.') {
+        // "." and ".." would refer to other directories:
+        Cow::Owned("_".repeat(value.len()))
+    } else {
+        Track::sanitize_pathname(value)
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut chars = template.chars().peekable();
+        let segments = parse_segments(&mut chars, false)
+            .with_context(|| format!("invalid template {:?}", template))?;
+        Ok(PathTemplate { segments })
+    }
+}
+
+fn parse_segments(chars: &mut Peekable<Chars>, nested: bool) -> anyhow::Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    loop {
+        match chars.next() {
+            None if nested => bail!("unterminated conditional segment"),
+            None => break,
+            Some('{') if chars.next_if_eq(&'{').is_some() => literal.push('{'),
+            Some('}') if nested => break,
+            Some('}') if chars.next_if_eq(&'}').is_some() => literal.push('}'),
+            Some('}') => bail!("unmatched '}}'"),
+            Some('{') => {
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(parse_placeholder(chars)?);
+            }
+            Some(c) => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Parses a placeholder after its opening brace, up to and including
+/// its closing brace.
+fn parse_placeholder(chars: &mut Peekable<Chars>) -> anyhow::Result<Segment> {
+    let mut sources = vec![];
+    let mut filters = vec![];
+    let mut word = String::new();
+    let mut in_filters = false;
+    let finish_word = |word: &mut String,
+                       sources: &mut Vec<Source>,
+                       filters: &mut Vec<Filter>,
+                       in_filters: bool|
+     -> anyhow::Result<()> {
+        let name = std::mem::take(word);
+        let name = name.trim();
+        if in_filters {
+            filters.push(name.parse()?);
+        } else if !name.is_empty() {
+            let key = match name.to_uppercase().as_str() {
+                "DISC" => "DISCNUMBER".to_string(),
+                "TRACK" => "TRACKNUMBER".to_string(),
+                key => key.to_string(),
+            };
+            sources.push(Source::Tag(key));
+        }
+        Ok(())
+    };
+    let end = loop {
+        match chars.next() {
+            None => bail!("unterminated placeholder"),
+            Some('"') if !in_filters => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => bail!("unterminated quoted text"),
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                    }
+                }
+                sources.push(Source::Literal(text));
+            }
+            Some(',') if !in_filters => {
+                finish_word(&mut word, &mut sources, &mut filters, in_filters)?
+            }
+            Some('|') => {
+                finish_word(&mut word, &mut sources, &mut filters, in_filters)?;
+                in_filters = true;
+            }
+            Some(c @ (':' | '}')) => {
+                finish_word(&mut word, &mut sources, &mut filters, in_filters)?;
+                break c;
+            }
+            Some('{') => bail!("unexpected '{{' in placeholder"),
+            Some(c) => word.push(c),
+        }
+    };
+    if sources.is_empty() {
+        bail!("empty placeholder");
+    }
+    let mut placeholder = Placeholder {
+        sources,
+        filters,
+        zero_pad: None,
+    };
+    if end == '}' {
+        return Ok(Segment::Value(placeholder));
+    }
+
+    // After a colon comes either a zero-padding width or the body of a
+    // conditional segment:
+    let mut lookahead = chars.clone();
+    let width: String = lookahead.by_ref().take_while(|c| *c != '}').collect();
+    if width.len() > 1 && width.starts_with('0') && width.chars().all(|c| c.is_ascii_digit()) {
+        *chars = lookahead;
+        placeholder.zero_pad = Some(width.parse()?);
+        return Ok(Segment::Value(placeholder));
+    }
+    let body = parse_segments(chars, true)?;
+    Ok(Segment::Conditional(placeholder, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn render(template: &str, tags: &[(&str, &str)]) -> String {
+        let tags: HashMap<String, String> = tags
+            .iter()
+            .map(|(key, value)| (key.to_uppercase(), value.to_string()))
+            .collect();
+        let template: PathTemplate = template.parse().expect("parsing");
+        template
+            .render(|key| tags.get(key).map(|value| Cow::Borrowed(value.as_str())))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_render() {
+        let template = "{albumartist,artist,\"Unknown Artist\"}/{date|year} - {album}{ discnumber: (Disc {discnumber})}/{tracknumber:02}. {title}.flac";
+        let tags = [
+            ("artist", "AC/DC"),
+            ("date", "1980-07-25"),
+            ("album", "Back in Black"),
+            ("tracknumber", "3/10"),
+            ("title", "What Do You Do for Money Honey?"),
+        ];
+        assert_eq!(
+            render(template, &tags),
+            "AC_DC/1980 - Back in Black/03_10. What Do You Do for Money Honey_.flac"
+        );
+        let mut with_disc = tags.to_vec();
+        with_disc.push(("discnumber", "2"));
+        assert_eq!(
+            render("{album}{ disc: (Disc {discnumber})}", &with_disc),
+            "Back in Black (Disc 2)"
+        );
+        assert_eq!(
+            render("{albumartist,\"Unknown Artist\"}", &[]),
+            "Unknown Artist"
+        );
+        assert_eq!(render("{tracknumber|number:02}", &tags), "03");
+        assert_eq!(render("{artist|first|upper}/{artist}", &tags), "A/AC_DC");
+        assert_eq!(render("{{{title}}}", &[("title", "x")]), "{x}");
+        assert_eq!(render("{missing}/{title}", &[("title", "..")]), "__");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for template in ["{title", "title}", "{}", "{title|bogus}", "{a: {b}"] {
+            assert!(
+                template.parse::<PathTemplate>().is_err(),
+                "{:?} should not parse",
+                template
+            );
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! Checking that the tracks split from a disc image hold exactly the
+//! image's audio, e.g. before deleting the image.
+
+use crate::{open_disc_image, utf8_decode_be_u64, Frames, PlannedTrack, SplitOptions};
+use anyhow::{bail, Context};
+use metaflac::block::StreamInfo;
+use std::{
+    fmt::Debug,
+    fs::File,
+    path::{Path, PathBuf},
+};
+use symphonia_bundle_flac::FlacReader;
+use symphonia_core::{
+    checksum::{Crc16Ansi, Crc8Ccitt},
+    formats::FormatReader,
+    io::{BufReader, MediaSourceStream, Monitor, ReadBytes},
+};
+use tracing::{debug, instrument};
+
+/// The outcome of verifying the tracks of one disc image.
+#[derive(Debug, Clone, Default)]
+pub struct Verification {
+    /// The disc image that was verified.
+    pub input: PathBuf,
+
+    /// Each of the image's tracks, in order.
+    pub tracks: Vec<TrackVerification>,
+
+    /// The ranges of the image's samples (start inclusive, end
+    /// exclusive) that should be in a track, but are in none.
+    pub uncovered: Vec<(u64, u64)>,
+}
+
+impl Verification {
+    /// Returns whether every sample of the image is in exactly one
+    /// track, and all tracks are intact.
+    pub fn is_ok(&self) -> bool {
+        self.uncovered.is_empty() && self.tracks.iter().all(|track| track.problems.is_empty())
+    }
+}
+
+/// The outcome of verifying one track file.
+#[derive(Debug, Clone, Default)]
+pub struct TrackVerification {
+    /// The track file.
+    pub path: PathBuf,
+
+    /// The sample of the disc image that the track's audio starts at,
+    /// if it could be found.
+    pub image_start: Option<u64>,
+
+    /// The number of samples decoded from the track.
+    pub samples: u64,
+
+    /// The number of frames in the track.
+    pub frames: u64,
+
+    /// What's wrong with the track, if anything.
+    pub problems: Vec<String>,
+}
+
+/// A decoded FLAC stream, of which a window of samples is kept in
+/// memory.
+struct PcmStream {
+    frames: Frames,
+    info: StreamInfo,
+    /// The decoded samples of each channel, starting at
+    /// `buffer_start`.
+    buffer: Vec<Vec<i32>>,
+    buffer_start: u64,
+    decoded_end: u64,
+    /// The first sample of each frame decoded so far.
+    frame_starts: Vec<u64>,
+}
+
+impl PcmStream {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {:?}", path))?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let reader = FlacReader::try_new(mss, &Default::default())
+            .with_context(|| format!("reading {:?}", path))?;
+        let info = StreamInfo::from_bytes(
+            reader
+                .default_track()
+                .and_then(|track| track.codec_params.extra_data.as_ref())
+                .context("Unclear track codec params - Not a flac file?")?,
+        );
+        Ok(Self {
+            frames: Frames::new(reader, &SplitOptions::default())?,
+            buffer: vec![vec![]; usize::from(info.num_channels)],
+            info,
+            buffer_start: 0,
+            decoded_end: 0,
+            frame_starts: vec![],
+        })
+    }
+
+    /// Decodes the stream up to at least sample `end` (or to the end
+    /// of the stream).
+    fn fill_to(&mut self, end: u64) -> anyhow::Result<()> {
+        while self.decoded_end < end.min(self.info.total_samples) {
+            let packet = self
+                .frames
+                .next_packet()
+                .with_context(|| format!("reading frame at sample {}", self.decoded_end))?;
+            let channels = self
+                .frames
+                .decode(&packet)
+                .with_context(|| format!("decoding frame at sample {}", self.decoded_end))?;
+            self.frame_starts.push(self.decoded_end);
+            self.decoded_end += channels.first().map_or(0, |channel| channel.len() as u64);
+            for (buffer, channel) in self.buffer.iter_mut().zip(channels) {
+                buffer.extend(channel);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the samples before `start`.
+    fn discard_before(&mut self, start: u64) {
+        let start = start.clamp(self.buffer_start, self.decoded_end);
+        let count = (start - self.buffer_start) as usize;
+        for buffer in &mut self.buffer {
+            buffer.drain(..count);
+        }
+        self.buffer_start = start;
+    }
+
+    /// Returns whether `samples` (per channel) are what the stream has
+    /// at `start`, decoding more of it if necessary.
+    fn matches_at(&mut self, start: u64, samples: &[Vec<i32>]) -> anyhow::Result<bool> {
+        let len = samples.first().map_or(0, Vec::len);
+        self.fill_to(start + len as u64)?;
+        if start < self.buffer_start
+            || start + len as u64 > self.decoded_end
+            || samples.len() != self.buffer.len()
+        {
+            return Ok(false);
+        }
+        let offset = (start - self.buffer_start) as usize;
+        Ok(self
+            .buffer
+            .iter()
+            .zip(samples)
+            .all(|(buffer, samples)| buffer[offset..offset + len] == samples[..]))
+    }
+}
+
+/// Checks the CRCs of a FLAC frame and returns the frame or sample
+/// number in its header.
+fn check_frame(frame: &[u8]) -> anyhow::Result<u64> {
+    if frame.len() < 6 {
+        bail!("frame is too short");
+    }
+    let mut reader = BufReader::new(frame);
+    let _sync = reader.read_be_u16()?;
+    let desc = reader.read_be_u16()?;
+    let (number, _) = utf8_decode_be_u64(&mut reader).context("decoding frame number")?;
+    let extra_block_size_bytes = match (desc & 0xf000) >> 12 {
+        0b0110 => 1,
+        0b0111 => 2,
+        _ => 0,
+    };
+    let extra_sample_rate_bytes = match (desc & 0x0f00) >> 8 {
+        0b1100 => 1,
+        0b1101 | 0b1110 => 2,
+        _ => 0,
+    };
+    let header_len = reader.pos() as usize + extra_block_size_bytes + extra_sample_rate_bytes;
+    if header_len + 3 > frame.len() {
+        bail!("frame is too short");
+    }
+
+    let mut header_crc = Crc8Ccitt::new(0);
+    header_crc.process_buf_bytes(&frame[..header_len]);
+    if header_crc.crc() != frame[header_len] {
+        bail!("header CRC mismatch");
+    }
+    let (body, footer) = frame.split_at(frame.len() - 2);
+    let mut footer_crc = Crc16Ansi::new(0);
+    footer_crc.process_buf_bytes(body);
+    if footer_crc.crc().to_be_bytes() != footer {
+        bail!("footer CRC mismatch");
+    }
+    Ok(number)
+}
+
+/// Decodes a track file and compares it to the disc image's audio,
+/// starting at the first of the `candidates` sample offsets at which
+/// the track's first frame matches.
+fn verify_track(
+    path: &Path,
+    image: &mut PcmStream,
+    candidates: &[u64],
+) -> anyhow::Result<TrackVerification> {
+    let mut verification = TrackVerification {
+        path: path.to_path_buf(),
+        ..Default::default()
+    };
+    let mut track = PcmStream::open(path)?;
+    if (
+        track.info.sample_rate,
+        track.info.num_channels,
+        track.info.bits_per_sample,
+    ) != (
+        image.info.sample_rate,
+        image.info.num_channels,
+        image.info.bits_per_sample,
+    ) {
+        verification
+            .problems
+            .push("stream format differs from the disc image".to_string());
+        return Ok(verification);
+    }
+
+    let mut variable_block_size = None;
+    let mut position = 0;
+    while position < track.info.total_samples {
+        let packet = match track.frames.next_packet() {
+            Ok(packet) => packet,
+            Err(error) => {
+                verification.problems.push(format!(
+                    "STREAMINFO says it has {} samples, but it ends after {}: {}",
+                    track.info.total_samples, position, error
+                ));
+                break;
+            }
+        };
+        // Frames are numbered by frame (for a fixed block size) or by
+        // their first sample (for a variable one) from the start of
+        // the track:
+        let variable = *variable_block_size.get_or_insert(packet.buf()[1] & 1 == 1);
+        let expected_number = if variable {
+            position
+        } else {
+            verification.frames
+        };
+        match check_frame(packet.buf()) {
+            Ok(number) if number == expected_number => {}
+            Ok(number) => verification.problems.push(format!(
+                "frame {} has number {} instead of {}",
+                verification.frames, number, expected_number
+            )),
+            Err(error) => verification
+                .problems
+                .push(format!("frame {}: {}", verification.frames, error)),
+        }
+        let samples = track
+            .frames
+            .decode(&packet)
+            .with_context(|| format!("decoding frame {}", verification.frames))?;
+        verification.frames += 1;
+
+        let start = match verification.image_start {
+            Some(start) => Some(start),
+            None => {
+                let mut found = None;
+                for candidate in candidates {
+                    if image.matches_at(*candidate, &samples)? {
+                        found = Some(*candidate);
+                        break;
+                    }
+                }
+                verification.image_start = found;
+                found
+            }
+        };
+        let Some(start) = start else {
+            verification
+                .problems
+                .push("its audio doesn't start anywhere near where it should".to_string());
+            return Ok(verification);
+        };
+        if !image.matches_at(start + position, &samples)? {
+            verification.problems.push(format!(
+                "audio differs from the disc image at track sample {}",
+                position
+            ));
+            return Ok(verification);
+        }
+        position += samples.first().map_or(0, |channel| channel.len() as u64);
+        image.discard_before(start + position);
+    }
+    verification.samples = position;
+    if position != track.info.total_samples {
+        verification.problems.push(format!(
+            "STREAMINFO says it has {} samples, but it has {}",
+            track.info.total_samples, position
+        ));
+    }
+    Ok(verification)
+}
+
+/// Decodes a disc image and the tracks split from it (with the same
+/// options), and checks that the tracks' audio, put together, is the
+/// image's audio; that their frames' CRCs and numbers are right; and
+/// that their STREAMINFO blocks have the right number of samples.
+///
+/// Where a track starts in the image is allowed to differ from its
+/// cue sheet position by up to a frame, since tracks get cut at frame
+/// boundaries (unless [SplitOptions::sample_accurate] is set).
+#[instrument(skip(base_path, options), err)]
+pub fn verify_one_file<P: AsRef<Path> + Debug, B: AsRef<Path> + Debug>(
+    input_path: P,
+    base_path: B,
+    options: &SplitOptions,
+) -> anyhow::Result<Verification> {
+    let mut verification = Verification {
+        input: input_path.as_ref().to_path_buf(),
+        ..Default::default()
+    };
+    let tracks = open_disc_image(&input_path, base_path, options)?;
+    let mut image = PcmStream::open(input_path.as_ref())?;
+    let max_block_size = u64::from(image.info.max_block_size);
+    let total_samples = image.info.total_samples;
+
+    // Where the last intact track ends in the image, and where the
+    // cue sheet says the last track ends; tracks are only expected to
+    // be contiguous where the cue sheet has no gaps between them
+    // (which it has e.g. with --pregap discard):
+    let mut covered_to = Some(0);
+    let mut planned_end = 0;
+    for PlannedTrack { path, track } in tracks {
+        let contiguous = track.start_ts <= planned_end;
+        planned_end = track.end_ts;
+        if !path.is_file() {
+            verification.tracks.push(TrackVerification {
+                problems: vec!["the track file doesn't exist".to_string()],
+                path,
+                ..Default::default()
+            });
+            covered_to = None;
+            continue;
+        }
+        // The track most likely starts right where the previous one
+        // ended (which can be a few frames off its cue sheet position
+        // after tracks shorter than a frame), or else at its cue sheet
+        // position or the image frame closest to it:
+        let window_start = track.start_ts.saturating_sub(max_block_size);
+        let window_end = track.start_ts + max_block_size;
+        let window = window_start..=window_end;
+        image.fill_to(window_end)?;
+        let mut candidates = vec![];
+        candidates.extend(covered_to);
+        candidates.push(track.start_ts);
+        candidates.extend(
+            image
+                .frame_starts
+                .iter()
+                .copied()
+                .filter(|start| window.contains(start)),
+        );
+        candidates.dedup();
+        debug!(?path, ?candidates, "verifying track");
+
+        let track_verification = verify_track(&path, &mut image, &candidates)
+            .with_context(|| format!("verifying {:?}", path))?;
+        if let (Some(start), Some(covered_to)) = (track_verification.image_start, covered_to) {
+            if start > covered_to && contiguous {
+                verification.uncovered.push((covered_to, start));
+            } else if start < covered_to {
+                verification
+                    .tracks
+                    .last_mut()
+                    .expect("there is a previous track")
+                    .problems
+                    .push(format!(
+                        "overlaps the next track by {} samples",
+                        covered_to - start
+                    ));
+            }
+        }
+        covered_to = track_verification
+            .image_start
+            .filter(|_| track_verification.problems.is_empty())
+            .map(|start| start + track_verification.samples);
+        verification.tracks.push(track_verification);
+    }
+    if let Some(covered_to) = covered_to {
+        if covered_to < total_samples && planned_end >= total_samples {
+            verification.uncovered.push((covered_to, total_samples));
+        }
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::OffsetFrame;
+    use symphonia_core::formats::Packet;
+
+    #[test]
+    fn test_check_frame() {
+        let samples = vec![vec![0i32, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]; 2];
+        let mut frame = OffsetFrame::new(None);
+        let first = frame.encode(&samples, 16, false).unwrap();
+        assert_eq!(check_frame(&first).unwrap(), 0);
+
+        // Renumbering it as the next frame keeps the CRCs intact:
+        let second = frame
+            .process(Packet::new_from_slice(0, 0, 16, &first))
+            .unwrap();
+        assert_eq!(check_frame(&second).unwrap(), 16);
+
+        let mut corrupt = second.clone();
+        corrupt[8] ^= 1;
+        assert!(check_frame(&corrupt).is_err());
+    }
+}